@@ -2,7 +2,66 @@ extern crate cc;
 use std::io::Read;
 use std::io::Write;
 
+const PRECOMPRESSED_EXTENSIONS: [&str; 5] = ["html", "css", "js", "json", "txt"];
+
+// Write `name.gz` and `name.br` next to every text asset in `dir` (recursively), unless they are
+// already up to date. Sidecars that wouldn't be smaller than the original are not generated.
+fn precompress_assets(dir: &std::path::Path) {
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            precompress_assets(&path);
+            continue;
+        }
+        let extension = match path.extension().and_then(|e| e.to_str()) {
+            Some(extension) => extension.to_lowercase(),
+            None => continue,
+        };
+        if !PRECOMPRESSED_EXTENSIONS.contains(&extension.as_str()) {
+            continue;
+        }
+        let content = std::fs::read(&path).unwrap();
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        for sidecar_extension in ["gz", "br"] {
+            let sidecar = std::path::PathBuf::from(format!(
+                "{}.{}",
+                path.to_str().unwrap(),
+                sidecar_extension
+            ));
+            if let Ok(sidecar_modified) = std::fs::metadata(&sidecar).and_then(|m| m.modified()) {
+                if sidecar_modified >= modified {
+                    continue;
+                }
+            }
+            let compressed = if sidecar_extension == "gz" {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder.write_all(&content).unwrap();
+                encoder.finish().unwrap()
+            } else {
+                let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 11, 22);
+                encoder.write_all(&content).unwrap();
+                encoder.flush().unwrap();
+                encoder.into_inner()
+            };
+            if compressed.len() < content.len() {
+                std::fs::write(&sidecar, compressed).unwrap();
+            } else if sidecar.exists() {
+                std::fs::remove_file(&sidecar).unwrap();
+            }
+        }
+    }
+}
+
 fn main() {
+    // `cc` emits `rerun-if-env-changed` directives, which disable the default behaviour of
+    // re-running this script whenever any file in the package changes
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=tuples.rs");
+    println!("cargo:rerun-if-changed=assets/template_files.txt");
+    println!("cargo:rerun-if-changed=c/sendfile.c");
+    println!("cargo:rerun-if-changed=c/free_space.c");
+    println!("cargo:rerun-if-changed=www");
     {
         let mut template_content = String::new();
         let mut tuples_content = String::new();
@@ -39,10 +98,15 @@ fn main() {
             .output()
             .expect("failed");
         let hello = output.stderr;
-        assert!(hello.len() == 0);
+        assert!(hello.is_empty());
+    }
+
+    if std::env::var("CARGO_FEATURE_PRECOMPRESS").is_ok() {
+        precompress_assets(std::path::Path::new("www"));
     }
 
     cc::Build::new()
         .file("c/sendfile.c")
-        .compile("tcp_utils_send_file");
+        .file("c/free_space.c")
+        .compile("tcp_utils");
 }
@@ -0,0 +1,12 @@
+#include <sys/statvfs.h>
+
+// The bytes available to unprivileged users on the file system holding `path`, -1 on error
+long long tcp_utils_free_space(char* path)
+{
+    struct statvfs stats;
+    if (statvfs(path, &stats) == -1)
+    {
+        return -1;
+    }
+    return (long long)stats.f_bavail * (long long)stats.f_frsize;
+}
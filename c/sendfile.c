@@ -7,10 +7,11 @@
 #include <sys/sendfile.h>
 #endif /* __linux__ */
 
-int tcp_utils_send_file(char* file_path, long file_size, int socket)
+int tcp_utils_send_file(char* file_path, long offset, long length, int socket)
 {
     printf("File path: %s\n", file_path);
-    printf("File size: %ld\n", file_size);
+    printf("Offset: %ld\n", offset);
+    printf("Length: %ld\n", length);
     printf("Socket FD: %d\n", socket);
     int resource_file = open(file_path, O_RDONLY);
     if (resource_file == -1)
@@ -19,13 +20,23 @@ int tcp_utils_send_file(char* file_path, long file_size, int socket)
         return -1;
     }
 #ifdef __linux__
-    ssize_t bytes_sent = sendfile(socket, resource_file, NULL, file_size);
-    printf("Size `%ld` bytes.\n", file_size);
-    printf("Sent `%ld` bytes.\n", bytes_sent);
-    if (bytes_sent == -1)
+    off_t file_offset = offset;
+    long remaining    = length;
+    // sendfile() may send less than requested: keep going until the whole range is out
+    while (remaining > 0)
+    {
+        ssize_t bytes_sent = sendfile(socket, resource_file, &file_offset, remaining);
+        if (bytes_sent <= 0)
+        {
+            break;
+        }
+        remaining -= bytes_sent;
+    }
+    printf("Sent `%ld` bytes.\n", length - remaining);
+    if (remaining > 0)
 #else
-    off_t len = file_size; // set to 0 will send all the origin file
-    int res   = sendfile(resource_file, socket, 0, &len, NULL, 0);
+    off_t len = length; // set to 0 will send all the origin file
+    int res   = sendfile(resource_file, socket, offset, &len, NULL, 0);
     printf("Sent `%lld` bytes.\n", len);
     if (res == -1)
 #endif
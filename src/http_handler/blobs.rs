@@ -0,0 +1,141 @@
+use super::store::temp_path;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Content-addressed storage: a blob is named after the SHA-256 digest of its content and stored
+// once however many uploads have that content. Each blob has a reference count next to it,
+// `<hash>.refs`, and goes away when the last reference is released.
+pub const BLOBS_DIR: &str = "artifacts/blobs";
+pub const BLOBS_PREFIX: &str = "/api/blobs/";
+const BLOB_HASH_LENGTH: usize = 64;
+
+// Reference counts are read, modified and written back: one change at a time
+static BLOBS_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn is_valid_blob_hash(hash: &str) -> bool {
+    return hash.len() == BLOB_HASH_LENGTH
+        && hash
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c));
+}
+
+// The hash addressed by a request target, e.g. "/api/blobs/<hash>" -> "<hash>"
+pub fn blob_hash_from_target(target: &str) -> Option<&str> {
+    return target.strip_prefix(BLOBS_PREFIX);
+}
+
+// Blobs are sharded on the first two bytes of their hash, e.g. "2c/f2/2cf24d...", so that no
+// directory grows too large
+pub fn blob_path(dir: &Path, hash: &str) -> PathBuf {
+    return dir.join(&hash[0..2]).join(&hash[2..4]).join(hash);
+}
+
+fn refs_path(dir: &Path, hash: &str) -> PathBuf {
+    return blob_path(dir, hash).with_extension("refs");
+}
+
+// The number of references to a blob, 0 if there is no such blob
+pub fn blob_references(dir: &Path, hash: &str) -> std::io::Result<u64> {
+    match std::fs::read_to_string(refs_path(dir, hash)) {
+        Ok(text) => {
+            return text.trim().parse::<u64>().map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid reference count")
+            });
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    }
+}
+
+fn write_references(dir: &Path, hash: &str, references: u64) -> std::io::Result<()> {
+    let path = refs_path(dir, hash);
+    let temp_path = temp_path(&path);
+    std::fs::write(&temp_path, format!("{}\n", references))?;
+    return std::fs::rename(&temp_path, &path);
+}
+
+// Add the complete file at `file` as the blob `hash`, which must be the digest of its content.
+// If the blob already exists, the file is a duplicate: it is removed and the blob gains a
+// reference. Returns whether the blob is new.
+pub fn add_blob(dir: &Path, file: &Path, hash: &str) -> std::io::Result<bool> {
+    let _lock = BLOBS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let path = blob_path(dir, hash);
+    let references = blob_references(dir, hash)?;
+    if references > 0 && path.exists() {
+        std::fs::remove_file(file)?;
+        write_references(dir, hash, references + 1)?;
+        return Ok(false);
+    }
+    std::fs::create_dir_all(path.parent().unwrap_or(dir))?;
+    std::fs::rename(file, &path)?;
+    write_references(dir, hash, 1)?;
+    return Ok(true);
+}
+
+// Drop a reference to a blob, deleting it with the last one. Returns whether it was deleted.
+pub fn release_blob(dir: &Path, hash: &str) -> std::io::Result<bool> {
+    let _lock = BLOBS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let references = blob_references(dir, hash)?;
+    if references > 1 {
+        write_references(dir, hash, references - 1)?;
+        return Ok(false);
+    }
+    // The blob goes before its count: a count without a blob is harmless, not the other way
+    match std::fs::remove_file(blob_path(dir, hash)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    match std::fs::remove_file(refs_path(dir, hash)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    return Ok(true);
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    pub fn blob_hashes() {
+        assert!(is_valid_blob_hash(HELLO_SHA256));
+        assert!(!is_valid_blob_hash(&HELLO_SHA256.to_uppercase()));
+        assert!(!is_valid_blob_hash(&HELLO_SHA256[1..]));
+        assert!(!is_valid_blob_hash(
+            "../../../../../../../../../../../../../../../../etc/passwd"
+        ));
+        assert_eq!(
+            blob_path(Path::new("blobs"), HELLO_SHA256),
+            Path::new("blobs/2c/f2").join(HELLO_SHA256)
+        );
+        assert_eq!(blob_hash_from_target("/api/blobs/abc"), Some("abc"));
+    }
+
+    #[test]
+    pub fn reference_counting() {
+        let dir =
+            std::env::temp_dir().join(format!("http-server-rust-blobs-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let first = dir.join("first");
+        let second = dir.join("second");
+        std::fs::write(&first, "hello").unwrap();
+        std::fs::write(&second, "hello").unwrap();
+
+        assert!(add_blob(&dir, &first, HELLO_SHA256).unwrap());
+        assert!(!add_blob(&dir, &second, HELLO_SHA256).unwrap());
+        assert!(!second.exists());
+        assert_eq!(blob_references(&dir, HELLO_SHA256).unwrap(), 2);
+        let path = blob_path(&dir, HELLO_SHA256);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hello");
+
+        assert!(!release_blob(&dir, HELLO_SHA256).unwrap());
+        assert!(path.exists());
+        assert!(release_blob(&dir, HELLO_SHA256).unwrap());
+        assert!(!path.exists());
+        assert_eq!(blob_references(&dir, HELLO_SHA256).unwrap(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
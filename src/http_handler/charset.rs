@@ -0,0 +1,262 @@
+use super::common::MediaType;
+
+// The character encodings text bodies are checked against, and converted from
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Charset {
+    Utf8,
+    // ISO-8859-1: every byte is the code point of the same value
+    Latin1,
+    Utf16Le,
+    Utf16Be,
+    // UTF-16 with its byte order given by a byte order mark, big-endian without one
+    Utf16,
+}
+
+impl Charset {
+    // The encoding named by a 'charset' parameter, among those the server knows
+    pub fn from_label(label: &str) -> Option<Self> {
+        match label.to_lowercase().as_str() {
+            "utf-8" | "utf8" | "us-ascii" | "ascii" => return Some(Self::Utf8),
+            "iso-8859-1" | "iso8859-1" | "iso_8859-1" | "latin1" | "l1" => {
+                return Some(Self::Latin1)
+            }
+            "utf-16le" => return Some(Self::Utf16Le),
+            "utf-16be" => return Some(Self::Utf16Be),
+            "utf-16" => return Some(Self::Utf16),
+            _ => return None,
+        }
+    }
+
+    // The encoding of a body of the given type: the declared charset, UTF-8 for text that doesn't
+    // declare any. `None` for other bodies, and for text in an unknown charset, which are taken
+    // as they are.
+    pub fn of_media_type(media_type: &MediaType) -> Option<Self> {
+        match media_type.parameter("charset") {
+            Some(label) => return Self::from_label(label),
+            None if media_type.is_text() => return Some(Self::Utf8),
+            None => return None,
+        }
+    }
+}
+
+fn invalid_text(charset: Charset) -> std::io::Error {
+    return std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("body is not valid {:?} text", charset),
+    );
+}
+
+// Checks text received in chunks, whose characters may be split across two chunks, and converts
+// it to UTF-8 if `transcode` is set. Invalid text fails with `InvalidData`.
+pub struct TextDecoder {
+    charset: Charset,
+    transcode: bool,
+    // The start of a character whose end is in the next chunk
+    pending: Vec<u8>,
+}
+
+impl TextDecoder {
+    pub fn new(charset: Charset, transcode: bool) -> Self {
+        return Self {
+            charset,
+            transcode,
+            pending: Vec::new(),
+        };
+    }
+
+    // Check the next chunk, appending what is to be stored to `out`: the chunk itself, or its
+    // complete characters in UTF-8
+    pub fn decode(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> std::io::Result<()> {
+        match self.charset {
+            Charset::Latin1 if self.transcode => {
+                out.extend(
+                    chunk
+                        .iter()
+                        .map(|byte| *byte as char)
+                        .collect::<String>()
+                        .bytes(),
+                );
+            }
+            Charset::Latin1 => out.extend_from_slice(chunk),
+            Charset::Utf8 => {
+                let mut data = std::mem::take(&mut self.pending);
+                data.extend_from_slice(chunk);
+                let valid = match std::str::from_utf8(&data) {
+                    Ok(_) => data.len(),
+                    // An error without a length is a character cut short by the end of the chunk
+                    Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                    Err(_) => return Err(invalid_text(self.charset)),
+                };
+                self.pending = data.split_off(valid);
+                if self.transcode {
+                    out.extend_from_slice(&data);
+                }
+            }
+            Charset::Utf16 | Charset::Utf16Le | Charset::Utf16Be => {
+                let mut data = std::mem::take(&mut self.pending);
+                data.extend_from_slice(chunk);
+                let mut start = 0;
+                if self.charset == Charset::Utf16 {
+                    if data.len() < 2 {
+                        self.pending = data;
+                        if !self.transcode {
+                            out.extend_from_slice(chunk);
+                        }
+                        return Ok(());
+                    }
+                    // The byte order mark only tells the byte order, it isn't part of the text
+                    (self.charset, start) = match data[0..2] {
+                        [0xff, 0xfe] => (Charset::Utf16Le, 2),
+                        [0xfe, 0xff] => (Charset::Utf16Be, 2),
+                        _ => (Charset::Utf16Be, 0),
+                    };
+                }
+                let units = data[start..]
+                    .chunks_exact(2)
+                    .map(|pair| match self.charset {
+                        Charset::Utf16Le => u16::from_le_bytes([pair[0], pair[1]]),
+                        _ => u16::from_be_bytes([pair[0], pair[1]]),
+                    })
+                    .collect::<Vec<u16>>();
+                // A high surrogate at the end waits for the low one in the next chunk
+                let mut complete = units.len();
+                if units
+                    .last()
+                    .is_some_and(|unit| (0xd800..0xdc00).contains(unit))
+                {
+                    complete -= 1;
+                }
+                let mut text = String::new();
+                for c in char::decode_utf16(units[..complete].iter().copied()) {
+                    text.push(c.map_err(|_| invalid_text(self.charset))?);
+                }
+                self.pending = data.split_off(start + complete * 2);
+                if self.transcode {
+                    out.extend_from_slice(text.as_bytes());
+                }
+            }
+        }
+        if !self.transcode && self.charset != Charset::Latin1 {
+            out.extend_from_slice(chunk);
+        }
+        return Ok(());
+    }
+
+    // The body is over: fails if it ends in the middle of a character
+    pub fn finish(&self) -> std::io::Result<()> {
+        if !self.pending.is_empty() {
+            return Err(invalid_text(self.charset));
+        }
+        return Ok(());
+    }
+
+    // The 'Content-Type' of what was stored if it isn't `media_type`, that of what was received
+    pub fn stored_media_type(&self, media_type: &MediaType) -> Option<String> {
+        if !self.transcode || self.charset == Charset::Utf8 {
+            return None;
+        }
+        let mut ret_media_type = media_type.clone();
+        ret_media_type.set_parameter("charset", "utf-8");
+        return Some(ret_media_type.to_header_value());
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // Decode `data` split in chunks of `chunk_size` bytes
+    fn decode_chunks(
+        charset: Charset,
+        transcode: bool,
+        data: &[u8],
+        chunk_size: usize,
+    ) -> std::io::Result<Vec<u8>> {
+        let mut decoder = TextDecoder::new(charset, transcode);
+        let mut out: Vec<u8> = Vec::new();
+        for chunk in data.chunks(chunk_size) {
+            decoder.decode(chunk, &mut out)?;
+        }
+        decoder.finish()?;
+        return Ok(out);
+    }
+
+    #[test]
+    pub fn charsets() {
+        let media_type = MediaType::parse("text/plain; charset=ISO-8859-1").unwrap();
+        assert_eq!(Charset::of_media_type(&media_type), Some(Charset::Latin1));
+        let media_type = MediaType::parse("application/json").unwrap();
+        assert_eq!(Charset::of_media_type(&media_type), Some(Charset::Utf8));
+        let media_type = MediaType::parse("text/plain; charset=koi8-r").unwrap();
+        assert_eq!(Charset::of_media_type(&media_type), None);
+        let media_type = MediaType::parse("application/octet-stream").unwrap();
+        assert_eq!(Charset::of_media_type(&media_type), None);
+        let media_type = MediaType::parse("image/svg+xml; charset=utf-16").unwrap();
+        assert_eq!(Charset::of_media_type(&media_type), Some(Charset::Utf16));
+    }
+
+    #[test]
+    pub fn utf8_across_chunks() {
+        let text = "h\u{e9}llo \u{1f600} w\u{f6}rld";
+        for chunk_size in 1..6 {
+            for transcode in [false, true] {
+                let out = decode_chunks(Charset::Utf8, transcode, text.as_bytes(), chunk_size);
+                assert_eq!(out.unwrap(), text.as_bytes());
+            }
+        }
+        // Latin-1 is not UTF-8, nor is a character cut short at the end
+        let e = decode_chunks(Charset::Utf8, false, b"caf\xe9 au lait", 3).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert!(decode_chunks(Charset::Utf8, false, &text.as_bytes()[0..8], 4).is_err());
+    }
+
+    #[test]
+    pub fn transcoding() {
+        let latin1 = b"caf\xe9 \xe0 la cr\xe8me";
+        let out = decode_chunks(Charset::Latin1, true, latin1, 4).unwrap();
+        assert_eq!(out, "caf\u{e9} \u{e0} la cr\u{e8}me".as_bytes());
+        assert_eq!(
+            decode_chunks(Charset::Latin1, false, latin1, 4).unwrap(),
+            latin1
+        );
+
+        let text = "h\u{e9} \u{1f600}";
+        let le = text
+            .encode_utf16()
+            .flat_map(|unit| unit.to_le_bytes())
+            .collect::<Vec<u8>>();
+        let be = text
+            .encode_utf16()
+            .flat_map(|unit| unit.to_be_bytes())
+            .collect::<Vec<u8>>();
+        let with_bom = [&[0xff, 0xfe][..], &le].concat();
+        for chunk_size in 1..5 {
+            for (charset, data) in [
+                (Charset::Utf16Le, &le),
+                (Charset::Utf16Be, &be),
+                (Charset::Utf16, &be),
+                (Charset::Utf16, &with_bom),
+            ] {
+                let out = decode_chunks(charset, true, data, chunk_size).unwrap();
+                assert_eq!(out, text.as_bytes());
+                assert_eq!(
+                    decode_chunks(charset, false, data, chunk_size).unwrap(),
+                    *data
+                );
+            }
+        }
+        // Unpaired surrogates and odd lengths are invalid
+        assert!(decode_chunks(Charset::Utf16Le, false, b"\x00\xdc\x41\x00", 1).is_err());
+        assert!(decode_chunks(Charset::Utf16Le, false, b"\x3d\xd8", 2).is_err());
+        assert!(decode_chunks(Charset::Utf16Le, false, b"\x41\x00\x42", 2).is_err());
+
+        let media_type = MediaType::parse("text/csv; charset=latin1; header=present").unwrap();
+        let decoder = TextDecoder::new(Charset::Latin1, true);
+        assert_eq!(
+            decoder.stored_media_type(&media_type).as_deref(),
+            Some("text/csv; header=present; charset=utf-8")
+        );
+        let decoder = TextDecoder::new(Charset::Latin1, false);
+        assert_eq!(decoder.stored_media_type(&media_type), None);
+    }
+}
@@ -0,0 +1,374 @@
+use super::blobs::BLOBS_PREFIX;
+use super::config::config;
+use super::documents::Document;
+use super::json::*;
+use super::response::ResponseStatus;
+use super::state::{DATA_DIR, SET_TARGET, STATUS_TARGET};
+use super::uploads::UPLOADS_TARGET;
+use super::urlencoded::{field_value, percent_decode, FormFields};
+use std::cmp::Ordering;
+use std::path::PathBuf;
+
+// When enabled, every array of objects in 'data/<collection>.json' is a REST collection: `GET`
+// on `/api/<collection>` lists its items, `POST` adds one, and `GET`, `PUT` and `DELETE` on
+// `/api/<collection>/<id>` read, replace and remove the item of that 'id'. The file is rewritten
+// in one rename after every change.
+pub const COLLECTIONS_PREFIX: &str = "/api/";
+// The query parameters of a listing that aren't filters
+const LIST_PARAMETERS: [&str; 3] = ["sort", "offset", "limit"];
+
+// The collection a request target is about, and the ID of one of its items, percent-decoded,
+// e.g. "/api/books/12" -> ("books", Some("12")), whether or not there is such a collection
+pub fn parse_collection_target(target: &str) -> Option<(&str, Option<String>)> {
+    let rest = target.strip_prefix(COLLECTIONS_PREFIX)?;
+    let (name, id) = match rest.split_once('/') {
+        Some((name, id)) if !id.is_empty() && !id.contains('/') => {
+            (name, Some(percent_decode(id, false)?))
+        }
+        Some(_) => return None,
+        None => (rest, None),
+    };
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return None;
+    }
+    return Some((name, id));
+}
+
+pub fn collection_path(name: &str) -> PathBuf {
+    return PathBuf::from(DATA_DIR).join(format!("{}.json", name));
+}
+
+// Whether a collection name would hide the targets of the state, the uploads or the blobs
+pub fn is_reserved_collection(name: &str) -> bool {
+    let collection_target = format!("{}{}", COLLECTIONS_PREFIX, name);
+    return [
+        STATUS_TARGET,
+        SET_TARGET,
+        UPLOADS_TARGET,
+        BLOBS_PREFIX.trim_end_matches('/'),
+    ]
+    .contains(&collection_target.as_str());
+}
+
+// The collection of a request target, if collections are enabled and its file exists. The
+// targets of the state, of the uploads and of the blobs keep their meaning.
+pub fn collection_from_target(target: &str) -> Option<(&str, Option<String>)> {
+    if !config().json_collections {
+        return None;
+    }
+    let (name, id) = parse_collection_target(target)?;
+    if is_reserved_collection(name) || !collection_path(name).is_file() {
+        return None;
+    }
+    return Some((name, id));
+}
+
+// Where an item is served, e.g. "/api/books/a%20b"
+pub fn item_location(name: &str, id: &str) -> String {
+    let mut ret_location = format!("{}{}/", COLLECTIONS_PREFIX, name);
+    for byte in id.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            ret_location.push(byte as char);
+        } else {
+            ret_location.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    return ret_location;
+}
+
+// The items of a collection's file, which must be an array
+pub fn read_collection(name: &str) -> Result<Vec<JsonValue>, ResponseStatus> {
+    let path = collection_path(name);
+    let document = std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|text| JsonValue::parse(&text).ok());
+    match document {
+        Some(JsonValue::Array(items)) => return Ok(items),
+        _ => {
+            println!("{} is not a JSON array", path.display());
+            return Err(ResponseStatus::InternalServerError);
+        }
+    }
+}
+
+// A member as it is written in a query or a URL: strings without their quotes
+fn member_text(value: &JsonValue) -> String {
+    match value {
+        JsonValue::String(text) => return text.clone(),
+        _ => return value.to_json_string(),
+    }
+}
+
+fn member<'a>(item: &'a JsonValue, name: &str) -> Option<&'a JsonValue> {
+    match item {
+        JsonValue::Object(members) => {
+            return members
+                .iter()
+                .find(|(member_name, _)| member_name == name)
+                .map(|(_, value)| value)
+        }
+        _ => return None,
+    }
+}
+
+pub fn item_id(item: &JsonValue) -> Option<String> {
+    return member(item, "id").map(member_text);
+}
+
+// Values of different types are ordered null, booleans, numbers, strings, then arrays and
+// objects as serialized; items missing the member come last
+fn compare_members(a: Option<&JsonValue>, b: Option<&JsonValue>) -> Ordering {
+    let rank = |value: &JsonValue| match value {
+        JsonValue::Null => 0,
+        JsonValue::Bool(_) => 1,
+        JsonValue::Number(_) => 2,
+        JsonValue::String(_) => 3,
+        _ => 4,
+    };
+    match (a, b) {
+        (None, None) => return Ordering::Equal,
+        (None, Some(_)) => return Ordering::Greater,
+        (Some(_), None) => return Ordering::Less,
+        (Some(JsonValue::Bool(a)), Some(JsonValue::Bool(b))) => return a.cmp(b),
+        (Some(JsonValue::Number(a)), Some(JsonValue::Number(b))) => return a.total_cmp(b),
+        (Some(JsonValue::String(a)), Some(JsonValue::String(b))) => return a.cmp(b),
+        (Some(a), Some(b)) => {
+            return rank(a)
+                .cmp(&rank(b))
+                .then_with(|| a.to_json_string().cmp(&b.to_json_string()))
+        }
+    }
+}
+
+// The items a listing's query selects: every other parameter than 'sort', 'offset' and 'limit'
+// keeps the items with a member of that name and value, e.g. "author=Hugo"; 'sort' orders them
+// by a member, in descending order with a leading '-'; 'offset' and 'limit' select a page.
+// `None` if 'offset' or 'limit' isn't a number.
+pub fn select_items(items: Vec<JsonValue>, parameters: &FormFields) -> Option<Vec<JsonValue>> {
+    let offset = field_value(parameters, "offset").map_or(Some(0), |v| v.parse::<usize>().ok())?;
+    let limit = field_value(parameters, "limit").map_or(Some(usize::MAX), |v| v.parse().ok())?;
+    let mut ret_items = items
+        .into_iter()
+        .filter(|item| {
+            parameters
+                .iter()
+                .filter(|(name, _)| !LIST_PARAMETERS.contains(&name.as_str()))
+                .all(|(name, value)| member(item, name).is_some_and(|m| member_text(m) == *value))
+        })
+        .collect::<Vec<JsonValue>>();
+    if let Some(sort) = field_value(parameters, "sort") {
+        let (name, descending) = match sort.strip_prefix('-') {
+            Some(name) => (name, true),
+            None => (sort, false),
+        };
+        // Items without the member stay last either way
+        ret_items.sort_by(|a, b| match (member(a, name), member(b, name)) {
+            (Some(a), Some(b)) if descending => compare_members(Some(b), Some(a)),
+            (a, b) => compare_members(a, b),
+        });
+    }
+    return Some(ret_items.into_iter().skip(offset).take(limit).collect());
+}
+
+// Add an item to a collection, with the next numeric ID if it has none. Returns the item as it
+// was added. Fails with 409 Conflict if there is already an item with its ID.
+pub fn insert_item(
+    items: &mut Vec<JsonValue>,
+    mut item: JsonValue,
+) -> Result<JsonValue, ResponseStatus> {
+    if !matches!(item, JsonValue::Object(_)) {
+        return Err(ResponseStatus::BadRequest);
+    }
+    match item_id(&item) {
+        Some(id) => {
+            if items
+                .iter()
+                .any(|other| item_id(other).as_deref() == Some(&id))
+            {
+                return Err(ResponseStatus::Conflict);
+            }
+        }
+        None => {
+            let last_id = items
+                .iter()
+                .filter_map(|other| match member(other, "id") {
+                    Some(JsonValue::Number(id)) => Some(*id),
+                    _ => None,
+                })
+                .fold(0.0, f64::max);
+            item.set("id", JsonValue::Number(last_id.floor() + 1.0));
+        }
+    }
+    items.push(item.clone());
+    return Ok(item);
+}
+
+// Replace the item of the given ID, or add it if there is none. Its 'id' is that of the URL,
+// set if missing: 400 Bad Request if it says otherwise. Returns the item, and whether it is new.
+pub fn replace_item(
+    items: &mut Vec<JsonValue>,
+    id: &str,
+    mut item: JsonValue,
+) -> Result<(JsonValue, bool), ResponseStatus> {
+    if !matches!(item, JsonValue::Object(_)) {
+        return Err(ResponseStatus::BadRequest);
+    }
+    let existing = items
+        .iter()
+        .position(|other| item_id(other).as_deref() == Some(id));
+    match item_id(&item) {
+        Some(item_id) if item_id != id => return Err(ResponseStatus::BadRequest),
+        Some(_) => {}
+        // The item keeps its ID as it was written, e.g. as a number
+        None => {
+            let id_value = existing
+                .and_then(|index| member(&items[index], "id").cloned())
+                .unwrap_or_else(|| JsonValue::from_str_value(id));
+            item.set("id", id_value);
+        }
+    }
+    match existing {
+        Some(index) => {
+            items[index] = item.clone();
+            return Ok((item, false));
+        }
+        None => {
+            items.push(item.clone());
+            return Ok((item, true));
+        }
+    }
+}
+
+// Apply `change` to the items of a collection and write them back in one rename, unless it fails.
+// Changes are made one at a time, each on the latest version of the file.
+pub fn update_collection<T>(
+    name: &str,
+    change: impl FnOnce(&mut Vec<JsonValue>) -> Result<T, ResponseStatus>,
+) -> Result<T, ResponseStatus> {
+    let mut ret_result = None;
+    Document::File(collection_path(name)).update(|current, _| {
+        let mut items = match current {
+            JsonValue::Array(items) => items.clone(),
+            _ => {
+                println!("{} is not a JSON array", collection_path(name).display());
+                return Err(ResponseStatus::InternalServerError);
+            }
+        };
+        ret_result = Some(change(&mut items)?);
+        return Ok(JsonValue::Array(items));
+    })?;
+    return ret_result.ok_or(ResponseStatus::InternalServerError);
+}
+
+// Remove the item of the given ID. Returns whether there was one.
+pub fn remove_item(items: &mut Vec<JsonValue>, id: &str) -> bool {
+    let length = items.len();
+    items.retain(|item| item_id(item).as_deref() != Some(id));
+    return items.len() < length;
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn items(text: &str) -> Vec<JsonValue> {
+        match JsonValue::parse(text).unwrap() {
+            JsonValue::Array(items) => return items,
+            _ => panic!("not an array"),
+        }
+    }
+
+    fn ids(items: &[JsonValue]) -> Vec<String> {
+        return items.iter().filter_map(item_id).collect();
+    }
+
+    #[test]
+    pub fn collection_targets() {
+        assert_eq!(parse_collection_target("/api/books"), Some(("books", None)));
+        assert_eq!(
+            parse_collection_target("/api/books/12"),
+            Some(("books", Some(String::from("12"))))
+        );
+        assert_eq!(
+            parse_collection_target("/api/books/a%20b"),
+            Some(("books", Some(String::from("a b"))))
+        );
+        assert_eq!(parse_collection_target("/api/books/%zz"), None);
+        assert_eq!(parse_collection_target("/api/books/"), None);
+        assert_eq!(parse_collection_target("/api/books/1/2"), None);
+        assert_eq!(parse_collection_target("/api/../secret"), None);
+        assert_eq!(parse_collection_target("/api/"), None);
+        assert_eq!(parse_collection_target("/books"), None);
+        assert_eq!(collection_path("books"), PathBuf::from("data/books.json"));
+        assert_eq!(item_location("books", "a b/1"), "/api/books/a%20b%2F1");
+    }
+
+    #[test]
+    pub fn reserved_collections() {
+        assert!(is_reserved_collection("status"));
+        assert!(is_reserved_collection("uploads"));
+        // A 'data/blobs.json' doesn't hide the blob store
+        let (name, _) = parse_collection_target("/api/blobs/0123abcd").unwrap();
+        assert!(is_reserved_collection(name));
+        assert!(!is_reserved_collection("books"));
+    }
+
+    #[test]
+    pub fn collection_listing() {
+        let books = items(
+            "[{\"id\": 1, \"author\": \"Hugo\", \"year\": 1862},\
+              {\"id\": 2, \"author\": \"Zola\", \"year\": 1885},\
+              {\"id\": \"x\", \"author\": \"Hugo\", \"year\": 1831},\
+              {\"id\": 4, \"author\": \"Sand\"}]",
+        );
+        let parameters = |query: &str| crate::http_handler::urlencoded::parse_urlencoded(query);
+        let select = |query: &str| select_items(books.clone(), &parameters(query).unwrap());
+        assert_eq!(ids(&select("").unwrap()), ["1", "2", "x", "4"]);
+        assert_eq!(ids(&select("author=Hugo").unwrap()), ["1", "x"]);
+        assert_eq!(ids(&select("author=Hugo&year=1831").unwrap()), ["x"]);
+        assert_eq!(ids(&select("id=1").unwrap()), ["1"]);
+        assert!(select("author=Dumas").unwrap().is_empty());
+        assert_eq!(ids(&select("sort=year").unwrap()), ["x", "1", "2", "4"]);
+        assert_eq!(ids(&select("sort=-year").unwrap()), ["2", "1", "x", "4"]);
+        assert_eq!(
+            ids(&select("sort=author&offset=1&limit=2").unwrap()),
+            ["x", "4"]
+        );
+        assert_eq!(select("limit=ten"), None);
+    }
+
+    #[test]
+    pub fn collection_changes() {
+        let mut books = items("[{\"id\": 1, \"title\": \"a\"}, {\"id\": \"b\"}]");
+        let item = JsonValue::parse("{\"title\": \"c\"}").unwrap();
+        let added = insert_item(&mut books, item).unwrap();
+        assert_eq!(added.to_json_string(), "{\"title\":\"c\",\"id\":2}");
+        let item = JsonValue::parse("{\"id\": \"b\"}").unwrap();
+        assert_eq!(insert_item(&mut books, item), Err(ResponseStatus::Conflict));
+        assert_eq!(
+            insert_item(&mut books, JsonValue::Array(Vec::new())),
+            Err(ResponseStatus::BadRequest)
+        );
+
+        let item = JsonValue::parse("{\"title\": \"d\"}").unwrap();
+        let (replaced, created) = replace_item(&mut books, "1", item).unwrap();
+        assert_eq!(replaced.to_json_string(), "{\"title\":\"d\",\"id\":1}");
+        assert!(!created);
+        let item = JsonValue::parse("{\"id\": \"e\"}").unwrap();
+        assert!(replace_item(&mut books, "e", item).unwrap().1);
+        let item = JsonValue::parse("{\"id\": 7}").unwrap();
+        assert_eq!(
+            replace_item(&mut books, "8", item),
+            Err(ResponseStatus::BadRequest)
+        );
+        assert_eq!(ids(&books), ["1", "b", "2", "e"]);
+
+        assert!(remove_item(&mut books, "b"));
+        assert!(!remove_item(&mut books, "b"));
+        assert_eq!(ids(&books), ["1", "2", "e"]);
+    }
+}
@@ -1,10 +1,14 @@
-const CONTENT_TYPE_HTML: &'static str = "text/html";
-const CONTENT_TYPE_JSON: &'static str = "application/json";
-const CONTENT_TYPE_PNG: &'static str = "image/png";
-const CONTENT_TYPE_JPEG: &'static str = "image/jpeg";
-const CONTENT_TYPE_CSS: &'static str = "text/css";
-const CONTENT_TYPE_JAVASCRIPT: &'static str = "text/javascript";
-const CONTENT_TYPE_TEXT: &'static str = "text/plain";
+const CONTENT_TYPE_HTML: &str = "text/html";
+const CONTENT_TYPE_JSON: &str = "application/json";
+const CONTENT_TYPE_PNG: &str = "image/png";
+const CONTENT_TYPE_JPEG: &str = "image/jpeg";
+const CONTENT_TYPE_CSS: &str = "text/css";
+const CONTENT_TYPE_JAVASCRIPT: &str = "text/javascript";
+const CONTENT_TYPE_TEXT: &str = "text/plain";
+const CONTENT_TYPE_FORM_DATA: &str = "multipart/form-data";
+const CONTENT_TYPE_FORM_URLENCODED: &str = "application/x-www-form-urlencoded";
+const CONTENT_TYPE_OFFSET_OCTET_STREAM: &str = "application/offset+octet-stream";
+const CONTENT_TYPE_EVENT_STREAM: &str = "text/event-stream";
 
 pub type ContentLength = u64;
 
@@ -17,6 +21,12 @@ pub enum ContentType {
     Css,
     Javascript,
     Text,
+    FormData,
+    FormUrlEncoded,
+    // A chunk of a resumable upload
+    OffsetOctetStream,
+    // Server-Sent Events
+    EventStream,
     Unknown,
 }
 
@@ -48,8 +58,11 @@ impl ContentType {
         return Self::Unknown;
     }
 
-    // The client's 'Content-Type' value is mapped to a specific variant
-    pub fn from_content_type_str(content_type_str: &str) -> Self {
+    // The client's 'Content-Type' value is mapped to a specific variant. Parameters such as
+    // 'charset' are ignored, only the type/subtype pair is taken into account.
+    pub fn from_media_type(media_type: &MediaType) -> Self {
+        let content_type_str = media_type.essence();
+        let content_type_str = content_type_str.as_str();
         if content_type_str == CONTENT_TYPE_JSON {
             return Self::Json;
         }
@@ -71,14 +84,31 @@ impl ContentType {
         if content_type_str == CONTENT_TYPE_TEXT {
             return Self::Text;
         }
+        if content_type_str == CONTENT_TYPE_FORM_DATA {
+            return Self::FormData;
+        }
+        if content_type_str == CONTENT_TYPE_FORM_URLENCODED {
+            return Self::FormUrlEncoded;
+        }
+        if content_type_str == CONTENT_TYPE_OFFSET_OCTET_STREAM {
+            return Self::OffsetOctetStream;
+        }
+        if content_type_str == CONTENT_TYPE_EVENT_STREAM {
+            return Self::EventStream;
+        }
         return Self::Unknown;
     }
 
-    pub fn is_binary(&self) -> bool {
-        match self {
-            ContentType::Png | ContentType::Jpeg => true,
-            _ => false,
-        }
+    // Text based formats shrink considerably when compressed
+    pub fn is_compressible(&self) -> bool {
+        matches!(
+            self,
+            ContentType::Html
+                | ContentType::Json
+                | ContentType::Css
+                | ContentType::Javascript
+                | ContentType::Text
+        )
     }
 
     // A specific variant is mapped to a 'Content-Type' value
@@ -91,7 +121,229 @@ impl ContentType {
             ContentType::Css => CONTENT_TYPE_CSS,
             ContentType::Javascript => CONTENT_TYPE_JAVASCRIPT,
             ContentType::Text => CONTENT_TYPE_TEXT,
-            ContentType::Unknown => "",
+            ContentType::FormData => CONTENT_TYPE_FORM_DATA,
+            ContentType::FormUrlEncoded => CONTENT_TYPE_FORM_URLENCODED,
+            ContentType::OffsetOctetStream => CONTENT_TYPE_OFFSET_OCTET_STREAM,
+            ContentType::EventStream => CONTENT_TYPE_EVENT_STREAM,
+            ContentType::Unknown => "application/octet-stream",
+        }
+    }
+}
+
+// Characters allowed in a token (RFC 9110, section 5.6.2)
+fn is_token_char(c: char) -> bool {
+    return c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+}
+
+fn is_token(input: &str) -> bool {
+    return !input.is_empty() && input.chars().all(is_token_char);
+}
+
+// Whether a character may not appear in a quoted string, even escaped: controls other than HTAB
+fn is_quoted_control(c: char) -> bool {
+    return c.is_ascii_control() && c != '\t';
+}
+
+// Parse the `; name=value` parameters following a media type or a disposition type. Names are
+// case-insensitive and stored lowercase, quoted values are unquoted.
+pub fn parse_parameters(input: &str) -> Option<Vec<(String, String)>> {
+    let mut rest = input;
+    let mut ret_parameters: Vec<(String, String)> = Vec::new();
+    loop {
+        rest = rest.trim_start_matches([' ', '\t']);
+        if rest.is_empty() {
+            break;
+        }
+        // Every parameter is introduced by a ';', empty parameters are tolerated
+        rest = rest.strip_prefix(';')?.trim_start_matches([' ', '\t']);
+        if rest.is_empty() || rest.starts_with(';') {
+            continue;
+        }
+        let name_end = rest.find(|c: char| !is_token_char(c)).unwrap_or(rest.len());
+        let name = &rest[..name_end];
+        rest = rest[name_end..].strip_prefix('=')?;
+        if name.is_empty() {
+            return None;
+        }
+        let value: String;
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let mut unquoted = String::new();
+            let mut chars = quoted.char_indices();
+            let mut closing_quote: Option<usize> = None;
+            while let Some((index, c)) = chars.next() {
+                match c {
+                    '"' => {
+                        closing_quote = Some(index);
+                        break;
+                    }
+                    // Control characters, CR and LF among them, can't be quoted (RFC 9110,
+                    // section 5.6.4)
+                    c if is_quoted_control(c) => return None,
+                    '\\' => match chars.next()?.1 {
+                        escaped if is_quoted_control(escaped) => return None,
+                        escaped => unquoted.push(escaped),
+                    },
+                    _ => unquoted.push(c),
+                }
+            }
+            rest = &quoted[closing_quote? + 1..];
+            value = unquoted;
+        } else {
+            let value_end = rest.find(|c: char| !is_token_char(c)).unwrap_or(rest.len());
+            value = String::from(&rest[..value_end]);
+            rest = &rest[value_end..];
+            if value.is_empty() {
+                return None;
+            }
+        }
+        ret_parameters.push((name.to_lowercase(), value));
+    }
+    return Some(ret_parameters);
+}
+
+// A parsed 'Content-Type' value, e.g. `multipart/form-data; boundary="abc"`. Type, subtype and
+// parameter names are case-insensitive and stored lowercase; parameter values are kept verbatim
+// (unquoted) because some of them, such as 'boundary', are case-sensitive.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MediaType {
+    pub main_type: String,
+    pub sub_type: String,
+    pub parameters: Vec<(String, String)>,
+}
+
+impl MediaType {
+    pub fn parse(input: &str) -> Option<Self> {
+        let (essence, rest) = match input.find(';') {
+            Some(index) => (&input[..index], &input[index..]),
+            None => (input, ""),
+        };
+        // Only spaces and tabs surround the essence, line breaks would go along with the value
+        let (main_type, sub_type) = essence.trim_matches([' ', '\t']).split_once('/')?;
+        if !is_token(main_type) || !is_token(sub_type) {
+            return None;
+        }
+        let parameters = parse_parameters(rest)?;
+        return Some(Self {
+            main_type: main_type.to_lowercase(),
+            sub_type: sub_type.to_lowercase(),
+            parameters,
+        });
+    }
+
+    // The type/subtype pair without parameters, e.g. "application/json"
+    pub fn essence(&self) -> String {
+        return format!("{}/{}", self.main_type, self.sub_type);
+    }
+
+    // The first parameter with the given (case-insensitive) name
+    pub fn parameter(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        for (key, value) in self.parameters.iter() {
+            if *key == name {
+                return Some(value.as_str());
+            }
         }
+        return None;
+    }
+
+    // Replace the parameters with the given name by a single one, or add it
+    pub fn set_parameter(&mut self, name: &str, value: &str) {
+        let name = name.to_lowercase();
+        self.parameters.retain(|(key, _)| *key != name);
+        self.parameters.push((name, String::from(value)));
+    }
+
+    // Whether the media type is one of text: 'text/*' and the text based formats of applications
+    pub fn is_text(&self) -> bool {
+        return self.main_type == "text"
+            || self.sub_type.ends_with("+json")
+            || self.sub_type.ends_with("+xml")
+            || (self.main_type == "application"
+                && matches!(self.sub_type.as_str(), "json" | "javascript" | "xml"));
+    }
+
+    // The 'Content-Type' value, values quoted where needed
+    pub fn to_header_value(&self) -> String {
+        let mut ret_value = self.essence();
+        for (name, value) in self.parameters.iter() {
+            if is_token(value) {
+                ret_value.push_str(&format!("; {}={}", name, value));
+            } else {
+                let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+                ret_value.push_str(&format!("; {}=\"{}\"", name, escaped));
+            }
+        }
+        return ret_value;
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn media_type_with_parameters() {
+        let media_type = MediaType::parse("Application/JSON; Charset=\"UTF-8\"").unwrap();
+        assert_eq!(media_type.essence(), "application/json");
+        assert_eq!(media_type.parameter("charset"), Some("UTF-8"));
+        assert_eq!(ContentType::from_media_type(&media_type), ContentType::Json);
+
+        let media_type =
+            MediaType::parse("multipart/form-data; boundary=\"a \\\"quoted\\\" b\";x=1").unwrap();
+        assert_eq!(media_type.essence(), "multipart/form-data");
+        assert_eq!(media_type.parameter("boundary"), Some("a \"quoted\" b"));
+        assert_eq!(media_type.parameter("X"), Some("1"));
+        assert_eq!(media_type.parameter("missing"), None);
+
+        let mut media_type = MediaType::parse("text/plain ;; charset=latin1 ;").unwrap();
+        assert_eq!(media_type.parameter("charset"), Some("latin1"));
+        assert!(media_type.is_text());
+        media_type.set_parameter("Charset", "utf-8");
+        media_type.set_parameter("title", "a \"b\"");
+        assert_eq!(
+            media_type.to_header_value(),
+            "text/plain; charset=utf-8; title=\"a \\\"b\\\"\""
+        );
+        assert_eq!(
+            MediaType::parse(&media_type.to_header_value()),
+            Some(media_type)
+        );
+        // Quoted strings take tabs, but no other control character, even escaped
+        assert!(MediaType::parse("text/plain; title=\"a\tb\"").is_some());
+        for input in [
+            "text/plain; title=\"a\r\nb\"",
+            "text/plain; title=\"a\rb\"",
+            "text/plain; title=\"a\\\nb\"",
+            "text/plain; title=\"a\x7fb\"",
+            "text/plain\r\n",
+        ] {
+            assert_eq!(MediaType::parse(input), None, "{:?}", input);
+        }
+        let media_type = MediaType::parse("text/html; charset=utf-8").unwrap();
+        assert_eq!(ContentType::from_media_type(&media_type), ContentType::Html);
+        assert!(MediaType::parse("application/ld+json").unwrap().is_text());
+        assert!(!MediaType::parse("image/png").unwrap().is_text());
+        let media_type = MediaType::parse("application/x-www-form-urlencoded").unwrap();
+        assert_eq!(
+            ContentType::from_media_type(&media_type),
+            ContentType::FormUrlEncoded
+        );
+        let media_type = MediaType::parse("application/offset+octet-stream").unwrap();
+        assert_eq!(
+            ContentType::from_media_type(&media_type),
+            ContentType::OffsetOctetStream
+        );
+    }
+
+    #[test]
+    pub fn invalid_media_type() {
+        assert_eq!(MediaType::parse(""), None);
+        assert_eq!(MediaType::parse("application"), None);
+        assert_eq!(MediaType::parse("application/"), None);
+        assert_eq!(MediaType::parse("text/plain; charset"), None);
+        assert_eq!(MediaType::parse("text/plain; charset="), None);
+        assert_eq!(MediaType::parse("text/plain; charset=\"utf-8"), None);
+        assert_eq!(MediaType::parse("text/plain charset=utf-8"), None);
+        assert_eq!(MediaType::parse("image/png x"), None);
     }
 }
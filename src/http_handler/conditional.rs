@@ -0,0 +1,275 @@
+use super::digest::to_hex;
+use super::request::RequestHeader;
+use super::response::ResponseStatus;
+use sha2::{Digest, Sha256};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// An entity tag, e.g. `"abc"` (strong) or `W/"abc"` (weak)
+#[derive(Debug, PartialEq)]
+pub struct EntityTag {
+    pub weak: bool,
+    pub opaque_tag: String,
+}
+
+impl EntityTag {
+    // Validator derived from the file's metadata: it changes whenever the file is modified
+    pub fn from_metadata(metadata: &std::fs::Metadata, weak: bool) -> Self {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or(Duration::ZERO);
+        return Self {
+            weak,
+            opaque_tag: format!("{:x}-{:x}", metadata.len(), modified.as_nanos()),
+        };
+    }
+
+    // Validator derived from the content itself, for documents held in memory
+    pub fn from_content(content: &[u8]) -> Self {
+        return Self {
+            weak: false,
+            opaque_tag: to_hex(&Sha256::digest(content)[..16]),
+        };
+    }
+
+    pub fn to_header_value(&self) -> String {
+        if self.weak {
+            return format!("W/\"{}\"", self.opaque_tag);
+        }
+        return format!("\"{}\"", self.opaque_tag);
+    }
+
+    // Strong comparison: both tags must be strong and identical (RFC 9110, section 8.8.3.2)
+    pub fn strong_eq(&self, other: &Self) -> bool {
+        return !self.weak && !other.weak && self.opaque_tag == other.opaque_tag;
+    }
+
+    // Weak comparison: the opaque tags must be identical, weakness is ignored
+    pub fn weak_eq(&self, other: &Self) -> bool {
+        return self.opaque_tag == other.opaque_tag;
+    }
+}
+
+// The value of an 'If-Match' or 'If-None-Match' header field
+#[derive(Debug, PartialEq)]
+pub enum EntityTagList {
+    Any,
+    Tags(Vec<EntityTag>),
+}
+
+impl EntityTagList {
+    // Parse "*" or a comma-separated list of entity tags. Malformed lists yield `None`.
+    pub fn parse(input: &str) -> Option<Self> {
+        let mut rest = input.trim();
+        if rest == "*" {
+            return Some(Self::Any);
+        }
+        let mut tags: Vec<EntityTag> = Vec::new();
+        while !rest.is_empty() {
+            let weak = rest.starts_with("W/");
+            if weak {
+                rest = &rest[2..];
+            }
+            rest = rest.strip_prefix('"')?;
+            let closing_quote = rest.find('"')?;
+            tags.push(EntityTag {
+                weak,
+                opaque_tag: String::from(&rest[..closing_quote]),
+            });
+            rest = rest[closing_quote + 1..].trim_start();
+            if let Some(next) = rest.strip_prefix(',') {
+                rest = next.trim_start();
+            } else if !rest.is_empty() {
+                return None;
+            }
+        }
+        return Some(Self::Tags(tags));
+    }
+
+    fn matches(&self, etag: &EntityTag, strong: bool) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Tags(tags) => tags.iter().any(|tag| {
+                if strong {
+                    tag.strong_eq(etag)
+                } else {
+                    tag.weak_eq(etag)
+                }
+            }),
+        }
+    }
+}
+
+// HTTP dates have a resolution of one second
+fn truncate_to_seconds(time: SystemTime) -> u64 {
+    return time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs();
+}
+
+fn parse_date_seconds(request_header: &RequestHeader, name: &str) -> Option<u64> {
+    return request_header
+        .header(name)
+        .and_then(super::date::parse_http_date)
+        .map(truncate_to_seconds);
+}
+
+// Evaluate the preconditions of a request against the current state of an existing resource,
+// following the order defined in RFC 9110, section 13.2.2. `None` means the request can proceed.
+pub fn evaluate_preconditions(
+    request_header: &RequestHeader,
+    etag: &EntityTag,
+    last_modified: Option<SystemTime>,
+) -> Option<ResponseStatus> {
+    let is_safe = request_header.method.is_safe();
+    let last_modified = last_modified.map(truncate_to_seconds);
+    let if_match = request_header.header("If-Match");
+    if let Some(if_match) = if_match {
+        match EntityTagList::parse(if_match) {
+            Some(tags) if tags.matches(etag, true) => {}
+            _ => return Some(ResponseStatus::PreconditionFailed),
+        }
+    } else if let (Some(if_unmodified_since), Some(last_modified)) = (
+        parse_date_seconds(request_header, "If-Unmodified-Since"),
+        last_modified,
+    ) {
+        if last_modified > if_unmodified_since {
+            return Some(ResponseStatus::PreconditionFailed);
+        }
+    }
+    let if_none_match = request_header.header("If-None-Match");
+    if let Some(if_none_match) = if_none_match {
+        if let Some(tags) = EntityTagList::parse(if_none_match) {
+            if tags.matches(etag, false) {
+                if is_safe {
+                    return Some(ResponseStatus::NotModified);
+                }
+                return Some(ResponseStatus::PreconditionFailed);
+            }
+        }
+    } else if is_safe {
+        if let (Some(if_modified_since), Some(last_modified)) = (
+            parse_date_seconds(request_header, "If-Modified-Since"),
+            last_modified,
+        ) {
+            if last_modified <= if_modified_since {
+                return Some(ResponseStatus::NotModified);
+            }
+        }
+    }
+    return None;
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::http_handler::date::format_http_date;
+
+    fn request(method: &str, headers: &[&str]) -> RequestHeader {
+        let mut lines = vec![format!("{} /index.html HTTP/1.1", method)];
+        lines.extend(headers.iter().map(|header| String::from(*header)));
+        return RequestHeader::new(&lines.join("\r\n"));
+    }
+
+    #[test]
+    pub fn entity_tag_list() {
+        assert_eq!(EntityTagList::parse("*"), Some(EntityTagList::Any));
+        assert_eq!(
+            EntityTagList::parse("\"a,b\", W/\"c\""),
+            Some(EntityTagList::Tags(vec![
+                EntityTag {
+                    weak: false,
+                    opaque_tag: String::from("a,b")
+                },
+                EntityTag {
+                    weak: true,
+                    opaque_tag: String::from("c")
+                },
+            ]))
+        );
+        assert_eq!(EntityTagList::parse("abc"), None);
+        assert_eq!(EntityTagList::parse("\"abc"), None);
+        assert_eq!(EntityTagList::parse("\"a\" \"b\""), None);
+    }
+
+    #[test]
+    pub fn precondition_precedence() {
+        let etag = EntityTag {
+            weak: false,
+            opaque_tag: String::from("v1"),
+        };
+        let modified = UNIX_EPOCH + Duration::from_secs(1700000000);
+        let before = format!(
+            "If-Modified-Since: {}",
+            format_http_date(modified - Duration::from_secs(1))
+        );
+        let same = format!("If-Modified-Since: {}", format_http_date(modified));
+
+        assert_eq!(
+            evaluate_preconditions(&request("GET", &[]), &etag, Some(modified)),
+            None
+        );
+        let not_modified = Some(ResponseStatus::NotModified);
+        let failed = Some(ResponseStatus::PreconditionFailed);
+        assert_eq!(
+            evaluate_preconditions(&request("GET", &["If-None-Match: W/\"v1\""]), &etag, None),
+            not_modified
+        );
+        assert_eq!(
+            evaluate_preconditions(&request("POST", &["If-None-Match: *"]), &etag, None),
+            failed
+        );
+        assert_eq!(
+            evaluate_preconditions(&request("GET", &[&same]), &etag, Some(modified)),
+            not_modified
+        );
+        assert_eq!(
+            evaluate_preconditions(&request("GET", &[&before]), &etag, Some(modified)),
+            None
+        );
+        // 'If-None-Match' takes precedence over 'If-Modified-Since'
+        assert_eq!(
+            evaluate_preconditions(
+                &request("GET", &["If-None-Match: \"v0\"", &same]),
+                &etag,
+                Some(modified)
+            ),
+            None
+        );
+        // 'If-Match' uses the strong comparison and takes precedence over everything else
+        assert_eq!(
+            evaluate_preconditions(&request("GET", &["If-Match: W/\"v1\""]), &etag, None),
+            failed
+        );
+        assert_eq!(
+            evaluate_preconditions(
+                &request(
+                    "GET",
+                    &["If-Match: \"v0\", \"v1\"", "If-None-Match: \"v1\""]
+                ),
+                &etag,
+                None
+            ),
+            not_modified
+        );
+        let unmodified_since = format!(
+            "If-Unmodified-Since: {}",
+            format_http_date(modified - Duration::from_secs(1))
+        );
+        assert_eq!(
+            evaluate_preconditions(&request("GET", &[&unmodified_since]), &etag, Some(modified)),
+            failed
+        );
+        // 'If-Unmodified-Since' is ignored when 'If-Match' is present
+        assert_eq!(
+            evaluate_preconditions(
+                &request("GET", &["If-Match: *", &unmodified_since]),
+                &etag,
+                Some(modified)
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,235 @@
+use super::state::StateSync;
+use std::sync::OnceLock;
+
+// Server settings, read once from the environment:
+// - HTTP_SERVER_UPLOAD_STORAGE: "directory" (default) keeps the data of every upload in its own
+//   directory, "content-addressed" stores it under its SHA-256 digest, once for identical uploads
+// - HTTP_SERVER_UPLOAD_TTL: how many seconds uploads are kept when the client doesn't say, forever
+//   if unset, at most `MAX_UPLOAD_TTL`
+// - HTTP_SERVER_SWEEP_INTERVAL: how many seconds pass between two sweeps of expired and abandoned
+//   uploads, 60 by default
+// - HTTP_SERVER_CLIENT_QUOTA, HTTP_SERVER_GLOBAL_QUOTA: how much the uploads of a single client
+//   address, and of all clients, may take, unlimited if unset
+// - HTTP_SERVER_MIN_FREE_SPACE: how much space must remain free on the disk after a body is
+//   written, 0 by default
+// - HTTP_SERVER_MAX_DECODED_SIZE: how large a compressed request body may get once decompressed,
+//   64M by default
+// - HTTP_SERVER_TEXT_UPLOADS: "raw" (default) stores text uploads as they are sent, once checked
+//   against their charset, "utf-8" converts Latin-1 and UTF-16 text to UTF-8
+// - HTTP_SERVER_STATE_SYNC: when the updates of the JSON API's state are flushed to the disk,
+//   "always" (default) before they are acknowledged, "periodic" once a second, "never"
+//   leaving it to the operating system
+// - HTTP_SERVER_COLLECTIONS: "on" serves every 'data/<collection>.json' array as a REST collection
+//   at '/api/<collection>', "off" (default) doesn't
+// Sizes are in bytes, or in KiB, MiB or GiB with a 'K', 'M' or 'G' suffix.
+#[derive(Debug, PartialEq)]
+pub struct Config {
+    pub content_addressed_uploads: bool,
+    pub upload_ttl: Option<u64>,
+    pub sweep_interval: std::time::Duration,
+    pub client_quota: Option<u64>,
+    pub global_quota: Option<u64>,
+    pub min_free_space: u64,
+    pub max_decoded_body_size: u64,
+    pub transcode_text_uploads: bool,
+    pub state_sync: StateSync,
+    pub json_collections: bool,
+}
+
+// The longest time uploads may be kept for, ten years, so that their expiry time stays a date
+pub const MAX_UPLOAD_TTL: u64 = 10 * 365 * 86400;
+const DEFAULT_SWEEP_INTERVAL: u64 = 60;
+const DEFAULT_MAX_DECODED_BODY_SIZE: u64 = 64 << 20;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+// A number of seconds uploads are kept, from 1 to `MAX_UPLOAD_TTL`
+pub fn parse_upload_ttl(value: &str) -> Option<u64> {
+    return value
+        .parse::<u64>()
+        .ok()
+        .filter(|ttl| *ttl > 0 && *ttl <= MAX_UPLOAD_TTL);
+}
+
+impl Config {
+    // Settings from a variable lookup, the defaults standing in for missing or invalid values
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let mut ret_config = Self {
+            content_addressed_uploads: false,
+            upload_ttl: None,
+            sweep_interval: std::time::Duration::from_secs(DEFAULT_SWEEP_INTERVAL),
+            client_quota: None,
+            global_quota: None,
+            min_free_space: 0,
+            max_decoded_body_size: DEFAULT_MAX_DECODED_BODY_SIZE,
+            transcode_text_uploads: false,
+            state_sync: StateSync::Always,
+            json_collections: false,
+        };
+        match var("HTTP_SERVER_UPLOAD_STORAGE").as_deref() {
+            Some("content-addressed") => ret_config.content_addressed_uploads = true,
+            Some("directory") | None => {}
+            Some(other) => println!("Ignoring unknown upload storage {:?}", other),
+        }
+        if let Some(value) = var("HTTP_SERVER_UPLOAD_TTL") {
+            match parse_upload_ttl(&value) {
+                Some(ttl) => ret_config.upload_ttl = Some(ttl),
+                None => println!("Ignoring invalid upload TTL {:?}", value),
+            }
+        }
+        if let Some(value) = var("HTTP_SERVER_SWEEP_INTERVAL") {
+            match value.parse::<u64>() {
+                Ok(interval) if interval > 0 => {
+                    ret_config.sweep_interval = std::time::Duration::from_secs(interval)
+                }
+                _ => println!("Ignoring invalid sweep interval {:?}", value),
+            }
+        }
+        for (name, setting) in [
+            ("HTTP_SERVER_CLIENT_QUOTA", &mut ret_config.client_quota),
+            ("HTTP_SERVER_GLOBAL_QUOTA", &mut ret_config.global_quota),
+        ] {
+            if let Some(value) = var(name) {
+                match parse_size(&value) {
+                    Some(size) => *setting = Some(size),
+                    None => println!("Ignoring invalid {} {:?}", name, value),
+                }
+            }
+        }
+        if let Some(value) = var("HTTP_SERVER_MIN_FREE_SPACE") {
+            match parse_size(&value) {
+                Some(size) => ret_config.min_free_space = size,
+                None => println!("Ignoring invalid minimum free space {:?}", value),
+            }
+        }
+        if let Some(value) = var("HTTP_SERVER_MAX_DECODED_SIZE") {
+            match parse_size(&value) {
+                Some(size) => ret_config.max_decoded_body_size = size,
+                None => println!("Ignoring invalid maximum decoded size {:?}", value),
+            }
+        }
+        match var("HTTP_SERVER_TEXT_UPLOADS").as_deref() {
+            Some("utf-8") => ret_config.transcode_text_uploads = true,
+            Some("raw") | None => {}
+            Some(other) => println!("Ignoring unknown text upload handling {:?}", other),
+        }
+        if let Some(value) = var("HTTP_SERVER_STATE_SYNC") {
+            match StateSync::from_str(&value) {
+                Some(sync) => ret_config.state_sync = sync,
+                None => println!("Ignoring unknown state sync policy {:?}", value),
+            }
+        }
+        match var("HTTP_SERVER_COLLECTIONS").as_deref() {
+            Some("on") => ret_config.json_collections = true,
+            Some("off") | None => {}
+            Some(other) => println!("Ignoring unknown collections setting {:?}", other),
+        }
+        return ret_config;
+    }
+}
+
+// A size in bytes, e.g. "512", "64K" or "10G"
+pub fn parse_size(input: &str) -> Option<u64> {
+    let (digits, unit) = match input.char_indices().last()? {
+        (index, 'K' | 'k') => (&input[..index], 1 << 10),
+        (index, 'M' | 'm') => (&input[..index], 1 << 20),
+        (index, 'G' | 'g') => (&input[..index], 1 << 30),
+        _ => (input, 1),
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    return digits.parse::<u64>().ok()?.checked_mul(unit);
+}
+
+pub fn config() -> &'static Config {
+    return CONFIG.get_or_init(|| Config::from_vars(|name| std::env::var(name).ok()));
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn config_from_vars() {
+        let config = Config::from_vars(|_| None);
+        assert!(!config.content_addressed_uploads);
+        let config = Config::from_vars(|name| {
+            (name == "HTTP_SERVER_UPLOAD_STORAGE").then(|| String::from("content-addressed"))
+        });
+        assert!(config.content_addressed_uploads);
+        let config = Config::from_vars(|_| Some(String::from("tape")));
+        assert!(!config.content_addressed_uploads);
+        assert!(!config.transcode_text_uploads);
+        assert!(!config.json_collections);
+        assert_eq!(config.state_sync, StateSync::Always);
+        assert_eq!(config.max_decoded_body_size, DEFAULT_MAX_DECODED_BODY_SIZE);
+        assert_eq!(config.upload_ttl, None);
+        assert_eq!(
+            config.sweep_interval,
+            std::time::Duration::from_secs(DEFAULT_SWEEP_INTERVAL)
+        );
+
+        let config = Config::from_vars(|name| match name {
+            "HTTP_SERVER_UPLOAD_TTL" => Some(String::from("3600")),
+            "HTTP_SERVER_SWEEP_INTERVAL" => Some(String::from("5")),
+            _ => None,
+        });
+        assert_eq!(config.upload_ttl, Some(3600));
+        assert_eq!(config.sweep_interval, std::time::Duration::from_secs(5));
+        let config = Config::from_vars(|name| {
+            (name == "HTTP_SERVER_TEXT_UPLOADS").then(|| String::from("utf-8"))
+        });
+        assert!(config.transcode_text_uploads);
+        let config = Config::from_vars(|name| {
+            (name == "HTTP_SERVER_STATE_SYNC").then(|| String::from("periodic"))
+        });
+        assert_eq!(config.state_sync, StateSync::Periodic);
+        let config = Config::from_vars(|name| {
+            (name == "HTTP_SERVER_COLLECTIONS").then(|| String::from("on"))
+        });
+        assert!(config.json_collections);
+        let config = Config::from_vars(|_| Some(String::from("0")));
+        assert_eq!(config.upload_ttl, None);
+        assert_eq!(config.client_quota, Some(0));
+
+        let config = Config::from_vars(|name| match name {
+            "HTTP_SERVER_CLIENT_QUOTA" => Some(String::from("10M")),
+            "HTTP_SERVER_GLOBAL_QUOTA" => Some(String::from("1G")),
+            "HTTP_SERVER_MIN_FREE_SPACE" => Some(String::from("-1")),
+            "HTTP_SERVER_MAX_DECODED_SIZE" => Some(String::from("1M")),
+            _ => None,
+        });
+        assert_eq!(config.client_quota, Some(10 << 20));
+        assert_eq!(config.global_quota, Some(1 << 30));
+        assert_eq!(config.min_free_space, 0);
+        assert_eq!(config.max_decoded_body_size, 1 << 20);
+    }
+
+    #[test]
+    pub fn upload_ttls() {
+        assert_eq!(parse_upload_ttl("3600"), Some(3600));
+        assert_eq!(parse_upload_ttl("0"), None);
+        assert_eq!(parse_upload_ttl("-1"), None);
+        // Longer TTLs are refused rather than pushing the expiry time past any date
+        assert_eq!(
+            parse_upload_ttl(&MAX_UPLOAD_TTL.to_string()),
+            Some(MAX_UPLOAD_TTL)
+        );
+        assert_eq!(parse_upload_ttl(&(MAX_UPLOAD_TTL + 1).to_string()), None);
+        assert_eq!(parse_upload_ttl("18446744073709551615"), None);
+    }
+
+    #[test]
+    pub fn sizes() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("64k"), Some(64 << 10));
+        assert_eq!(parse_size("2G"), Some(2 << 30));
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("1.5M"), None);
+        assert_eq!(parse_size("+1"), None);
+        assert_eq!(parse_size("99999999999999999999"), None);
+        assert_eq!(parse_size("99999999999999G"), None);
+    }
+}
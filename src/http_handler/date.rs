@@ -0,0 +1,197 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+const SECONDS_PER_DAY: u64 = 86400;
+// 9999-12-31T23:59:59Z, RFC 3339 having four-digit years
+const MAX_RFC3339_SECONDS: u64 = 253402300799;
+
+// Gregorian calendar date of a number of days since 1970-01-01 (Howard Hinnant's algorithm)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    return (year, month, day);
+}
+
+// Number of days since 1970-01-01 of a Gregorian calendar date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let day_of_year = (153 * shifted_month + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    return era * 146097 + day_of_era - 719468;
+}
+
+// Format a time as an IMF-fixdate, e.g. "Sun, 06 Nov 1994 08:49:37 GMT" (RFC 9110, section 5.6.7)
+pub fn format_http_date(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs();
+    let days = seconds / SECONDS_PER_DAY;
+    let seconds_of_day = seconds % SECONDS_PER_DAY;
+    let (year, month, day) = civil_from_days(days as i64);
+    return format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60
+    );
+}
+
+// Format seconds since the Unix epoch as an RFC 3339 UTC timestamp, later times than the system
+// or the format can represent showing as the last second of year 9999
+pub fn format_unix_rfc3339(seconds: u64) -> String {
+    return match UNIX_EPOCH.checked_add(Duration::from_secs(seconds.min(MAX_RFC3339_SECONDS))) {
+        Some(time) => format_rfc3339(time),
+        None => String::from("9999-12-31T23:59:59Z"),
+    };
+}
+
+// Seconds since the Unix epoch, 0 for earlier times
+pub fn unix_seconds(time: SystemTime) -> u64 {
+    return time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs();
+}
+
+// Format a time as an RFC 3339 UTC timestamp, e.g. "1994-11-06T08:49:37Z", as used in JSON
+pub fn format_rfc3339(time: SystemTime) -> String {
+    let seconds = unix_seconds(time);
+    let seconds_of_day = seconds % SECONDS_PER_DAY;
+    let (year, month, day) = civil_from_days((seconds / SECONDS_PER_DAY) as i64);
+    return format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60
+    );
+}
+
+fn parse_month(month_str: &str) -> Option<u32> {
+    let index = MONTHS.iter().position(|month| *month == month_str)?;
+    return Some(index as u32 + 1);
+}
+
+fn parse_time_of_day(time_str: &str) -> Option<u64> {
+    let parts = time_str
+        .split(':')
+        .map(|part| part.parse::<u64>().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    if parts.len() != 3 || parts[0] > 23 || parts[1] > 59 || parts[2] > 60 {
+        return None;
+    }
+    return Some(parts[0] * 3600 + parts[1] * 60 + parts[2]);
+}
+
+// Parse an HTTP-date in any of the three formats a recipient must accept: IMF-fixdate,
+// the obsolete RFC 850 format and ANSI C's asctime() format
+pub fn parse_http_date(date_str: &str) -> Option<SystemTime> {
+    let fields = date_str.split_whitespace().collect::<Vec<&str>>();
+    let (year, month, day, time_str) = match fields.len() {
+        // Sun, 06 Nov 1994 08:49:37 GMT
+        6 if fields[5] == "GMT" => (
+            fields[3].parse::<i64>().ok()?,
+            parse_month(fields[2])?,
+            fields[1].parse::<u32>().ok()?,
+            fields[4],
+        ),
+        // Sunday, 06-Nov-94 08:49:37 GMT
+        4 if fields[3] == "GMT" => {
+            let date_fields = fields[1].split('-').collect::<Vec<&str>>();
+            if date_fields.len() != 3 {
+                return None;
+            }
+            let short_year = date_fields[2].parse::<i64>().ok()?;
+            // Two digit years more than 50 years in the future are in the past century
+            let year = if short_year < 70 {
+                2000 + short_year
+            } else {
+                1900 + short_year
+            };
+            (
+                year,
+                parse_month(date_fields[1])?,
+                date_fields[0].parse::<u32>().ok()?,
+                fields[2],
+            )
+        }
+        // Sun Nov  6 08:49:37 1994
+        5 => (
+            fields[4].parse::<i64>().ok()?,
+            parse_month(fields[1])?,
+            fields[2].parse::<u32>().ok()?,
+            fields[3],
+        ),
+        _ => return None,
+    };
+    if day == 0 || day > 31 || year < 1970 {
+        return None;
+    }
+    let seconds =
+        days_from_civil(year, month, day) as u64 * SECONDS_PER_DAY + parse_time_of_day(time_str)?;
+    return Some(UNIX_EPOCH + Duration::from_secs(seconds));
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn http_date() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(format_http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format_rfc3339(time), "1994-11-06T08:49:37Z");
+        assert_eq!(format_unix_rfc3339(784111777), "1994-11-06T08:49:37Z");
+        assert_eq!(format_unix_rfc3339(u64::MAX), "9999-12-31T23:59:59Z");
+        assert_eq!(unix_seconds(time), 784111777);
+        assert_eq!(
+            format_http_date(UNIX_EPOCH),
+            "Thu, 01 Jan 1970 00:00:00 GMT"
+        );
+        assert_eq!(
+            format_http_date(UNIX_EPOCH + Duration::from_secs(951782400)),
+            "Tue, 29 Feb 2000 00:00:00 GMT"
+        );
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+        assert_eq!(
+            parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"),
+            Some(time)
+        );
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), Some(time));
+        assert_eq!(
+            parse_http_date(&format_http_date(
+                UNIX_EPOCH + Duration::from_secs(1700000000)
+            )),
+            Some(UNIX_EPOCH + Duration::from_secs(1700000000))
+        );
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 CET"), None);
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 25:49:37 GMT"), None);
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+}
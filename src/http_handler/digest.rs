@@ -0,0 +1,380 @@
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub type Sha256Digest = [u8; 32];
+
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut ret_string = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let group = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for index in 0..4 {
+            if index <= chunk.len() {
+                ret_string
+                    .push(BASE64_ALPHABET[(group >> (18 - 6 * index) & 0x3f) as usize] as char);
+            } else {
+                ret_string.push('=');
+            }
+        }
+    }
+    return ret_string;
+}
+
+// Decode standard base64, padding included. Returns None for anything else.
+pub fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    let bytes = input.as_bytes();
+    if !bytes.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut ret_data: Vec<u8> = Vec::with_capacity(bytes.len() / 4 * 3);
+    for (chunk_index, chunk) in bytes.chunks(4).enumerate() {
+        let is_last = chunk_index == bytes.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|byte| **byte == b'=').count();
+        if padding > 2 || (padding > 0 && !is_last) {
+            return None;
+        }
+        let mut group: u32 = 0;
+        for byte in chunk[0..4 - padding].iter() {
+            let value = BASE64_ALPHABET.iter().position(|c| c == byte)? as u32;
+            group = group << 6 | value;
+        }
+        group <<= 6 * padding;
+        let decoded = [(group >> 16) as u8, (group >> 8) as u8, group as u8];
+        ret_data.extend_from_slice(&decoded[0..3 - padding]);
+    }
+    return Some(ret_data);
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    return data.iter().map(|byte| format!("{:02x}", byte)).collect();
+}
+
+// The SHA-256 digest written in hex by `to_hex`
+pub fn sha256_from_hex(hex: &str) -> Option<Sha256Digest> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut ret_digest: Sha256Digest = [0; 32];
+    for (index, byte) in ret_digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).ok()?;
+    }
+    return Some(ret_digest);
+}
+
+// The 'Repr-Digest' value announcing a SHA-256 digest (RFC 9530)
+pub fn repr_digest_value(digest: &Sha256Digest) -> String {
+    return format!("sha-256=:{}:", base64_encode(digest));
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DigestAlgorithm {
+    Sha256,
+    Md5,
+}
+
+impl DigestAlgorithm {
+    // Algorithm names are case-insensitive; the ones not listed here are ignored
+    pub fn from_token(token: &str) -> Option<Self> {
+        if token.eq_ignore_ascii_case("sha-256") {
+            return Some(Self::Sha256);
+        }
+        if token.eq_ignore_ascii_case("md5") {
+            return Some(Self::Md5);
+        }
+        return None;
+    }
+
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha-256",
+            Self::Md5 => "md5",
+        }
+    }
+
+    fn digest_length(&self) -> usize {
+        match self {
+            Self::Sha256 => 32,
+            Self::Md5 => 16,
+        }
+    }
+}
+
+// A digest of the body announced by the client
+#[derive(Debug, PartialEq)]
+pub struct ExpectedDigest {
+    pub algorithm: DigestAlgorithm,
+    pub value: Vec<u8>,
+}
+
+impl ExpectedDigest {
+    fn new(algorithm: DigestAlgorithm, value: Vec<u8>) -> Option<Self> {
+        if value.len() != algorithm.digest_length() {
+            return None;
+        }
+        return Some(Self { algorithm, value });
+    }
+}
+
+// Collect the digests announced through 'Repr-Digest' (RFC 9530, e.g. `sha-256=:base64:`), the
+// obsolete 'Digest' (RFC 3230, e.g. `SHA-256=base64`) and 'Content-MD5' (RFC 1864). Returns None
+// if one of them is malformed.
+pub fn expected_digests(
+    repr_digest: Option<&str>,
+    digest: Option<&str>,
+    content_md5: Option<&str>,
+) -> Option<Vec<ExpectedDigest>> {
+    let mut ret_digests: Vec<ExpectedDigest> = Vec::new();
+    for member in repr_digest.unwrap_or("").split(',') {
+        let member = member.trim();
+        if member.is_empty() {
+            continue;
+        }
+        // Parameters of a member carry no meaning for the digests supported here
+        let member = member.split(';').next().unwrap_or("");
+        let (algorithm, value) = member.split_once('=')?;
+        let value = value.trim().strip_prefix(':')?.strip_suffix(':')?;
+        if let Some(algorithm) = DigestAlgorithm::from_token(algorithm.trim()) {
+            ret_digests.push(ExpectedDigest::new(algorithm, base64_decode(value)?)?);
+        }
+    }
+    for instance in digest.unwrap_or("").split(',') {
+        let instance = instance.trim();
+        if instance.is_empty() {
+            continue;
+        }
+        // Other algorithms may use other encodings than base64
+        let (algorithm, value) = instance.split_once('=')?;
+        if let Some(algorithm) = DigestAlgorithm::from_token(algorithm.trim()) {
+            ret_digests.push(ExpectedDigest::new(
+                algorithm,
+                base64_decode(value.trim())?,
+            )?);
+        }
+    }
+    if let Some(content_md5) = content_md5 {
+        let value = base64_decode(content_md5.trim())?;
+        ret_digests.push(ExpectedDigest::new(DigestAlgorithm::Md5, value)?);
+    }
+    return Some(ret_digests);
+}
+
+// Reads at most `length` bytes from the body while hashing them. SHA-256 is always computed, MD5
+// only if a client expects it. Once the whole body went through, it is checked against the
+// expected digests: a mismatch fails the read, so nothing built from the body gets committed.
+// A body cut short fails with `UnexpectedEof`, so that the end of the reader is that of a
+// complete body.
+pub struct DigestReader<R: Read> {
+    reader: R,
+    remaining: u64,
+    expected: Vec<ExpectedDigest>,
+    sha256: Sha256,
+    md5: Option<Md5>,
+    digest: Option<Sha256Digest>,
+}
+
+impl<R: Read> DigestReader<R> {
+    pub fn new(reader: R, length: u64, expected: Vec<ExpectedDigest>) -> std::io::Result<Self> {
+        let needs_md5 = expected
+            .iter()
+            .any(|digest| digest.algorithm == DigestAlgorithm::Md5);
+        let mut ret_reader = Self {
+            reader,
+            remaining: length,
+            expected,
+            sha256: Sha256::new(),
+            md5: if needs_md5 { Some(Md5::new()) } else { None },
+            digest: None,
+        };
+        // An empty body is complete right away
+        if length == 0 {
+            ret_reader.verify()?;
+        }
+        return Ok(ret_reader);
+    }
+
+    pub fn get_ref(&self) -> &R {
+        return &self.reader;
+    }
+
+    // The SHA-256 digest of the body, once it was entirely read
+    pub fn digest(&self) -> Option<Sha256Digest> {
+        return self.digest;
+    }
+
+    fn verify(&mut self) -> std::io::Result<()> {
+        let sha256: Sha256Digest = self.sha256.clone().finalize().into();
+        let md5 = self.md5.as_ref().map(|md5| md5.clone().finalize().to_vec());
+        for expected in self.expected.iter() {
+            let computed = match expected.algorithm {
+                DigestAlgorithm::Sha256 => sha256.to_vec(),
+                DigestAlgorithm::Md5 => md5.clone().unwrap_or_default(),
+            };
+            if computed != expected.value {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "{} digest mismatch: expected {}, computed {}",
+                        expected.algorithm.to_str(),
+                        to_hex(&expected.value),
+                        to_hex(&computed)
+                    ),
+                ));
+            }
+        }
+        self.digest = Some(sha256);
+        return Ok(());
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let size = buf
+            .len()
+            .min(self.remaining.min(usize::MAX as u64) as usize);
+        let bytes_read = self.reader.read(&mut buf[0..size])?;
+        if bytes_read == 0 && size > 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("body ended {} bytes early", self.remaining),
+            ));
+        }
+        self.sha256.update(&buf[0..bytes_read]);
+        if let Some(md5) = self.md5.as_mut() {
+            md5.update(&buf[0..bytes_read]);
+        }
+        self.remaining -= bytes_read as u64;
+        if bytes_read > 0 && self.remaining == 0 {
+            self.verify()?;
+        }
+        return Ok(bytes_read);
+    }
+}
+
+// Hashes what goes through it, e.g. a body once decompressed, whose digest isn't that of the
+// bytes sent
+pub struct Sha256Reader<R: Read> {
+    reader: R,
+    sha256: Sha256,
+}
+
+impl<R: Read> Sha256Reader<R> {
+    pub fn new(reader: R) -> Self {
+        return Self {
+            reader,
+            sha256: Sha256::new(),
+        };
+    }
+
+    // The SHA-256 digest of what was read so far
+    pub fn digest(&self) -> Sha256Digest {
+        return self.sha256.clone().finalize().into();
+    }
+}
+
+impl<R: Read> Read for Sha256Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = self.reader.read(buf)?;
+        self.sha256.update(&buf[0..bytes_read]);
+        return Ok(bytes_read);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // SHA-256 and MD5 of "hello"
+    const HELLO_SHA256: &str = "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=";
+    const HELLO_MD5: &str = "XUFAKrxLKna5cZ2REBfFkg==";
+
+    #[test]
+    pub fn base64() {
+        for (data, encoded) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64_encode(data.as_bytes()), encoded);
+            assert_eq!(base64_decode(encoded), Some(data.as_bytes().to_vec()));
+        }
+        assert_eq!(base64_decode("Zg="), None);
+        assert_eq!(base64_decode("Z==="), None);
+        assert_eq!(base64_decode("Zg==Zg=="), None);
+        assert_eq!(base64_decode("Zm9*"), None);
+    }
+
+    #[test]
+    pub fn digest_headers() {
+        let repr_digest = format!("sha-512=:{}:, sha-256=:{}:;x=1", HELLO_MD5, HELLO_SHA256);
+        let digest = format!("MD5={},UNIXsum=30637", HELLO_MD5);
+        let digests = expected_digests(Some(&repr_digest), Some(&digest), Some(HELLO_MD5)).unwrap();
+        assert_eq!(digests.len(), 3);
+        assert_eq!(digests[0].algorithm, DigestAlgorithm::Sha256);
+        assert_eq!(digests[1].algorithm, DigestAlgorithm::Md5);
+        assert_eq!(digests[2].algorithm, DigestAlgorithm::Md5);
+        assert_eq!(expected_digests(None, None, None), Some(Vec::new()));
+
+        // Missing colons, bad base64, a value of the wrong length
+        assert_eq!(
+            expected_digests(Some(&format!("sha-256={}", HELLO_SHA256)), None, None),
+            None
+        );
+        assert_eq!(expected_digests(None, Some("SHA-256=abc"), None), None);
+        assert_eq!(expected_digests(None, None, Some(HELLO_SHA256)), None);
+    }
+
+    #[test]
+    pub fn digest_reader() {
+        let expected = expected_digests(
+            Some(&format!("sha-256=:{}:", HELLO_SHA256)),
+            None,
+            Some(HELLO_MD5),
+        )
+        .unwrap();
+        let mut reader = DigestReader::new("hello, world".as_bytes(), 5, expected).unwrap();
+        let mut body = String::new();
+        reader.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "hello");
+        assert_eq!(
+            repr_digest_value(&reader.digest().unwrap()),
+            format!("sha-256=:{}:", HELLO_SHA256)
+        );
+
+        let expected = expected_digests(None, None, Some(HELLO_MD5)).unwrap();
+        let mut reader = DigestReader::new("hellO".as_bytes(), 5, expected).unwrap();
+        let error = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(reader.digest(), None);
+        let mut reader = DigestReader::new("hell".as_bytes(), 5, Vec::new()).unwrap();
+        let error = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(reader.digest(), None);
+
+        let expected = expected_digests(None, None, Some(HELLO_MD5)).unwrap();
+        assert!(DigestReader::new("".as_bytes(), 0, expected).is_err());
+        let reader = DigestReader::new("".as_bytes(), 0, Vec::new()).unwrap();
+        let hex = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!(to_hex(&reader.digest().unwrap()), hex);
+        assert_eq!(sha256_from_hex(hex), reader.digest());
+        assert_eq!(sha256_from_hex(&hex[1..]), None);
+        assert_eq!(sha256_from_hex(&hex.replace('e', "g")), None);
+        let mut reader = Sha256Reader::new("hello".as_bytes());
+        assert_eq!(to_hex(&reader.digest()), hex);
+        reader.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(base64_encode(&reader.digest()), HELLO_SHA256);
+    }
+}
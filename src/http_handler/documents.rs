@@ -0,0 +1,175 @@
+use super::conditional::EntityTag;
+use super::files::from_address_to_path;
+use super::json::*;
+use super::response::ResponseStatus;
+use super::state::*;
+use super::store::write_atomically;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// The JSON documents of the API that clients can change with 'PATCH': the state, and the JSON
+// files of the data directory served through 'tuples.rs'
+#[derive(Debug, PartialEq, Clone)]
+pub enum Document {
+    State,
+    File(PathBuf),
+}
+
+// Changes to the files are made one at a time, so that each is based on the latest version
+static FILE_DOCUMENTS: Mutex<()> = Mutex::new(());
+
+// The entity tag of a document held in memory, which changes with its content
+pub fn document_etag(document: &JsonValue) -> EntityTag {
+    return EntityTag::from_content(document.to_json_string().as_bytes());
+}
+
+// Whether a file is one of the JSON documents of the data directory. They are tagged by their
+// content, whichever encoding they are sent in, so that 'If-Match' works with any tag a client
+// got from 'GET'.
+pub fn is_document_path(path: &Path) -> bool {
+    return path.starts_with(DATA_DIR) && path.extension().is_some_and(|ext| ext == "json");
+}
+
+fn failed(context: &str, e: std::io::Error) -> ResponseStatus {
+    println!("{}: {}", context, e);
+    match e.kind() {
+        std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded => {
+            return ResponseStatus::InsufficientStorage
+        }
+        _ => return ResponseStatus::InternalServerError,
+    }
+}
+
+// A changed document that couldn't be loaded again, or not received as a body, isn't kept
+fn check_bounds(document: &JsonValue, length: usize) -> Result<(), ResponseStatus> {
+    if document.depth() > MAX_NESTING_DEPTH {
+        println!(
+            "Refusing a document nested deeper than {}",
+            MAX_NESTING_DEPTH
+        );
+        return Err(ResponseStatus::Conflict);
+    }
+    if length as u64 > MAX_JSON_BODY_SIZE {
+        println!("Refusing a document of {} bytes", length);
+        return Err(ResponseStatus::PayloadTooLarge);
+    }
+    return Ok(());
+}
+
+impl Document {
+    pub fn from_target(target: &str) -> Option<Self> {
+        if target == STATUS_TARGET {
+            return Some(Self::State);
+        }
+        let path = Path::new(from_address_to_path(target));
+        if is_document_path(path) {
+            return Some(Self::File(path.to_path_buf()));
+        }
+        return None;
+    }
+
+    // Replace the document with what `change` makes of it, given its current version and entity
+    // tag. Returns the new version and its tag. A file is replaced in one rename; the state must
+    // stay an object, or the change is a conflict. Either must stay parseable, and no larger
+    // than a JSON body, so that it can be loaded again and sent back whole.
+    pub fn update(
+        &self,
+        change: impl FnOnce(&JsonValue, &EntityTag) -> Result<JsonValue, ResponseStatus>,
+    ) -> Result<(JsonValue, EntityTag), ResponseStatus> {
+        match self {
+            Self::State => {
+                let state = replace_state(|current| {
+                    let changed = change(current, &document_etag(current))?;
+                    if !matches!(changed, JsonValue::Object(_)) {
+                        println!("Refusing to replace the state with a non-object");
+                        return Err(ResponseStatus::Conflict);
+                    }
+                    check_bounds(&changed, changed.to_json_string().len())?;
+                    return Ok(changed);
+                })?;
+                let etag = document_etag(&state);
+                return Ok((state, etag));
+            }
+            Self::File(path) => {
+                let _lock = FILE_DOCUMENTS.lock().unwrap_or_else(|e| e.into_inner());
+                let context = format!("Failed to update {}", path.display());
+                let text = std::fs::read_to_string(path).map_err(|e| failed(&context, e))?;
+                let current = match JsonValue::parse(&text) {
+                    Ok(current) => current,
+                    Err(e) => {
+                        println!("{}: {} at line {}", context, e.message, e.line);
+                        return Err(ResponseStatus::InternalServerError);
+                    }
+                };
+                let changed = change(&current, &EntityTag::from_content(text.as_bytes()))?;
+                let content = format!("{}\n", changed.to_json_string());
+                check_bounds(&changed, content.len() - 1)?;
+                write_atomically(path, &mut content.as_bytes(), Some(content.len() as u64))
+                    .map_err(|e| failed(&context, e))?;
+                return Ok((changed, EntityTag::from_content(content.as_bytes())));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn document_updates() {
+        assert_eq!(Document::from_target("/api/status"), Some(Document::State));
+        assert_eq!(Document::from_target("/index.html"), None);
+        assert_eq!(Document::from_target("/api/nothing"), None);
+
+        let dir =
+            std::env::temp_dir().join(format!("http-server-rust-documents-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("settings.json");
+        std::fs::write(&path, "{\"a\": 1}").unwrap();
+        let document = Document::File(path.clone());
+        let mut seen = None;
+        let (value, etag) = document
+            .update(|current, etag| {
+                seen = Some(etag.to_header_value());
+                let mut changed = current.clone();
+                changed.set("b", JsonValue::Bool(true));
+                return Ok(changed);
+            })
+            .unwrap();
+        assert_eq!(value.to_json_string(), "{\"a\":1,\"b\":true}");
+        assert_ne!(seen, Some(etag.to_header_value()));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "{\"a\":1,\"b\":true}\n"
+        );
+
+        // A refused change leaves the file as it is
+        let e = document.update(|_, _| Err(ResponseStatus::PreconditionFailed));
+        assert_eq!(e, Err(ResponseStatus::PreconditionFailed));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "{\"a\":1,\"b\":true}\n"
+        );
+        // So does a change the file couldn't be loaded again after
+        let e = document.update(|current, _| {
+            let mut changed = current.clone();
+            for _ in 0..MAX_NESTING_DEPTH {
+                changed = JsonValue::Array(vec![changed]);
+            }
+            return Ok(changed);
+        });
+        assert_eq!(e, Err(ResponseStatus::Conflict));
+        let e = document.update(|_, _| {
+            let large = "x".repeat(MAX_JSON_BODY_SIZE as usize);
+            return Ok(JsonValue::from_str_value(&large));
+        });
+        assert_eq!(e, Err(ResponseStatus::PayloadTooLarge));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "{\"a\":1,\"b\":true}\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,378 @@
+use std::io::{Read, Write};
+
+// Bodies smaller than this are not worth compressing: the framing overhead eats the gain
+pub const COMPRESSION_MIN_SIZE: u64 = 512;
+// Bodies larger than this are sent as they are using the zero-copy path, compressing them would
+// require holding the whole file in memory
+pub const COMPRESSION_MAX_SIZE: u64 = 8 * 1024 * 1024;
+const BROTLI_BUFFER_SIZE: usize = 4096;
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW_SIZE: u32 = 22;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ContentEncoding {
+    Identity,
+    Gzip,
+    // The zlib format (RFC 1950), despite the name
+    Deflate,
+    Brotli,
+}
+
+impl ContentEncoding {
+    pub fn from_token(token: &str) -> Option<Self> {
+        match token.to_lowercase().as_str() {
+            "identity" => Some(Self::Identity),
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            "deflate" => Some(Self::Deflate),
+            "br" => Some(Self::Brotli),
+            _ => None,
+        }
+    }
+
+    // A specific variant is mapped to a 'Content-Encoding' value
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            Self::Identity => "identity",
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+            Self::Brotli => "br",
+        }
+    }
+}
+
+// Parse a q-value ("0", "0.5", "1.000"...) into thousandths to avoid comparing floats
+fn parse_qvalue(input: &str) -> Option<u16> {
+    let (integer, fraction) = match input.split_once('.') {
+        Some((integer, fraction)) => (integer, fraction),
+        None => (input, ""),
+    };
+    if fraction.len() > 3 || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let fraction_value = format!("{:0<3}", fraction).parse::<u16>().ok()?;
+    match integer {
+        "0" => Some(fraction_value),
+        "1" if fraction_value == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+// Split an 'Accept-Encoding' value into (coding, q-value) pairs. Entries with an invalid q-value
+// are ignored.
+pub fn parse_accept_encoding(accept_encoding: &str) -> Vec<(String, u16)> {
+    let mut ret_codings: Vec<(String, u16)> = Vec::new();
+    for entry in accept_encoding.split(',') {
+        let mut parts = entry.split(';');
+        let coding = parts.next().unwrap_or("").trim().to_lowercase();
+        if coding.is_empty() {
+            continue;
+        }
+        let mut qvalue: Option<u16> = Some(1000);
+        for parameter in parts {
+            if let Some((name, value)) = parameter.split_once('=') {
+                if name.trim().eq_ignore_ascii_case("q") {
+                    qvalue = parse_qvalue(value.trim());
+                }
+            }
+        }
+        if let Some(qvalue) = qvalue {
+            ret_codings.push((coding, qvalue));
+        }
+    }
+    return ret_codings;
+}
+
+// Pick the best encoding among the `available` ones (in order of server preference) according to
+// the client's 'Accept-Encoding' (RFC 9110, section 12.5.3). Without the header, or when nothing
+// else is acceptable, the content is sent as it is.
+pub fn negotiate_encoding(
+    accept_encoding: Option<&str>,
+    available: &[ContentEncoding],
+) -> ContentEncoding {
+    let codings = match accept_encoding {
+        Some(accept_encoding) => parse_accept_encoding(accept_encoding),
+        None => return ContentEncoding::Identity,
+    };
+    let wildcard_qvalue = codings
+        .iter()
+        .find(|(coding, _)| coding == "*")
+        .map(|(_, qvalue)| *qvalue);
+    let mut best: (ContentEncoding, u16) = (ContentEncoding::Identity, 0);
+    for encoding in available {
+        let explicit_qvalue = codings
+            .iter()
+            .find(|(coding, _)| ContentEncoding::from_token(coding).as_ref() == Some(encoding))
+            .map(|(_, qvalue)| *qvalue);
+        let qvalue = match (explicit_qvalue, wildcard_qvalue) {
+            (Some(qvalue), _) => qvalue,
+            (None, Some(qvalue)) => qvalue,
+            // 'identity' is always acceptable unless explicitly excluded
+            (None, None) if *encoding == ContentEncoding::Identity => 1,
+            (None, None) => 0,
+        };
+        if qvalue > best.1 {
+            best = (encoding.clone(), qvalue);
+        }
+    }
+    return best.0;
+}
+
+// The precompressed sibling of a file, e.g. 'www/index.html.gz'
+pub fn sidecar_path(path: &str, encoding: &ContentEncoding) -> String {
+    match encoding {
+        ContentEncoding::Identity => String::from(path),
+        ContentEncoding::Gzip => format!("{}.gz", path),
+        ContentEncoding::Deflate => format!("{}.zz", path),
+        ContentEncoding::Brotli => format!("{}.br", path),
+    }
+}
+
+// The encodings for which an up-to-date precompressed sibling of `path` exists, in order of
+// preference. Sidecars older than the original are ignored as stale.
+pub fn precompressed_encodings(path: &str) -> Vec<ContentEncoding> {
+    let mut ret_encodings: Vec<ContentEncoding> = Vec::new();
+    let original_modified = match std::fs::metadata(path).and_then(|m| m.modified()) {
+        Ok(modified) => modified,
+        Err(_) => return ret_encodings,
+    };
+    for encoding in [ContentEncoding::Brotli, ContentEncoding::Gzip] {
+        if let Ok(modified) =
+            std::fs::metadata(sidecar_path(path, &encoding)).and_then(|m| m.modified())
+        {
+            if modified >= original_modified {
+                ret_encodings.push(encoding);
+            }
+        }
+    }
+    return ret_encodings;
+}
+
+pub fn compress(data: &[u8], encoding: &ContentEncoding) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        ContentEncoding::Identity => Ok(data.to_vec()),
+        ContentEncoding::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        ContentEncoding::Deflate => {
+            let mut encoder =
+                flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        ContentEncoding::Brotli => {
+            let mut encoder = brotli::CompressorWriter::new(
+                Vec::new(),
+                BROTLI_BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW_SIZE,
+            );
+            encoder.write_all(data)?;
+            encoder.flush()?;
+            Ok(encoder.into_inner())
+        }
+    }
+}
+
+// The content coding of a request body, from its 'Content-Encoding'. `None` if the server can't
+// decode it, including when several codings were applied one after the other.
+pub fn request_encoding(content_encoding: Option<&str>) -> Option<ContentEncoding> {
+    let codings = content_encoding
+        .unwrap_or("")
+        .split(',')
+        .map(|coding| coding.trim())
+        .filter(|coding| !coding.is_empty() && !coding.eq_ignore_ascii_case("identity"))
+        .collect::<Vec<&str>>();
+    match codings.as_slice() {
+        [] => return Some(ContentEncoding::Identity),
+        [coding] => return ContentEncoding::from_token(coding),
+        _ => return None,
+    }
+}
+
+enum BodyDecoder<R: Read> {
+    Identity(R),
+    Gzip(flate2::read::GzDecoder<R>),
+    Deflate(flate2::read::ZlibDecoder<R>),
+    Brotli(Box<brotli::Decompressor<R>>),
+}
+
+// A request body with its content coding removed. Compressed bodies may not decompress to more
+// than `limit` bytes, which fails with `FileTooLarge`: a few kilobytes can expand to gigabytes.
+// Whatever follows the compressed data is read and ignored, so that the end of the body is
+// reached.
+pub struct DecodedBody<R: Read> {
+    decoder: BodyDecoder<R>,
+    remaining: u64,
+}
+
+impl<R: Read> DecodedBody<R> {
+    pub fn new(reader: R, encoding: &ContentEncoding, limit: u64) -> Self {
+        return Self {
+            decoder: match encoding {
+                ContentEncoding::Identity => BodyDecoder::Identity(reader),
+                ContentEncoding::Gzip => BodyDecoder::Gzip(flate2::read::GzDecoder::new(reader)),
+                ContentEncoding::Deflate => {
+                    BodyDecoder::Deflate(flate2::read::ZlibDecoder::new(reader))
+                }
+                ContentEncoding::Brotli => BodyDecoder::Brotli(Box::new(
+                    brotli::Decompressor::new(reader, BROTLI_BUFFER_SIZE),
+                )),
+            },
+            remaining: limit,
+        };
+    }
+
+    pub fn is_identity(&self) -> bool {
+        return matches!(self.decoder, BodyDecoder::Identity(_));
+    }
+
+    pub fn get_ref(&self) -> &R {
+        match &self.decoder {
+            BodyDecoder::Identity(reader) => reader,
+            BodyDecoder::Gzip(decoder) => decoder.get_ref(),
+            BodyDecoder::Deflate(decoder) => decoder.get_ref(),
+            BodyDecoder::Brotli(decoder) => decoder.get_ref(),
+        }
+    }
+
+    fn get_mut(&mut self) -> &mut R {
+        match &mut self.decoder {
+            BodyDecoder::Identity(reader) => reader,
+            BodyDecoder::Gzip(decoder) => decoder.get_mut(),
+            BodyDecoder::Deflate(decoder) => decoder.get_mut(),
+            BodyDecoder::Brotli(decoder) => decoder.get_mut(),
+        }
+    }
+}
+
+impl<R: Read> Read for DecodedBody<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = match &mut self.decoder {
+            BodyDecoder::Identity(reader) => return reader.read(buf),
+            BodyDecoder::Gzip(decoder) => decoder.read(buf)?,
+            BodyDecoder::Deflate(decoder) => decoder.read(buf)?,
+            BodyDecoder::Brotli(decoder) => decoder.read(buf)?,
+        };
+        if bytes_read == 0 && !buf.is_empty() {
+            std::io::copy(self.get_mut(), &mut std::io::sink())?;
+        }
+        if bytes_read as u64 > self.remaining {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::FileTooLarge,
+                "body decompresses to more than the limit",
+            ));
+        }
+        self.remaining -= bytes_read as u64;
+        return Ok(bytes_read);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    const ALL: [ContentEncoding; 3] = [
+        ContentEncoding::Brotli,
+        ContentEncoding::Gzip,
+        ContentEncoding::Identity,
+    ];
+
+    #[test]
+    pub fn accept_encoding_negotiation() {
+        assert_eq!(negotiate_encoding(None, &ALL), ContentEncoding::Identity);
+        assert_eq!(
+            negotiate_encoding(Some(""), &ALL),
+            ContentEncoding::Identity
+        );
+        assert_eq!(
+            negotiate_encoding(Some("gzip, deflate, br"), &ALL),
+            ContentEncoding::Brotli
+        );
+        assert_eq!(
+            negotiate_encoding(Some("gzip, deflate, br;q=0.5"), &ALL),
+            ContentEncoding::Gzip
+        );
+        assert_eq!(
+            negotiate_encoding(Some("GZIP;Q=0.8, identity;q=0.9"), &ALL),
+            ContentEncoding::Identity
+        );
+        assert_eq!(negotiate_encoding(Some("*"), &ALL), ContentEncoding::Brotli);
+        assert_eq!(
+            negotiate_encoding(Some("*;q=0.2, br;q=0"), &ALL),
+            ContentEncoding::Gzip
+        );
+        assert_eq!(
+            negotiate_encoding(Some("br;q=2, gzip;q=0.001"), &ALL),
+            ContentEncoding::Gzip
+        );
+        assert_eq!(
+            negotiate_encoding(Some("deflate"), &ALL),
+            ContentEncoding::Identity
+        );
+        assert_eq!(
+            negotiate_encoding(
+                Some("br"),
+                &[ContentEncoding::Gzip, ContentEncoding::Identity]
+            ),
+            ContentEncoding::Identity
+        );
+    }
+
+    #[test]
+    pub fn compression_round_trip() {
+        let data = "Hello, TCP! ".repeat(200);
+        let compressed = compress(data.as_bytes(), &ContentEncoding::Gzip).unwrap();
+        assert!(compressed.len() < data.len());
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(compressed.as_slice())
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+
+        let compressed = compress(data.as_bytes(), &ContentEncoding::Brotli).unwrap();
+        assert!(compressed.len() < data.len());
+        let mut decompressed = String::new();
+        brotli::Decompressor::new(compressed.as_slice(), BROTLI_BUFFER_SIZE)
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    pub fn request_body_decoding() {
+        assert_eq!(request_encoding(None), Some(ContentEncoding::Identity));
+        assert_eq!(request_encoding(Some("GZIP")), Some(ContentEncoding::Gzip));
+        assert_eq!(
+            request_encoding(Some("identity, deflate")),
+            Some(ContentEncoding::Deflate)
+        );
+        assert_eq!(request_encoding(Some("gzip, br")), None);
+        assert_eq!(request_encoding(Some("zstd")), None);
+
+        let data = "{\"status\": \"ok\"} ".repeat(100);
+        for encoding in [
+            ContentEncoding::Gzip,
+            ContentEncoding::Deflate,
+            ContentEncoding::Brotli,
+        ] {
+            let mut compressed = compress(data.as_bytes(), &encoding).unwrap();
+            compressed.extend_from_slice(b"trailing");
+            let mut body = DecodedBody::new(compressed.as_slice(), &encoding, 10000);
+            let mut decoded = String::new();
+            body.read_to_string(&mut decoded).unwrap();
+            assert_eq!(decoded, data);
+            assert!(body.get_ref().is_empty());
+
+            // Decompressing past the limit stops there
+            let mut body = DecodedBody::new(compressed.as_slice(), &encoding, 1000);
+            let e = body.read_to_end(&mut Vec::new()).unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::FileTooLarge);
+        }
+        let mut body = DecodedBody::new(&b"not gzip"[..], &ContentEncoding::Gzip, 1000);
+        assert!(body.read_to_end(&mut Vec::new()).is_err());
+        let mut body = DecodedBody::new(data.as_bytes(), &ContentEncoding::Identity, 0);
+        assert_eq!(body.read_to_end(&mut Vec::new()).unwrap(), data.len());
+    }
+}
@@ -0,0 +1,619 @@
+// Documents nested deeper than this are refused rather than risking the stack
+pub const MAX_NESTING_DEPTH: usize = 128;
+
+// A JSON document. Object members keep their order, which makes the output predictable.
+#[derive(Debug, PartialEq, Clone)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    pub fn from_str_value(value: &str) -> Self {
+        return Self::String(String::from(value));
+    }
+
+    // Parse a complete JSON text (RFC 8259). Members repeated in an object keep their last value.
+    pub fn parse(text: &str) -> Result<Self, JsonError> {
+        let mut parser = Parser { text, position: 0 };
+        parser.skip_whitespace();
+        let ret_value = parser.parse_value(0)?;
+        parser.skip_whitespace();
+        if parser.position < text.len() {
+            return Err(parser.error("unexpected data after the JSON value"));
+        }
+        return Ok(ret_value);
+    }
+
+    // Parse a body that should be a JSON text, which is in UTF-8
+    pub fn from_utf8(body: &[u8]) -> Result<Self, JsonError> {
+        match std::str::from_utf8(body) {
+            Ok(text) => return Self::parse(text),
+            Err(e) => {
+                let valid = std::str::from_utf8(&body[..e.valid_up_to()]).unwrap_or_default();
+                return Err(JsonError::at(valid, valid.len(), "invalid UTF-8"));
+            }
+        }
+    }
+
+    // Set an object's member, replacing its value in place if it exists. Other values are left
+    // untouched.
+    pub fn set(&mut self, name: &str, value: JsonValue) {
+        if let Self::Object(members) = self {
+            match members.iter_mut().find(|(member, _)| member == name) {
+                Some((_, member_value)) => *member_value = value,
+                None => members.push((String::from(name), value)),
+            }
+        }
+    }
+
+    // Remove an object's member. Returns its value, if there was one.
+    pub fn remove(&mut self, name: &str) -> Option<JsonValue> {
+        match self {
+            Self::Object(members) => {
+                let index = members.iter().position(|(member, _)| member == name)?;
+                return Some(members.remove(index).1);
+            }
+            _ => return None,
+        }
+    }
+
+    // The value a JSON Pointer's reference tokens lead to, if any (RFC 6901): a token names an
+    // object's member, or the index of an array's element
+    pub fn pointer(&self, tokens: &[String]) -> Option<&JsonValue> {
+        let mut ret_value = self;
+        for token in tokens {
+            ret_value = match ret_value {
+                Self::Object(members) => &members.iter().find(|(member, _)| member == token)?.1,
+                Self::Array(values) => values.get(array_index(token)?)?,
+                _ => return None,
+            };
+        }
+        return Some(ret_value);
+    }
+
+    pub fn pointer_mut(&mut self, tokens: &[String]) -> Option<&mut JsonValue> {
+        let mut ret_value = self;
+        for token in tokens {
+            ret_value = match ret_value {
+                Self::Object(members) => {
+                    &mut members.iter_mut().find(|(member, _)| member == token)?.1
+                }
+                Self::Array(values) => values.get_mut(array_index(token)?)?,
+                _ => return None,
+            };
+        }
+        return Some(ret_value);
+    }
+
+    // How deeply the innermost value is nested, as counted against `MAX_NESTING_DEPTH` when
+    // parsing: 0 for a scalar or an empty array or object
+    pub fn depth(&self) -> usize {
+        let children_depth = |values: &mut dyn Iterator<Item = &JsonValue>| {
+            values.map(|value| value.depth() + 1).max().unwrap_or(0)
+        };
+        match self {
+            Self::Array(values) => return children_depth(&mut values.iter()),
+            Self::Object(members) => return children_depth(&mut members.iter().map(|(_, v)| v)),
+            _ => return 0,
+        }
+    }
+
+    // Whether two values are the same JSON: objects are equal if they have the same members,
+    // whatever their order (RFC 6902, section 4.6)
+    pub fn json_eq(&self, other: &JsonValue) -> bool {
+        match (self, other) {
+            (Self::Object(members), Self::Object(other_members)) => {
+                return members.len() == other_members.len()
+                    && members.iter().all(|(name, value)| {
+                        other_members.iter().any(|(other_name, other_value)| {
+                            name == other_name && value.json_eq(other_value)
+                        })
+                    });
+            }
+            (Self::Array(values), Self::Array(other_values)) => {
+                return values.len() == other_values.len()
+                    && values
+                        .iter()
+                        .zip(other_values)
+                        .all(|(value, other_value)| value.json_eq(other_value));
+            }
+            _ => return self == other,
+        }
+    }
+
+    // The compact serialization of the value (RFC 8259)
+    pub fn to_json_string(&self) -> String {
+        let mut ret_string = String::new();
+        self.write_to(&mut ret_string);
+        return ret_string;
+    }
+
+    fn write_to(&self, output: &mut String) {
+        match self {
+            Self::Null => output.push_str("null"),
+            Self::Bool(value) => output.push_str(if *value { "true" } else { "false" }),
+            Self::Number(value) => output.push_str(&format_number(*value)),
+            Self::String(value) => write_string(value, output),
+            Self::Array(values) => {
+                output.push('[');
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        output.push(',');
+                    }
+                    value.write_to(output);
+                }
+                output.push(']');
+            }
+            Self::Object(members) => {
+                output.push('{');
+                for (index, (name, value)) in members.iter().enumerate() {
+                    if index > 0 {
+                        output.push(',');
+                    }
+                    write_string(name, output);
+                    output.push(':');
+                    value.write_to(output);
+                }
+                output.push('}');
+            }
+        }
+    }
+}
+
+// Integers are written without a fractional part; JSON has no representation for NaN and
+// infinities, they become null
+fn format_number(value: f64) -> String {
+    if !value.is_finite() {
+        return String::from("null");
+    }
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        return format!("{}", value as i64);
+    }
+    return format!("{}", value);
+}
+
+fn write_string(value: &str, output: &mut String) {
+    output.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => output.push_str(&format!("\\u{:04x}", c as u32)),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+}
+
+// The index of an array's element in a JSON Pointer: decimal digits, without leading zeros
+pub fn array_index(token: &str) -> Option<usize> {
+    if token.is_empty()
+        || !token.bytes().all(|byte| byte.is_ascii_digit())
+        || (token.starts_with('0') && token.len() > 1)
+    {
+        return None;
+    }
+    return token.parse::<usize>().ok();
+}
+
+// The reference tokens of a JSON Pointer, e.g. ["a", "b/c"] for "/a/b~1c". `None` if it isn't
+// one: a pointer is empty or starts with '/', and '~' only escapes '0' ('~') or '1' ('/').
+pub fn parse_pointer(pointer: &str) -> Option<Vec<String>> {
+    if pointer.is_empty() {
+        return Some(Vec::new());
+    }
+    let mut ret_tokens: Vec<String> = Vec::new();
+    for token in pointer.strip_prefix('/')?.split('/') {
+        let mut unescaped = String::new();
+        let mut chars = token.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '~' => match chars.next() {
+                    Some('0') => unescaped.push('~'),
+                    Some('1') => unescaped.push('/'),
+                    _ => return None,
+                },
+                c => unescaped.push(c),
+            }
+        }
+        ret_tokens.push(unescaped);
+    }
+    return Some(ret_tokens);
+}
+
+// Why a text isn't valid JSON, and where: `offset` counts bytes, `line` and `column` characters,
+// both from 1
+#[derive(Debug, PartialEq)]
+pub struct JsonError {
+    pub message: String,
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl JsonError {
+    // An error at byte `offset` of `text`
+    pub fn at(text: &str, offset: usize, message: &str) -> Self {
+        let before = &text[..offset];
+        let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+        return Self {
+            message: String::from(message),
+            offset,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        };
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        return JsonValue::Object(vec![
+            (
+                String::from("error"),
+                JsonValue::from_str_value("malformed JSON"),
+            ),
+            (
+                String::from("message"),
+                JsonValue::from_str_value(&self.message),
+            ),
+            (String::from("line"), JsonValue::Number(self.line as f64)),
+            (
+                String::from("column"),
+                JsonValue::Number(self.column as f64),
+            ),
+            (
+                String::from("offset"),
+                JsonValue::Number(self.offset as f64),
+            ),
+        ]);
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> JsonError {
+        return JsonError::at(self.text, self.position, message);
+    }
+
+    fn peek(&self) -> Option<u8> {
+        return self.text.as_bytes().get(self.position).copied();
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.position += 1;
+        }
+    }
+
+    fn expect_literal(&mut self, literal: &str, value: JsonValue) -> Result<JsonValue, JsonError> {
+        if !self.text[self.position..].starts_with(literal) {
+            return Err(self.error(&format!("invalid literal, expected '{}'", literal)));
+        }
+        self.position += literal.len();
+        return Ok(value);
+    }
+
+    fn parse_value(&mut self, depth: usize) -> Result<JsonValue, JsonError> {
+        if depth > MAX_NESTING_DEPTH {
+            return Err(self.error("too deeply nested"));
+        }
+        match self.peek() {
+            None => return Err(self.error("unexpected end of input, expected a value")),
+            Some(b'n') => return self.expect_literal("null", JsonValue::Null),
+            Some(b't') => return self.expect_literal("true", JsonValue::Bool(true)),
+            Some(b'f') => return self.expect_literal("false", JsonValue::Bool(false)),
+            Some(b'"') => return Ok(JsonValue::String(self.parse_string()?)),
+            Some(b'-' | b'0'..=b'9') => return self.parse_number(),
+            Some(b'[') => {
+                self.position += 1;
+                let mut values: Vec<JsonValue> = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(b']') {
+                    self.position += 1;
+                    return Ok(JsonValue::Array(values));
+                }
+                loop {
+                    self.skip_whitespace();
+                    values.push(self.parse_value(depth + 1)?);
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b',') => self.position += 1,
+                        Some(b']') => {
+                            self.position += 1;
+                            return Ok(JsonValue::Array(values));
+                        }
+                        _ => return Err(self.error("expected ',' or ']' in array")),
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.position += 1;
+                let mut ret_object = JsonValue::Object(Vec::new());
+                self.skip_whitespace();
+                if self.peek() == Some(b'}') {
+                    self.position += 1;
+                    return Ok(ret_object);
+                }
+                loop {
+                    self.skip_whitespace();
+                    if self.peek() != Some(b'"') {
+                        return Err(self.error("expected a member name in object"));
+                    }
+                    let name = self.parse_string()?;
+                    self.skip_whitespace();
+                    if self.peek() != Some(b':') {
+                        return Err(self.error("expected ':' after member name"));
+                    }
+                    self.position += 1;
+                    self.skip_whitespace();
+                    let value = self.parse_value(depth + 1)?;
+                    ret_object.set(&name, value);
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b',') => self.position += 1,
+                        Some(b'}') => {
+                            self.position += 1;
+                            return Ok(ret_object);
+                        }
+                        _ => return Err(self.error("expected ',' or '}' in object")),
+                    }
+                }
+            }
+            Some(_) => return Err(self.error("unexpected character, expected a value")),
+        }
+    }
+
+    // -?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?
+    fn parse_number(&mut self) -> Result<JsonValue, JsonError> {
+        let start = self.position;
+        if self.peek() == Some(b'-') {
+            self.position += 1;
+        }
+        match self.peek() {
+            Some(b'0') => self.position += 1,
+            Some(b'1'..=b'9') => self.skip_digits(),
+            _ => return Err(self.error("expected a digit")),
+        }
+        if self.peek() == Some(b'.') {
+            self.position += 1;
+            if !self.peek().is_some_and(|byte| byte.is_ascii_digit()) {
+                return Err(self.error("expected a digit after the decimal point"));
+            }
+            self.skip_digits();
+        }
+        if matches!(self.peek(), Some(b'e' | b'E')) {
+            self.position += 1;
+            if matches!(self.peek(), Some(b'+' | b'-')) {
+                self.position += 1;
+            }
+            if !self.peek().is_some_and(|byte| byte.is_ascii_digit()) {
+                return Err(self.error("expected a digit in the exponent"));
+            }
+            self.skip_digits();
+        }
+        match self.text[start..self.position].parse::<f64>() {
+            Ok(value) if value.is_finite() => return Ok(JsonValue::Number(value)),
+            _ => {
+                self.position = start;
+                return Err(self.error("number out of range"));
+            }
+        }
+    }
+
+    fn skip_digits(&mut self) {
+        while self.peek().is_some_and(|byte| byte.is_ascii_digit()) {
+            self.position += 1;
+        }
+    }
+
+    fn parse_hex_escape(&mut self) -> Result<u16, JsonError> {
+        let digits = self.text.get(self.position..self.position + 4);
+        match digits.and_then(|digits| u16::from_str_radix(digits, 16).ok()) {
+            Some(unit) if digits.is_some_and(|d| d.bytes().all(|b| b.is_ascii_hexdigit())) => {
+                self.position += 4;
+                return Ok(unit);
+            }
+            _ => return Err(self.error("expected 4 hexadecimal digits after '\\u'")),
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, JsonError> {
+        // Skip the opening quote
+        self.position += 1;
+        let mut ret_string = String::new();
+        loop {
+            let c = match self.text[self.position..].chars().next() {
+                Some(c) => c,
+                None => return Err(self.error("unterminated string")),
+            };
+            match c {
+                '"' => {
+                    self.position += 1;
+                    return Ok(ret_string);
+                }
+                '\\' => {
+                    self.position += 1;
+                    let escaped = match self.peek() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            self.position += 1;
+                            let escape_start = self.position - 2;
+                            let unit = self.parse_hex_escape()?;
+                            let mut units = vec![unit];
+                            // A high surrogate must be followed by the escape of a low one
+                            if (0xd800..0xdc00).contains(&unit)
+                                && self.text[self.position..].starts_with("\\u")
+                            {
+                                self.position += 2;
+                                units.push(self.parse_hex_escape()?);
+                            }
+                            match char::decode_utf16(units).collect::<Result<String, _>>() {
+                                Ok(decoded) => ret_string.push_str(&decoded),
+                                Err(_) => {
+                                    self.position = escape_start;
+                                    return Err(self.error("invalid surrogate in '\\u' escape"));
+                                }
+                            }
+                            continue;
+                        }
+                        _ => return Err(self.error("invalid escape sequence")),
+                    };
+                    self.position += 1;
+                    ret_string.push(escaped);
+                }
+                c if (c as u32) < 0x20 => {
+                    return Err(self.error("control character in string"));
+                }
+                c => {
+                    self.position += c.len_utf8();
+                    ret_string.push(c);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn serialization() {
+        let value = JsonValue::Object(vec![
+            (
+                String::from("id"),
+                JsonValue::from_str_value("a\"b\\c\n\u{1}é"),
+            ),
+            (
+                String::from("values"),
+                JsonValue::Array(vec![
+                    JsonValue::Number(42.0),
+                    JsonValue::Number(-0.5),
+                    JsonValue::Number(f64::NAN),
+                ]),
+            ),
+            (String::from("empty"), JsonValue::Object(Vec::new())),
+        ]);
+        assert_eq!(
+            value.to_json_string(),
+            "{\"id\":\"a\\\"b\\\\c\\n\\u0001é\",\"values\":[42,-0.5,null],\"empty\":{}}"
+        );
+    }
+
+    #[test]
+    pub fn parsing() {
+        let value = JsonValue::parse(
+            " {\"a\": [1, -2.5e3, true, false, null], \"b\": {\"c\": \"\\u00e9\\ud83d\\ude00\\n\"},\
+             \"a\": 0} ",
+        )
+        .unwrap();
+        assert_eq!(
+            value.to_json_string(),
+            "{\"a\":0,\"b\":{\"c\":\"\u{e9}\u{1f600}\\n\"}}"
+        );
+        assert_eq!(
+            JsonValue::parse("\"x\"").unwrap(),
+            JsonValue::from_str_value("x")
+        );
+        assert_eq!(
+            JsonValue::parse("[]").unwrap(),
+            JsonValue::Array(Vec::new())
+        );
+
+        // Where and why a text isn't JSON
+        let e = JsonValue::parse("{\n  \"a\": tru\n}").unwrap_err();
+        assert_eq!((e.offset, e.line, e.column), (9, 2, 8));
+        assert_eq!(e.message, "invalid literal, expected 'true'");
+        for invalid in [
+            "",
+            "{",
+            "[1,]",
+            "{\"a\" 1}",
+            "{a: 1}",
+            "01",
+            "1.",
+            "-",
+            "1e",
+            "\"\\x\"",
+            "\"a\nb\"",
+            "\"\\ud800\"",
+            "\"\\u12\"",
+            "1 2",
+            "1e999",
+            "'a'",
+        ] {
+            assert!(JsonValue::parse(invalid).is_err(), "{:?}", invalid);
+        }
+        let nested = format!("{}{}", "[".repeat(200), "]".repeat(200));
+        assert!(JsonValue::parse(&nested).is_err());
+        let nested = format!("{}{}", "[".repeat(100), "]".repeat(100));
+        assert!(JsonValue::parse(&nested).is_ok());
+        // The deepest document that parses is as deep as `depth` allows
+        let depth = MAX_NESTING_DEPTH + 1;
+        let nested = format!("{}1{}", "[".repeat(depth - 1), "]".repeat(depth - 1));
+        assert_eq!(
+            JsonValue::parse(&nested).unwrap().depth(),
+            MAX_NESTING_DEPTH
+        );
+        let nested = format!("{}1{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(JsonValue::parse(&nested).is_err());
+        assert_eq!(JsonValue::parse("{\"a\":[]}").unwrap().depth(), 1);
+    }
+
+    #[test]
+    pub fn pointers() {
+        // The examples of RFC 6901, section 5
+        let document = JsonValue::parse(
+            "{\"foo\": [\"bar\", \"baz\"], \"\": 0, \"a/b\": 1, \"c%d\": 2, \"e^f\": 3, \"g|h\": 4,\
+             \"i\\\\j\": 5, \"k\\\"l\": 6, \" \": 7, \"m~n\": 8}",
+        )
+        .unwrap();
+        let resolve = |pointer: &str| {
+            parse_pointer(pointer).and_then(|tokens| {
+                document
+                    .pointer(&tokens)
+                    .map(|value| value.to_json_string())
+            })
+        };
+        assert_eq!(resolve(""), Some(document.to_json_string()));
+        assert_eq!(resolve("/foo").as_deref(), Some("[\"bar\",\"baz\"]"));
+        assert_eq!(resolve("/foo/0").as_deref(), Some("\"bar\""));
+        for (pointer, value) in [
+            ("/", "0"),
+            ("/a~1b", "1"),
+            ("/c%d", "2"),
+            ("/e^f", "3"),
+            ("/g|h", "4"),
+            ("/i\\j", "5"),
+            ("/k\"l", "6"),
+            ("/ ", "7"),
+            ("/m~0n", "8"),
+        ] {
+            assert_eq!(resolve(pointer).as_deref(), Some(value), "{}", pointer);
+        }
+        for unresolved in [
+            "/foo/2", "/foo/-", "/foo/01", "/foo/+1", "/bar", "/a~1b/c", "foo",
+        ] {
+            assert_eq!(resolve(unresolved), None, "{}", unresolved);
+        }
+        assert_eq!(parse_pointer("/a~2"), None);
+        assert_eq!(parse_pointer("/~01"), Some(vec![String::from("~1")]));
+    }
+}
@@ -1,4 +1,27 @@
+pub mod blobs;
+pub mod charset;
+pub mod collections;
 pub mod common;
+pub mod conditional;
+pub mod config;
+pub mod date;
+pub mod digest;
+pub mod documents;
+pub mod encoding;
 pub mod files;
+pub mod json;
+pub mod multipart;
+pub mod patch;
+pub mod progress;
+pub mod quota;
+pub mod range;
 pub mod request;
 pub mod response;
+pub mod resumable;
+pub mod routes;
+pub mod sniff;
+pub mod state;
+pub mod store;
+pub mod sweeper;
+pub mod uploads;
+pub mod urlencoded;
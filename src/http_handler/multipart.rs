@@ -0,0 +1,487 @@
+use super::common::parse_parameters;
+use super::progress::Transfer;
+use super::sniff::*;
+use super::uploads::{sanitize_file_name, UploadMetadata, UploadStore, UploadWriter};
+use super::urlencoded::FormFields;
+use std::io::{Read, Write};
+
+const BUFF_READ_SIZE: usize = 32768;
+// The header section of a single part is tiny in practice: bigger ones are rejected
+const MAX_PART_HEADER_SIZE: usize = 8192;
+const MAX_BOUNDARY_LENGTH: usize = 70;
+// Text fields are kept in memory, files are streamed to the upload store
+const MAX_FORM_FIELD_SIZE: u64 = 64 * 1024;
+const MAX_FORM_FILE_SIZE: u64 = 1024 * 1024 * 1024;
+const MAX_FORM_PARTS: usize = 64;
+
+fn invalid_data(message: &str) -> std::io::Error {
+    return std::io::Error::new(std::io::ErrorKind::InvalidData, String::from(message));
+}
+
+// What the 'Content-Disposition' and 'Content-Type' of a part say about it
+#[derive(Debug, PartialEq)]
+pub struct PartHeaders {
+    pub name: String,
+    // Set for file inputs, even when empty (no file selected)
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+}
+
+impl PartHeaders {
+    fn parse(header_lines: &[String]) -> std::io::Result<Self> {
+        let mut ret_headers = Self {
+            name: String::new(),
+            filename: None,
+            content_type: None,
+        };
+        let mut has_disposition = false;
+        for line in header_lines.iter() {
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => return Err(invalid_data("malformed part header")),
+            };
+            if name.eq_ignore_ascii_case("Content-Disposition") {
+                let (disposition, parameters) = match value.find(';') {
+                    Some(index) => (&value[..index], &value[index..]),
+                    None => (value, ""),
+                };
+                if !disposition.trim().eq_ignore_ascii_case("form-data") {
+                    return Err(invalid_data("part is not form-data"));
+                }
+                let parameters = parse_parameters(parameters)
+                    .ok_or_else(|| invalid_data("malformed Content-Disposition"))?;
+                for (parameter, value) in parameters {
+                    match parameter.as_str() {
+                        "name" => ret_headers.name = value,
+                        "filename" => ret_headers.filename = Some(value),
+                        _ => {}
+                    }
+                }
+                has_disposition = true;
+            } else if name.eq_ignore_ascii_case("Content-Type") {
+                ret_headers.content_type = Some(String::from(value));
+            }
+        }
+        if !has_disposition {
+            return Err(invalid_data("part without Content-Disposition"));
+        }
+        return Ok(ret_headers);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum MultipartState {
+    // Before the first delimiter
+    Preamble,
+    // The headers of the current part were read, its content wasn't
+    PartContent,
+    // Right after a delimiter, the content of the previous part was read
+    Delimiter,
+    Done,
+}
+
+// A streaming 'multipart/form-data' parser (RFC 7578): parts are handed out one at a time and
+// their content is copied to a writer without ever holding a whole part in memory
+pub struct MultipartReader<R: Read> {
+    reader: R,
+    // "\r\n--" followed by the boundary
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    state: MultipartState,
+}
+
+impl<R: Read> MultipartReader<R> {
+    pub fn new(reader: R, boundary: &str) -> std::io::Result<Self> {
+        if boundary.is_empty() || boundary.len() > MAX_BOUNDARY_LENGTH {
+            return Err(invalid_data("invalid boundary"));
+        }
+        return Ok(Self {
+            reader,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // The first delimiter may be at the very beginning of the body: pretend it's preceded
+            // by a line break like all the others
+            buffer: b"\r\n".to_vec(),
+            state: MultipartState::Preamble,
+        });
+    }
+
+    // Read from the underlying reader until the buffer holds at least `size` bytes. Returns false
+    // if the body ended before.
+    fn fill(&mut self, size: usize) -> std::io::Result<bool> {
+        let mut chunk: [u8; BUFF_READ_SIZE] = [0; BUFF_READ_SIZE];
+        while self.buffer.len() < size {
+            let bytes_read = self.reader.read(&mut chunk)?;
+            if bytes_read == 0 {
+                return Ok(false);
+            }
+            self.buffer.extend_from_slice(&chunk[0..bytes_read]);
+        }
+        return Ok(true);
+    }
+
+    // Copy everything up to the next delimiter to `sink`, and consume the delimiter. Fails if
+    // more than `limit` bytes precede it.
+    fn read_until_delimiter(&mut self, sink: &mut impl Write, limit: u64) -> std::io::Result<u64> {
+        let mut copied: u64 = 0;
+        loop {
+            let position = self
+                .buffer
+                .windows(self.delimiter.len())
+                .position(|window| window == self.delimiter.as_slice());
+            // Without a delimiter in sight, its beginning might be at the end of the buffer
+            let available = match position {
+                Some(position) => position,
+                None => self.buffer.len().saturating_sub(self.delimiter.len() - 1),
+            };
+            copied += available as u64;
+            if copied > limit {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::FileTooLarge,
+                    format!("part larger than {} bytes", limit),
+                ));
+            }
+            sink.write_all(&self.buffer[0..available])?;
+            self.buffer.drain(0..available);
+            if position.is_some() {
+                self.buffer.drain(0..self.delimiter.len());
+                return Ok(copied);
+            }
+            let wanted = self.buffer.len() + BUFF_READ_SIZE;
+            if !self.fill(wanted)? && !self.contains_delimiter() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "body ended before the closing delimiter",
+                ));
+            }
+        }
+    }
+
+    fn contains_delimiter(&self) -> bool {
+        return self
+            .buffer
+            .windows(self.delimiter.len())
+            .any(|window| window == self.delimiter.as_slice());
+    }
+
+    // Read a header line (without its CRLF) from the buffer
+    fn read_line(&mut self, consumed: &mut usize) -> std::io::Result<String> {
+        loop {
+            if let Some(position) = self.buffer.windows(2).position(|window| window == b"\r\n") {
+                let line = String::from_utf8_lossy(&self.buffer[0..position]).into_owned();
+                self.buffer.drain(0..position + 2);
+                *consumed += position + 2;
+                return Ok(line);
+            }
+            if *consumed + self.buffer.len() > MAX_PART_HEADER_SIZE {
+                return Err(invalid_data("part header too large"));
+            }
+            let wanted = self.buffer.len() + 1;
+            if !self.fill(wanted)? {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "body ended in a part header",
+                ));
+            }
+        }
+    }
+
+    // Move on to the next part and return its headers, or `None` after the last one. The content
+    // of the current part is skipped if it wasn't read.
+    pub fn next_part(&mut self) -> std::io::Result<Option<PartHeaders>> {
+        match self.state {
+            MultipartState::Done => return Ok(None),
+            MultipartState::Preamble | MultipartState::PartContent => {
+                self.read_until_delimiter(&mut std::io::sink(), u64::MAX)?;
+            }
+            MultipartState::Delimiter => {}
+        }
+        // A delimiter is followed by "--" on the last one, then optional whitespace and CRLF
+        if !self.fill(2)? {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "body ended after a delimiter",
+            ));
+        }
+        if self.buffer.starts_with(b"--") {
+            self.state = MultipartState::Done;
+            return Ok(None);
+        }
+        let mut consumed: usize = 0;
+        let padding = self.read_line(&mut consumed)?;
+        if !padding.trim_matches([' ', '\t']).is_empty() {
+            return Err(invalid_data("garbage after delimiter"));
+        }
+        let mut header_lines: Vec<String> = Vec::new();
+        loop {
+            let line = self.read_line(&mut consumed)?;
+            if line.is_empty() {
+                break;
+            }
+            header_lines.push(line);
+        }
+        self.state = MultipartState::PartContent;
+        return PartHeaders::parse(&header_lines).map(Some);
+    }
+
+    // Copy the content of the current part to `sink`, failing with `FileTooLarge` beyond `limit`
+    // bytes. Returns the size of the content.
+    pub fn read_part(&mut self, sink: &mut impl Write, limit: u64) -> std::io::Result<u64> {
+        if self.state != MultipartState::PartContent {
+            return Ok(0);
+        }
+        let size = self.read_until_delimiter(sink, limit)?;
+        self.state = MultipartState::Delimiter;
+        return Ok(size);
+    }
+}
+
+// A file received through a form and saved as an upload
+#[derive(Debug, PartialEq)]
+pub struct FormFile {
+    // The name of the form field
+    pub field: String,
+    pub id: String,
+    // The sanitized name of the file on the client's side
+    pub filename: String,
+    pub size: u64,
+    // The SHA-256 digest of the file, in hex
+    pub sha256: String,
+}
+
+// A submitted 'multipart/form-data' body: the text fields and the files it contained
+#[derive(Debug, PartialEq, Default)]
+pub struct FormData {
+    pub fields: FormFields,
+    pub files: Vec<FormFile>,
+}
+
+impl FormData {
+    // Read the whole form, saving every file part as a new upload of `store` sent by
+    // `client_address`, attached to `transfer`, its type checked under `policy`. On failure, the
+    // files saved so far are deleted.
+    pub fn receive(
+        reader: impl Read,
+        boundary: &str,
+        store: &UploadStore,
+        client_address: &str,
+        transfer: &Transfer,
+        policy: SniffPolicy,
+    ) -> std::io::Result<Self> {
+        let mut ret_form = Self::default();
+        let result =
+            ret_form.receive_parts(reader, boundary, store, client_address, transfer, policy);
+        if let Err(e) = result {
+            ret_form.discard(store);
+            return Err(e);
+        }
+        return Ok(ret_form);
+    }
+
+    fn receive_parts(
+        &mut self,
+        reader: impl Read,
+        boundary: &str,
+        store: &UploadStore,
+        client_address: &str,
+        transfer: &Transfer,
+        policy: SniffPolicy,
+    ) -> std::io::Result<()> {
+        let mut multipart = MultipartReader::new(reader, boundary)?;
+        let mut part_count: usize = 0;
+        while let Some(part) = multipart.next_part()? {
+            part_count += 1;
+            if part_count > MAX_FORM_PARTS {
+                return Err(invalid_data("too many parts"));
+            }
+            match part.filename {
+                Some(filename) => {
+                    let mut upload = UploadWriter::create(store)?;
+                    transfer.attach(&upload.id);
+                    if let Err(e) = multipart.read_part(&mut upload, MAX_FORM_FILE_SIZE) {
+                        upload.abort();
+                        return Err(e);
+                    }
+                    // A file input left empty is still sent, with no name and no content
+                    if filename.is_empty() && upload.size == 0 {
+                        upload.abort();
+                        continue;
+                    }
+                    let filename = sanitize_file_name(&filename);
+                    let mut metadata =
+                        UploadMetadata::new(part.content_type.as_deref().unwrap_or(""));
+                    metadata.filename = filename.clone();
+                    metadata.client_address = String::from(client_address);
+                    if let Err(e) = check_content_type(&mut metadata, upload.head(), policy) {
+                        upload.abort();
+                        return Err(e);
+                    }
+                    let id = upload.finish(&mut metadata)?;
+                    self.files.push(FormFile {
+                        field: part.name,
+                        id,
+                        filename,
+                        size: metadata.size,
+                        sha256: metadata.sha256,
+                    });
+                }
+                None => {
+                    let mut value: Vec<u8> = Vec::new();
+                    multipart.read_part(&mut value, MAX_FORM_FIELD_SIZE)?;
+                    let value = String::from_utf8(value)
+                        .map_err(|_| invalid_data("form field is not UTF-8"))?;
+                    self.fields.push((part.name, value));
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    // Delete the uploads created for the form's files
+    pub fn discard(&self, store: &UploadStore) {
+        for file in self.files.iter() {
+            let _ = store.remove(&file.id);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::http_handler::uploads::test::TempUploadStore;
+
+    const BODY: &str = "preamble\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"firstName\"\r\n\
+        \r\n\
+        Ada\r\n\
+        --XyZ  \r\n\
+        Content-Disposition: form-data; name=\"upload\"; filename=\"a \\\"b\\\".txt\"\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        line 1\r\n--Xy not yet\r\nline 2\r\n\
+        --XyZ--\r\n\
+        epilogue";
+
+    // Reads at most `chunk_size` bytes at a time, to exercise delimiters split across reads
+    struct SlowReader<'a> {
+        data: &'a [u8],
+        chunk_size: usize,
+    }
+
+    impl Read for SlowReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let size = self.chunk_size.min(buf.len()).min(self.data.len());
+            buf[0..size].copy_from_slice(&self.data[0..size]);
+            self.data = &self.data[size..];
+            return Ok(size);
+        }
+    }
+
+    #[test]
+    pub fn form_parts() {
+        for chunk_size in [1, 2, 3, 7, 1000] {
+            let reader = SlowReader {
+                data: BODY.as_bytes(),
+                chunk_size,
+            };
+            let mut multipart = MultipartReader::new(reader, "XyZ").unwrap();
+            let part = multipart.next_part().unwrap().unwrap();
+            assert_eq!(part.name, "firstName");
+            assert_eq!(part.filename, None);
+            let mut content: Vec<u8> = Vec::new();
+            assert_eq!(multipart.read_part(&mut content, 100).unwrap(), 3);
+            assert_eq!(content, b"Ada");
+
+            let part = multipart.next_part().unwrap().unwrap();
+            assert_eq!(part.name, "upload");
+            assert_eq!(part.filename, Some(String::from("a \"b\".txt")));
+            assert_eq!(part.content_type, Some(String::from("text/plain")));
+            let mut content: Vec<u8> = Vec::new();
+            multipart.read_part(&mut content, 100).unwrap();
+            assert_eq!(content, b"line 1\r\n--Xy not yet\r\nline 2");
+            assert_eq!(multipart.next_part().unwrap(), None);
+            assert_eq!(multipart.next_part().unwrap(), None);
+        }
+    }
+
+    #[test]
+    pub fn skipped_parts_and_limits() {
+        let mut multipart = MultipartReader::new(BODY.as_bytes(), "XyZ").unwrap();
+        assert_eq!(multipart.next_part().unwrap().unwrap().name, "firstName");
+        assert_eq!(multipart.next_part().unwrap().unwrap().name, "upload");
+        let error = multipart.read_part(&mut std::io::sink(), 10).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::FileTooLarge);
+    }
+
+    #[test]
+    pub fn malformed_bodies() {
+        let truncated = &BODY[0..BODY.find("line 2").unwrap()];
+        let mut multipart = MultipartReader::new(truncated.as_bytes(), "XyZ").unwrap();
+        multipart.next_part().unwrap();
+        multipart.next_part().unwrap();
+        let error = multipart.read_part(&mut std::io::sink(), 100).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        let mut multipart = MultipartReader::new("no delimiter".as_bytes(), "XyZ").unwrap();
+        assert!(multipart.next_part().is_err());
+
+        let body = "--XyZ\r\nContent-Type: text/plain\r\n\r\nx\r\n--XyZ--";
+        let mut multipart = MultipartReader::new(body.as_bytes(), "XyZ").unwrap();
+        assert_eq!(
+            multipart.next_part().unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+        assert!(MultipartReader::new("".as_bytes(), "").is_err());
+    }
+
+    #[test]
+    pub fn form_data() {
+        let store = TempUploadStore::new("form-data", false, None);
+        let transfer = Transfer::start("192.0.2.1", BODY.len() as u64);
+        let form = FormData::receive(
+            BODY.as_bytes(),
+            "XyZ",
+            &store,
+            "192.0.2.1",
+            &transfer,
+            SniffPolicy::Reject,
+        )
+        .unwrap();
+        assert_eq!(
+            form.fields,
+            vec![(String::from("firstName"), String::from("Ada"))]
+        );
+        assert_eq!(form.files.len(), 1);
+        assert_eq!(form.files[0].field, "upload");
+        assert_eq!(form.files[0].filename, "a _b_.txt");
+        assert_eq!(form.files[0].size, 28);
+        let progress = crate::http_handler::progress::upload_progress(&form.files[0].id).unwrap();
+        assert_eq!(progress.ids.len(), form.files.len());
+        let upload_dir = store.dir.join(&form.files[0].id);
+        let metadata = UploadMetadata::read(&upload_dir).unwrap();
+        assert_eq!(metadata.content_type, "text/plain");
+        assert_eq!(metadata.filename, "a _b_.txt");
+        assert_eq!(metadata.size, 28);
+        assert_eq!(metadata.client_address, "192.0.2.1");
+
+        // Nothing is left behind when the body is cut short
+        let truncated = &BODY[0..BODY.find("line 2").unwrap()];
+        let policy = SniffPolicy::Reject;
+        assert!(
+            FormData::receive(truncated.as_bytes(), "XyZ", &store, "", &transfer, policy).is_err()
+        );
+        assert_eq!(std::fs::read_dir(&store.dir).unwrap().count(), 1);
+
+        // Nor when a file isn't what it claims
+        let fake_image = BODY.replace("text/plain", "image/jpeg");
+        let e = FormData::receive(fake_image.as_bytes(), "XyZ", &store, "", &transfer, policy);
+        assert_eq!(e.unwrap_err().kind(), std::io::ErrorKind::Unsupported);
+        assert_eq!(std::fs::read_dir(&store.dir).unwrap().count(), 1);
+
+        let empty_file =
+            "--b\r\nContent-Disposition: form-data; name=\"f\"; filename=\"\"\r\n\r\n\r\n--b--";
+        let form =
+            FormData::receive(empty_file.as_bytes(), "b", &store, "", &transfer, policy).unwrap();
+        assert_eq!(form, FormData::default());
+    }
+}
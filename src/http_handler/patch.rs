@@ -0,0 +1,427 @@
+use super::json::*;
+use super::state::MAX_JSON_BODY_SIZE;
+
+pub const JSON_PATCH_CONTENT_TYPE: &str = "application/json-patch+json";
+pub const MERGE_PATCH_CONTENT_TYPE: &str = "application/merge-patch+json";
+// Beyond this, a JSON Patch is refused as malformed
+const MAX_PATCH_OPERATIONS: usize = 100;
+
+// Why a patch wasn't applied
+#[derive(Debug, PartialEq)]
+pub enum PatchError {
+    // The patch document isn't a valid patch: 400 Bad Request
+    Malformed(String),
+    // An operation can't be applied to the document, or a 'test' failed: 409 Conflict
+    Conflict(String),
+    // The operations copy more than a JSON body may hold: 413 Payload Too Large
+    TooLarge(String),
+}
+
+// A change to a JSON document, as sent with 'PATCH'
+#[derive(Debug, PartialEq)]
+pub enum JsonPatch {
+    // A list of operations applied in order, all or nothing (RFC 6902)
+    Operations(Vec<PatchOperation>),
+    // A document mirroring the target: its members replace the target's, recursively, null
+    // removing them (RFC 7396)
+    Merge(JsonValue),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PatchOperation {
+    Add {
+        path: Vec<String>,
+        value: JsonValue,
+    },
+    Remove {
+        path: Vec<String>,
+    },
+    Replace {
+        path: Vec<String>,
+        value: JsonValue,
+    },
+    Move {
+        from: Vec<String>,
+        path: Vec<String>,
+    },
+    Copy {
+        from: Vec<String>,
+        path: Vec<String>,
+    },
+    Test {
+        path: Vec<String>,
+        value: JsonValue,
+    },
+}
+
+fn malformed(message: String) -> PatchError {
+    return PatchError::Malformed(message);
+}
+
+impl PatchOperation {
+    fn parse(operation: &JsonValue) -> Result<Self, PatchError> {
+        let member = |name: &str| match operation {
+            JsonValue::Object(members) => members
+                .iter()
+                .find(|(member, _)| member == name)
+                .map(|(_, value)| value),
+            _ => None,
+        };
+        let pointer = |name: &str| match member(name) {
+            Some(JsonValue::String(pointer)) => parse_pointer(pointer)
+                .ok_or_else(|| malformed(format!("invalid JSON Pointer {:?}", pointer))),
+            _ => Err(malformed(format!("missing '{}' pointer", name))),
+        };
+        let value = || {
+            member("value")
+                .cloned()
+                .ok_or_else(|| malformed(String::from("missing 'value'")))
+        };
+        match member("op") {
+            Some(JsonValue::String(op)) => match op.as_str() {
+                "add" => {
+                    return Ok(Self::Add {
+                        path: pointer("path")?,
+                        value: value()?,
+                    })
+                }
+                "remove" => {
+                    return Ok(Self::Remove {
+                        path: pointer("path")?,
+                    })
+                }
+                "replace" => {
+                    return Ok(Self::Replace {
+                        path: pointer("path")?,
+                        value: value()?,
+                    })
+                }
+                "move" => {
+                    return Ok(Self::Move {
+                        from: pointer("from")?,
+                        path: pointer("path")?,
+                    })
+                }
+                "copy" => {
+                    return Ok(Self::Copy {
+                        from: pointer("from")?,
+                        path: pointer("path")?,
+                    })
+                }
+                "test" => {
+                    return Ok(Self::Test {
+                        path: pointer("path")?,
+                        value: value()?,
+                    })
+                }
+                _ => return Err(malformed(format!("unknown operation {:?}", op))),
+            },
+            _ => return Err(malformed(String::from("missing 'op'"))),
+        }
+    }
+}
+
+fn conflict(message: &str, path: &[String]) -> PatchError {
+    return PatchError::Conflict(format!("{} at /{}", message, path.join("/")));
+}
+
+// Add `value` at `path`: a new member, a new element before the one at the index, or after the
+// last one for "-", or the whole document for the empty path
+fn add(document: &mut JsonValue, path: &[String], value: JsonValue) -> Result<(), PatchError> {
+    let (last, parent) = match path.split_last() {
+        Some(split) => split,
+        None => {
+            *document = value;
+            return Ok(());
+        }
+    };
+    match document.pointer_mut(parent) {
+        Some(object @ JsonValue::Object(_)) => object.set(last, value),
+        Some(JsonValue::Array(values)) => match array_index(last) {
+            Some(index) if index <= values.len() => values.insert(index, value),
+            None if last == "-" => values.push(value),
+            _ => return Err(conflict("no such index", path)),
+        },
+        _ => return Err(conflict("no object or array", parent)),
+    }
+    return Ok(());
+}
+
+fn remove(document: &mut JsonValue, path: &[String]) -> Result<JsonValue, PatchError> {
+    let (last, parent) = match path.split_last() {
+        Some(split) => split,
+        None => return Err(conflict("the document can't be removed", path)),
+    };
+    let removed = match document.pointer_mut(parent) {
+        Some(object @ JsonValue::Object(_)) => object.remove(last),
+        Some(JsonValue::Array(values)) => match array_index(last) {
+            Some(index) if index < values.len() => Some(values.remove(index)),
+            _ => None,
+        },
+        _ => None,
+    };
+    return removed.ok_or_else(|| conflict("no value", path));
+}
+
+impl JsonPatch {
+    // The patch in a body of the given media type. `None` if the type isn't a patch format.
+    pub fn parse(content_type: &str, body: JsonValue) -> Option<Result<Self, PatchError>> {
+        match content_type {
+            MERGE_PATCH_CONTENT_TYPE => return Some(Ok(Self::Merge(body))),
+            JSON_PATCH_CONTENT_TYPE => match body {
+                JsonValue::Array(operations) if operations.len() > MAX_PATCH_OPERATIONS => {
+                    return Some(Err(malformed(format!(
+                        "more than {} operations",
+                        MAX_PATCH_OPERATIONS
+                    ))))
+                }
+                JsonValue::Array(operations) => {
+                    return Some(
+                        operations
+                            .iter()
+                            .map(PatchOperation::parse)
+                            .collect::<Result<Vec<PatchOperation>, PatchError>>()
+                            .map(Self::Operations),
+                    );
+                }
+                _ => {
+                    return Some(Err(malformed(String::from(
+                        "a JSON Patch is an array of operations",
+                    ))))
+                }
+            },
+            _ => return None,
+        }
+    }
+
+    // The patched document. The document is left as it is if any operation fails. Each 'copy'
+    // may double the document, so all together they may copy no more than a JSON body's size.
+    pub fn apply(&self, document: &JsonValue) -> Result<JsonValue, PatchError> {
+        let mut ret_document = document.clone();
+        let mut copied: u64 = 0;
+        match self {
+            Self::Merge(patch) => merge_patch(&mut ret_document, patch),
+            Self::Operations(operations) => {
+                for operation in operations {
+                    match operation {
+                        PatchOperation::Add { path, value } => {
+                            add(&mut ret_document, path, value.clone())?
+                        }
+                        PatchOperation::Remove { path } => {
+                            remove(&mut ret_document, path)?;
+                        }
+                        PatchOperation::Replace { path, value } => {
+                            match ret_document.pointer_mut(path) {
+                                Some(target) => *target = value.clone(),
+                                None => return Err(conflict("no value", path)),
+                            }
+                        }
+                        PatchOperation::Move { from, path } => {
+                            // A value can't be moved into itself
+                            if path.len() > from.len() && path.starts_with(from) {
+                                return Err(conflict("can't move a value into itself", from));
+                            }
+                            let value = remove(&mut ret_document, from)?;
+                            add(&mut ret_document, path, value)?;
+                        }
+                        PatchOperation::Copy { from, path } => {
+                            let value = match ret_document.pointer(from) {
+                                Some(value) => value.clone(),
+                                None => return Err(conflict("no value", from)),
+                            };
+                            copied += value.to_json_string().len() as u64;
+                            if copied > MAX_JSON_BODY_SIZE {
+                                return Err(PatchError::TooLarge(String::from("too much copied")));
+                            }
+                            add(&mut ret_document, path, value)?;
+                        }
+                        PatchOperation::Test { path, value } => {
+                            if !ret_document
+                                .pointer(path)
+                                .is_some_and(|target| target.json_eq(value))
+                            {
+                                return Err(conflict("test failed", path));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        return Ok(ret_document);
+    }
+}
+
+// RFC 7396, section 2
+fn merge_patch(target: &mut JsonValue, patch: &JsonValue) {
+    let members = match patch {
+        JsonValue::Object(members) => members,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+    if !matches!(target, JsonValue::Object(_)) {
+        *target = JsonValue::Object(Vec::new());
+    }
+    for (name, value) in members {
+        if *value == JsonValue::Null {
+            target.remove(name);
+            continue;
+        }
+        match target.pointer_mut(std::slice::from_ref(name)) {
+            Some(member) => merge_patch(member, value),
+            None => {
+                let mut member = JsonValue::Null;
+                merge_patch(&mut member, value);
+                target.set(name, member);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn patch(content_type: &str, document: &str, patch: &str) -> Result<String, PatchError> {
+        let document = JsonValue::parse(document).unwrap();
+        let patch = JsonPatch::parse(content_type, JsonValue::parse(patch).unwrap()).unwrap()?;
+        return patch
+            .apply(&document)
+            .map(|document| document.to_json_string());
+    }
+
+    #[test]
+    pub fn json_patch() {
+        let json_patch =
+            |document: &str, operations: &str| patch(JSON_PATCH_CONTENT_TYPE, document, operations);
+        // Examples of RFC 6902, appendix A
+        assert_eq!(
+            json_patch(
+                "{\"foo\":\"bar\"}",
+                "[{\"op\":\"add\",\"path\":\"/baz\",\"value\":\"qux\"}]"
+            ),
+            Ok(String::from("{\"foo\":\"bar\",\"baz\":\"qux\"}"))
+        );
+        assert_eq!(
+            json_patch(
+                "{\"foo\":[\"bar\",\"baz\"]}",
+                "[{\"op\":\"add\",\"path\":\"/foo/1\",\"value\":\"qux\"}]"
+            ),
+            Ok(String::from("{\"foo\":[\"bar\",\"qux\",\"baz\"]}"))
+        );
+        assert_eq!(
+            json_patch(
+                "{\"baz\":\"qux\",\"foo\":\"bar\"}",
+                "[{\"op\":\"remove\",\"path\":\"/baz\"},\
+                  {\"op\":\"replace\",\"path\":\"/foo\",\"value\":\"boo\"}]"
+            ),
+            Ok(String::from("{\"foo\":\"boo\"}"))
+        );
+        assert_eq!(
+            json_patch(
+                "{\"foo\":{\"bar\":\"baz\",\"waldo\":\"fred\"},\"qux\":{\"corge\":\"grault\"}}",
+                "[{\"op\":\"move\",\"from\":\"/foo/waldo\",\"path\":\"/qux/thud\"}]"
+            ),
+            Ok(String::from(
+                "{\"foo\":{\"bar\":\"baz\"},\"qux\":{\"corge\":\"grault\",\"thud\":\"fred\"}}"
+            ))
+        );
+        assert_eq!(
+            json_patch(
+                "{\"foo\":[\"all\",\"grass\",\"cows\",\"eat\"]}",
+                "[{\"op\":\"move\",\"from\":\"/foo/1\",\"path\":\"/foo/3\"}]"
+            ),
+            Ok(String::from(
+                "{\"foo\":[\"all\",\"cows\",\"eat\",\"grass\"]}"
+            ))
+        );
+        assert_eq!(
+            json_patch(
+                "{\"baz\":{\"a\":1,\"b\":[1,2]}}",
+                "[{\"op\":\"test\",\"path\":\"/baz\",\"value\":{\"b\":[1,2],\"a\":1}},\
+                  {\"op\":\"copy\",\"from\":\"/baz/b\",\"path\":\"/baz/b/-\"}]"
+            ),
+            Ok(String::from("{\"baz\":{\"a\":1,\"b\":[1,2,[1,2]]}}"))
+        );
+
+        // A failed operation fails the whole patch
+        let e = json_patch(
+            "{\"baz\":\"qux\"}",
+            "[{\"op\":\"add\",\"path\":\"/a\",\"value\":1},\
+              {\"op\":\"test\",\"path\":\"/baz\",\"value\":\"bar\"}]",
+        );
+        assert_eq!(
+            e,
+            Err(PatchError::Conflict(String::from("test failed at /baz")))
+        );
+        for operations in [
+            "[{\"op\":\"add\",\"path\":\"/baz/bat\",\"value\":\"qux\"}]",
+            "[{\"op\":\"remove\",\"path\":\"/a\"}]",
+            "[{\"op\":\"replace\",\"path\":\"/a\",\"value\":1}]",
+            "[{\"op\":\"add\",\"path\":\"/list/3\",\"value\":1}]",
+            "[{\"op\":\"move\",\"from\":\"/list\",\"path\":\"/list/0\"}]",
+        ] {
+            let e = json_patch("{\"baz\":\"qux\",\"list\":[1,2]}", operations);
+            assert!(matches!(e, Err(PatchError::Conflict(_))), "{}", operations);
+        }
+        for operations in [
+            "{\"op\":\"add\",\"path\":\"/a\",\"value\":1}",
+            "[{\"op\":\"add\",\"path\":\"/a\"}]",
+            "[{\"op\":\"jump\",\"path\":\"/a\"}]",
+            "[{\"path\":\"/a\"}]",
+            "[{\"op\":\"remove\",\"path\":\"a\"}]",
+        ] {
+            let e = json_patch("{}", operations);
+            assert!(matches!(e, Err(PatchError::Malformed(_))), "{}", operations);
+        }
+
+        // Copying the document into itself doubles it: this can't go on forever
+        let copy = "{\"op\":\"copy\",\"from\":\"\",\"path\":\"/a\"}";
+        let operations = format!("[{}]", vec![copy; MAX_PATCH_OPERATIONS + 1].join(","));
+        let e = json_patch("{}", &operations);
+        assert!(matches!(e, Err(PatchError::Malformed(_))));
+        let document = format!("{{\"b\":\"{}\"}}", "x".repeat(100_000));
+        let operations = format!("[{}]", vec![copy; 20].join(","));
+        let e = json_patch(&document, &operations);
+        assert!(matches!(e, Err(PatchError::TooLarge(_))));
+    }
+
+    #[test]
+    pub fn merge_patches() {
+        // Examples of RFC 7396, appendix A
+        for (document, merge, result) in [
+            (
+                "{\"a\":\"b\",\"z\":0}",
+                "{\"a\":\"c\"}",
+                "{\"a\":\"c\",\"z\":0}",
+            ),
+            (
+                "{\"a\":\"b\"}",
+                "{\"b\":\"c\"}",
+                "{\"a\":\"b\",\"b\":\"c\"}",
+            ),
+            ("{\"a\":\"b\",\"b\":\"c\"}", "{\"a\":null}", "{\"b\":\"c\"}"),
+            ("{\"a\":[\"b\"]}", "{\"a\":\"c\"}", "{\"a\":\"c\"}"),
+            (
+                "{\"a\":{\"b\":\"c\"}}",
+                "{\"a\":{\"b\":\"d\",\"c\":null}}",
+                "{\"a\":{\"b\":\"d\"}}",
+            ),
+            ("{\"a\":\"foo\"}", "\"bar\"", "\"bar\""),
+            ("{\"e\":null}", "{\"a\":1}", "{\"e\":null,\"a\":1}"),
+            ("[1,2]", "{\"a\":\"b\",\"c\":null}", "{\"a\":\"b\"}"),
+            (
+                "{}",
+                "{\"a\":{\"bb\":{\"ccc\":null}}}",
+                "{\"a\":{\"bb\":{}}}",
+            ),
+        ] {
+            assert_eq!(
+                patch(MERGE_PATCH_CONTENT_TYPE, document, merge).as_deref(),
+                Ok(result)
+            );
+        }
+        assert!(JsonPatch::parse("application/json", JsonValue::Null).is_none());
+    }
+}
@@ -0,0 +1,371 @@
+use super::json::JsonValue;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How long the outcome of a finished transfer can still be queried
+const FINISHED_TRANSFER_RETENTION: Duration = Duration::from_secs(60);
+// How often event streams report on the transfers they follow
+pub const PROGRESS_EVENT_INTERVAL: Duration = Duration::from_millis(500);
+// How long an event stream lasts at most: it holds a connection, and clients reconnect anyway
+pub const MAX_EVENT_STREAM_DURATION: Duration = Duration::from_secs(300);
+
+// A request body being received, known by the IDs of the uploads it is written to: one for a
+// plain or resumable upload, one per file for a form
+#[derive(Debug, Clone)]
+struct TransferState {
+    key: u64,
+    ids: Vec<String>,
+    client_address: String,
+    received: u64,
+    total: u64,
+    started: Instant,
+    // When the transfer ended, and whether the body was accepted
+    finished: Option<(Instant, bool)>,
+}
+
+static TRANSFERS: Mutex<Vec<TransferState>> = Mutex::new(Vec::new());
+static TRANSFER_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn transfers() -> std::sync::MutexGuard<'static, Vec<TransferState>> {
+    return TRANSFERS.lock().unwrap_or_else(|e| e.into_inner());
+}
+
+// A handle on a transfer in the registry
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transfer {
+    key: u64,
+}
+
+impl Transfer {
+    // Register a body of `total` bytes sent by `client_address`. Transfers finished long ago are
+    // forgotten on the way.
+    pub fn start(client_address: &str, total: u64) -> Self {
+        let key = TRANSFER_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let mut transfers = transfers();
+        transfers.retain(|transfer| match transfer.finished {
+            Some((finished, _)) => finished.elapsed() < FINISHED_TRANSFER_RETENTION,
+            None => true,
+        });
+        transfers.push(TransferState {
+            key,
+            ids: Vec::new(),
+            client_address: String::from(client_address),
+            received: 0,
+            total,
+            started: Instant::now(),
+            finished: None,
+        });
+        return Self { key };
+    }
+
+    fn update(&self, change: impl FnOnce(&mut TransferState)) {
+        if let Some(transfer) = transfers().iter_mut().find(|t| t.key == self.key) {
+            change(transfer);
+        }
+    }
+
+    // The body is being written to the upload `id`
+    pub fn attach(&self, id: &str) {
+        self.update(|transfer| transfer.ids.push(String::from(id)));
+    }
+
+    // The body is a chunk of the resumable upload `id`, starting at `offset` of its `length`
+    // bytes: progress is that of the whole upload. Fails if another transfer is writing to it.
+    pub fn resume(&self, id: &str, offset: u64, length: u64) -> bool {
+        let mut transfers = transfers();
+        if transfers
+            .iter()
+            .any(|t| t.finished.is_none() && t.key != self.key && t.ids.iter().any(|i| i == id))
+        {
+            return false;
+        }
+        if let Some(transfer) = transfers.iter_mut().find(|t| t.key == self.key) {
+            transfer.ids.push(String::from(id));
+            transfer.received += offset;
+            transfer.total = length;
+        }
+        return true;
+    }
+
+    pub fn advance(&self, bytes: u64) {
+        self.update(|transfer| transfer.received += bytes);
+    }
+
+    pub fn finish(&self, accepted: bool) {
+        self.update(|transfer| transfer.finished = Some((Instant::now(), accepted)));
+    }
+}
+
+// Where a transfer stands
+#[derive(Debug, PartialEq)]
+pub struct ProgressSnapshot {
+    pub ids: Vec<String>,
+    pub received: u64,
+    pub total: u64,
+    // The average rate so far, in bytes per second
+    pub rate: u64,
+    // The seconds left at that rate, if it is known
+    pub eta: Option<u64>,
+    pub state: &'static str,
+}
+
+impl ProgressSnapshot {
+    fn from_state(transfer: &TransferState) -> Self {
+        let elapsed = transfer
+            .finished
+            .map_or(transfer.started.elapsed(), |(finished, _)| {
+                finished.duration_since(transfer.started)
+            })
+            .as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            (transfer.received as f64 / elapsed) as u64
+        } else {
+            0
+        };
+        let remaining = transfer.total.saturating_sub(transfer.received);
+        return Self {
+            ids: transfer.ids.clone(),
+            received: transfer.received,
+            total: transfer.total,
+            rate,
+            eta: match (transfer.finished, rate) {
+                (Some(_), _) => Some(0),
+                (None, 0) => None,
+                (None, rate) => Some(remaining.div_ceil(rate)),
+            },
+            state: match transfer.finished {
+                None => "receiving",
+                Some((_, true)) => "complete",
+                Some((_, false)) => "failed",
+            },
+        };
+    }
+
+    // A stored upload, received before
+    pub fn complete(id: &str, size: u64) -> Self {
+        return Self {
+            ids: vec![String::from(id)],
+            received: size,
+            total: size,
+            rate: 0,
+            eta: Some(0),
+            state: "complete",
+        };
+    }
+
+    pub fn is_finished(&self) -> bool {
+        return self.state != "receiving";
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut fields = vec![
+            (
+                String::from("uploads"),
+                JsonValue::Array(
+                    self.ids
+                        .iter()
+                        .map(|id| JsonValue::from_str_value(id))
+                        .collect(),
+                ),
+            ),
+            (
+                String::from("received"),
+                JsonValue::Number(self.received as f64),
+            ),
+            (String::from("total"), JsonValue::Number(self.total as f64)),
+            (String::from("rate"), JsonValue::Number(self.rate as f64)),
+            (String::from("state"), JsonValue::from_str_value(self.state)),
+        ];
+        if let Some(eta) = self.eta {
+            fields.push((String::from("eta"), JsonValue::Number(eta as f64)));
+        }
+        return JsonValue::Object(fields);
+    }
+}
+
+// The latest transfer to the upload `id`, if it is recent
+pub fn upload_progress(id: &str) -> Option<ProgressSnapshot> {
+    return transfers()
+        .iter()
+        .rev()
+        .find(|transfer| transfer.ids.iter().any(|i| i == id))
+        .map(ProgressSnapshot::from_state);
+}
+
+// The recent transfers of a client, with the key telling them apart
+fn client_progress(client_address: &str) -> Vec<(u64, ProgressSnapshot)> {
+    return transfers()
+        .iter()
+        .filter(|transfer| transfer.client_address == client_address)
+        .map(|transfer| (transfer.key, ProgressSnapshot::from_state(transfer)))
+        .collect();
+}
+
+// A body whose bytes count as the progress of a transfer
+pub struct ProgressReader<R> {
+    inner: R,
+    transfer: Transfer,
+}
+
+impl<R: Read> ProgressReader<R> {
+    pub fn new(inner: R, transfer: Transfer) -> Self {
+        return Self { inner, transfer };
+    }
+
+    pub fn transfer(&self) -> &Transfer {
+        return &self.transfer;
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        self.transfer.advance(bytes_read as u64);
+        return Ok(bytes_read);
+    }
+}
+
+// What a 'text/event-stream' response follows
+#[derive(Debug, PartialEq, Clone)]
+pub enum ProgressFeed {
+    // One upload, until its transfer is over
+    Upload(String),
+    // Every transfer of a client, while the connection stays open, up to `MAX_EVENT_STREAM_DURATION`
+    Client(String),
+}
+
+// A snapshot as a Server-Sent Event
+pub fn progress_event(snapshot: &ProgressSnapshot) -> String {
+    return format!(
+        "event: progress\ndata: {}\n\n",
+        snapshot.to_json().to_json_string()
+    );
+}
+
+fn write_event(out: &mut impl Write, snapshot: &ProgressSnapshot) -> std::io::Result<()> {
+    out.write_all(progress_event(snapshot).as_bytes())?;
+    return out.flush();
+}
+
+// Send Server-Sent Events reporting on the transfers of `feed`, one every `interval` while
+// something changes. Ends when the client goes away, when the upload followed is over, or after
+// `max_duration`.
+pub fn write_events(
+    feed: &ProgressFeed,
+    out: &mut impl Write,
+    interval: Duration,
+    max_duration: Duration,
+) -> std::io::Result<()> {
+    let started = std::time::Instant::now();
+    // What was last reported of each transfer; those over before the stream started are old news
+    let mut sent: Vec<(u64, u64, &'static str)> = match feed {
+        ProgressFeed::Client(client_address) => client_progress(client_address)
+            .into_iter()
+            .filter(|(_, snapshot)| snapshot.is_finished())
+            .map(|(key, snapshot)| (key, snapshot.received, snapshot.state))
+            .collect(),
+        ProgressFeed::Upload(_) => Vec::new(),
+    };
+    loop {
+        match feed {
+            ProgressFeed::Upload(id) => {
+                let snapshot = match upload_progress(id) {
+                    Some(snapshot) => snapshot,
+                    None => return Ok(()),
+                };
+                write_event(out, &snapshot)?;
+                if snapshot.is_finished() {
+                    return Ok(());
+                }
+            }
+            ProgressFeed::Client(client_address) => {
+                let mut changed = false;
+                for (key, snapshot) in client_progress(client_address) {
+                    let current = (key, snapshot.received, snapshot.state);
+                    if !sent.contains(&current) {
+                        sent.retain(|(sent_key, _, _)| *sent_key != key);
+                        sent.push(current);
+                        write_event(out, &snapshot)?;
+                        changed = true;
+                    }
+                }
+                // A comment keeps the connection alive, and tells when the client is gone
+                if !changed {
+                    out.write_all(b":\n\n")?;
+                    out.flush()?;
+                }
+            }
+        }
+        if started.elapsed() >= max_duration {
+            return Ok(());
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn transfer_progress() {
+        let id = "0000000000000000000000aa";
+        let transfer = Transfer::start("192.0.2.10", 10);
+        assert_eq!(upload_progress(id), None);
+        let mut reader = ProgressReader::new("hello world".as_bytes(), transfer.clone());
+        let mut buffer = [0; 4];
+        reader.read_exact(&mut buffer).unwrap();
+        transfer.attach(id);
+        let snapshot = upload_progress(id).unwrap();
+        assert_eq!(snapshot.received, 4);
+        assert_eq!(snapshot.total, 10);
+        assert_eq!(snapshot.state, "receiving");
+
+        // A second transfer can't write to a resumable upload being written to
+        let chunk = Transfer::start("192.0.2.10", 6);
+        assert!(!chunk.resume(id, 4, 20));
+        transfer.finish(true);
+        assert!(chunk.resume(id, 4, 20));
+        chunk.advance(6);
+        let snapshot = upload_progress(id).unwrap();
+        assert_eq!((snapshot.received, snapshot.total), (10, 20));
+        chunk.finish(false);
+        let snapshot = upload_progress(id).unwrap();
+        assert_eq!(snapshot.state, "failed");
+        assert_eq!(snapshot.eta, Some(0));
+        assert_eq!(
+            snapshot.to_json().to_json_string(),
+            format!(
+                "{{\"uploads\":[\"{}\"],\"received\":10,\"total\":20,\"rate\":{},\
+                 \"state\":\"failed\",\"eta\":0}}",
+                id, snapshot.rate
+            )
+        );
+
+        let mut events: Vec<u8> = Vec::new();
+        write_events(
+            &ProgressFeed::Upload(String::from(id)),
+            &mut events,
+            Duration::ZERO,
+            MAX_EVENT_STREAM_DURATION,
+        )
+        .unwrap();
+        let events = String::from_utf8(events).unwrap();
+        assert!(events.starts_with("event: progress\ndata: {\"uploads\""));
+        assert!(events.ends_with("\"state\":\"failed\",\"eta\":0}\n\n"));
+        assert_eq!(events.matches("event:").count(), 1);
+
+        // Following a client would never end by itself
+        let mut events: Vec<u8> = Vec::new();
+        write_events(
+            &ProgressFeed::Client(String::from("192.0.2.30")),
+            &mut events,
+            Duration::ZERO,
+            Duration::ZERO,
+        )
+        .unwrap();
+        assert_eq!(events, b":\n\n");
+    }
+}
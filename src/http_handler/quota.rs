@@ -0,0 +1,244 @@
+use super::config::config;
+use super::resumable::UPLOAD_INFO_FILE;
+use super::uploads::{is_valid_upload_id, UploadMetadata, UploadStore};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// How much disk space bodies may take. Uploads count with their size, whether or not their data
+// is shared with identical uploads, and until they are swept; uploads in progress count with the
+// length they announced.
+#[derive(Debug, PartialEq, Clone)]
+pub struct StorageLimits {
+    pub client_quota: Option<u64>,
+    pub global_quota: Option<u64>,
+    pub min_free_space: u64,
+}
+
+impl StorageLimits {
+    pub fn configured() -> Self {
+        return Self {
+            client_quota: config().client_quota,
+            global_quota: config().global_quota,
+            min_free_space: config().min_free_space,
+        };
+    }
+}
+
+// The space available on the file system holding `path`, or its closest existing ancestor
+pub fn free_space(path: &Path) -> std::io::Result<u64> {
+    extern "C" {
+        fn tcp_utils_free_space(path: *const std::os::raw::c_char) -> i64;
+    }
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(Path::new("."));
+    let c_string = std::ffi::CString::new(existing.as_os_str().as_encoded_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let free = unsafe { tcp_utils_free_space(c_string.as_ptr()) };
+    if free < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    return Ok(free as u64);
+}
+
+fn insufficient_storage(message: String) -> std::io::Error {
+    return std::io::Error::new(std::io::ErrorKind::StorageFull, message);
+}
+
+// Whether a body of `length` bytes can be written to `dir` and still leave the minimum free
+// space. Fails with `StorageFull` if not. Returns how many bytes may be written in all, at least
+// `length`.
+pub fn check_free_space(limits: &StorageLimits, dir: &Path, length: u64) -> std::io::Result<u64> {
+    let free = free_space(dir)?;
+    if length.saturating_add(limits.min_free_space) > free {
+        return Err(insufficient_storage(format!(
+            "{} bytes don't fit in {} free bytes (keeping {} free)",
+            length, free, limits.min_free_space
+        )));
+    }
+    return Ok(free - limits.min_free_space);
+}
+
+// The bodies being uploaded, counted against the quotas until they are complete or given up:
+// (reservation ID, store directory, client address, announced length)
+static RESERVATIONS: Mutex<Vec<(u64, PathBuf, String, u64)>> = Mutex::new(Vec::new());
+static NEXT_RESERVATION_ID: AtomicU64 = AtomicU64::new(0);
+
+// Room held for an upload in progress, given back when dropped
+#[derive(Debug)]
+pub struct QuotaReservation {
+    id: u64,
+    // How many bytes the upload may take in all, at least the length it announced
+    pub room: u64,
+}
+
+impl Drop for QuotaReservation {
+    fn drop(&mut self) {
+        let mut reservations = RESERVATIONS.lock().unwrap_or_else(|e| e.into_inner());
+        reservations.retain(|(id, _, _, _)| *id != self.id);
+    }
+}
+
+// How much the uploads of `store` take, for `client_address` and for all clients: complete
+// uploads with their size, resumable uploads in progress with the length they announced
+fn stored_usage(store: &UploadStore, client_address: &str) -> std::io::Result<(u64, u64)> {
+    let entries = match std::fs::read_dir(&store.dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((0, 0)),
+        Err(e) => return Err(e),
+    };
+    let mut client_usage: u64 = 0;
+    let mut global_usage: u64 = 0;
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_name().to_str().is_some_and(is_valid_upload_id) {
+            continue;
+        }
+        let upload_dir = entry.path();
+        let metadata = match UploadMetadata::read(&upload_dir) {
+            Ok(metadata) => metadata,
+            Err(_) => match std::fs::read_to_string(upload_dir.join(UPLOAD_INFO_FILE)) {
+                Ok(text) => UploadMetadata::parse(&text),
+                Err(_) => continue,
+            },
+        };
+        if metadata.client_address == client_address {
+            client_usage += metadata.size;
+        }
+        global_usage += metadata.size;
+    }
+    return Ok((client_usage, global_usage));
+}
+
+// Whether `client_address` may upload `length` more bytes to `store`, counting the uploads in
+// progress. Fails with `FileTooLarge` if the client's quota would be exceeded, `StorageFull` if
+// the global quota or the free space would be. The room is held until the reservation is dropped.
+pub fn check_upload(
+    limits: &StorageLimits,
+    store: &UploadStore,
+    client_address: &str,
+    length: u64,
+) -> std::io::Result<QuotaReservation> {
+    // Checked and reserved at once, or two uploads could both take the last of the room
+    let mut reservations = RESERVATIONS.lock().unwrap_or_else(|e| e.into_inner());
+    let (mut client_usage, mut global_usage) = (0, 0);
+    for (_, dir, reserved_client, reserved) in reservations.iter() {
+        if *dir == store.dir {
+            if reserved_client == client_address {
+                client_usage += reserved;
+            }
+            global_usage += reserved;
+        }
+    }
+    // The space reserved is yet to be taken from the disk
+    let free_room = check_free_space(limits, &store.dir, length.saturating_add(global_usage))?
+        .saturating_sub(global_usage);
+    let mut room = free_room;
+    if limits.client_quota.is_some() || limits.global_quota.is_some() {
+        let (client_stored, global_stored) = stored_usage(store, client_address)?;
+        client_usage += client_stored;
+        global_usage += global_stored;
+        if let Some(quota) = limits.client_quota {
+            if client_usage.saturating_add(length) > quota {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::FileTooLarge,
+                    format!(
+                        "{} would exceed its quota of {} bytes ({} used, {} more)",
+                        client_address, quota, client_usage, length
+                    ),
+                ));
+            }
+        }
+        if let Some(quota) = limits.global_quota {
+            if global_usage.saturating_add(length) > quota {
+                return Err(insufficient_storage(format!(
+                    "uploads would exceed their quota of {} bytes ({} used, {} more)",
+                    quota, global_usage, length
+                )));
+            }
+        }
+        for (quota, usage) in [
+            (limits.client_quota, client_usage),
+            (limits.global_quota, global_usage),
+        ] {
+            if let Some(quota) = quota {
+                room = room.min(quota.saturating_sub(usage));
+            }
+        }
+    }
+    let id = NEXT_RESERVATION_ID.fetch_add(1, Ordering::Relaxed);
+    reservations.push((id, store.dir.clone(), String::from(client_address), length));
+    return Ok(QuotaReservation { id, room });
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::http_handler::resumable::ResumableUpload;
+    use crate::http_handler::uploads::test::TempUploadStore;
+    use crate::http_handler::uploads::UploadWriter;
+
+    #[test]
+    pub fn upload_quotas() {
+        use std::io::Write;
+        let store = TempUploadStore::new("quota", false, None);
+        for client_address in ["192.0.2.1", "192.0.2.2"] {
+            let mut writer = UploadWriter::create(&store).unwrap();
+            writer.write_all(&[0; 100]).unwrap();
+            let mut metadata = UploadMetadata::new("");
+            metadata.client_address = String::from(client_address);
+            writer.finish(&mut metadata).unwrap();
+        }
+        let mut limits = StorageLimits {
+            client_quota: Some(150),
+            global_quota: Some(300),
+            min_free_space: 0,
+        };
+        assert_eq!(
+            check_upload(&limits, &store, "192.0.2.1", 50).unwrap().room,
+            50
+        );
+        let e = check_upload(&limits, &store, "192.0.2.1", 51).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::FileTooLarge);
+        assert!(check_upload(&limits, &store, "192.0.2.3", 100).is_ok());
+
+        // Uploads in progress count until they are over
+        let reservation = check_upload(&limits, &store, "192.0.2.1", 30).unwrap();
+        let e = check_upload(&limits, &store, "192.0.2.1", 30).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::FileTooLarge);
+        assert_eq!(
+            check_upload(&limits, &store, "192.0.2.1", 20).unwrap().room,
+            20
+        );
+        drop(reservation);
+        assert!(check_upload(&limits, &store, "192.0.2.1", 30).is_ok());
+        // Resumable uploads count with the length they announced
+        let mut metadata = UploadMetadata::new("");
+        metadata.client_address = String::from("192.0.2.1");
+        let upload = ResumableUpload::create(&store, 40, metadata).unwrap();
+        let e = check_upload(&limits, &store, "192.0.2.1", 11).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::FileTooLarge);
+        store.remove(&upload.id).unwrap();
+
+        limits.client_quota = None;
+        assert_eq!(
+            check_upload(&limits, &store, "192.0.2.1", 10).unwrap().room,
+            100
+        );
+        let e = check_upload(&limits, &store, "192.0.2.1", 101).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::StorageFull);
+
+        // No disk is that large
+        limits.global_quota = None;
+        let e = check_upload(&limits, &store, "192.0.2.1", u64::MAX).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::StorageFull);
+        limits.min_free_space = u64::MAX;
+        let e = check_upload(&limits, &store, "192.0.2.1", 0).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::StorageFull);
+
+        // A directory yet to be created is on its parent's file system
+        assert!(free_space(&store.root().join("a/b")).unwrap() > 0);
+    }
+}
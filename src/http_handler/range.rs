@@ -0,0 +1,157 @@
+// Requests asking for more ranges than this are served in full, to avoid spending a lot of
+// resources on pathological requests (RFC 9110, section 14.2)
+const MAX_RANGES: usize = 32;
+
+// A satisfiable byte range, i.e., `start + length` never exceeds the representation's length
+#[derive(Debug, PartialEq, Clone)]
+pub struct ByteRange {
+    pub start: u64,
+    pub length: u64,
+}
+
+impl ByteRange {
+    // The value of the 'Content-Range' header field for this range
+    pub fn to_content_range(&self, complete_length: u64) -> String {
+        return format!(
+            "bytes {}-{}/{}",
+            self.start,
+            self.start + self.length - 1,
+            complete_length
+        );
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+    // The 'Range' header is invalid or uses an unknown unit: the whole representation is sent
+    Ignored,
+    // None of the ranges overlaps the representation
+    Unsatisfiable,
+    Satisfiable(Vec<ByteRange>),
+}
+
+fn parse_position(position_str: &str) -> Option<u64> {
+    if position_str.is_empty() || !position_str.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    return position_str.parse::<u64>().ok();
+}
+
+// Parse the value of a 'Range' header field (RFC 9110, section 14.1.2) against a representation
+// of `complete_length` bytes. Unsatisfiable ranges are dropped, the others are clamped.
+pub fn parse_range(range_str: &str, complete_length: u64) -> RangeRequest {
+    let range_set = match range_str.trim().split_once('=') {
+        Some((unit, range_set)) if unit.trim().eq_ignore_ascii_case("bytes") => range_set,
+        _ => return RangeRequest::Ignored,
+    };
+    let mut ret_ranges: Vec<ByteRange> = Vec::new();
+    let mut range_count = 0;
+    for range_spec in range_set.split(',') {
+        let range_spec = range_spec.trim();
+        if range_spec.is_empty() {
+            continue;
+        }
+        range_count += 1;
+        if range_count > MAX_RANGES {
+            return RangeRequest::Ignored;
+        }
+        let (first_str, last_str) = match range_spec.split_once('-') {
+            Some(positions) => positions,
+            None => return RangeRequest::Ignored,
+        };
+        if first_str.is_empty() {
+            // Suffix range: the last N bytes
+            let suffix_length = match parse_position(last_str) {
+                Some(suffix_length) => suffix_length,
+                None => return RangeRequest::Ignored,
+            };
+            if suffix_length > 0 && complete_length > 0 {
+                let length = suffix_length.min(complete_length);
+                ret_ranges.push(ByteRange {
+                    start: complete_length - length,
+                    length,
+                });
+            }
+            continue;
+        }
+        let first = match parse_position(first_str) {
+            Some(first) => first,
+            None => return RangeRequest::Ignored,
+        };
+        let last = if last_str.is_empty() {
+            u64::MAX
+        } else {
+            match parse_position(last_str) {
+                Some(last) if last >= first => last,
+                _ => return RangeRequest::Ignored,
+            }
+        };
+        if first < complete_length {
+            ret_ranges.push(ByteRange {
+                start: first,
+                length: last.min(complete_length - 1) - first + 1,
+            });
+        }
+    }
+    if range_count == 0 {
+        return RangeRequest::Ignored;
+    }
+    if ret_ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+    return RangeRequest::Satisfiable(ret_ranges);
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn range(start: u64, length: u64) -> ByteRange {
+        return ByteRange { start, length };
+    }
+
+    #[test]
+    pub fn range_parsing() {
+        assert_eq!(
+            parse_range("bytes=0-499", 1000),
+            RangeRequest::Satisfiable(vec![range(0, 500)])
+        );
+        assert_eq!(
+            parse_range("bytes=500-", 1000),
+            RangeRequest::Satisfiable(vec![range(500, 500)])
+        );
+        assert_eq!(
+            parse_range("bytes=-200", 1000),
+            RangeRequest::Satisfiable(vec![range(800, 200)])
+        );
+        assert_eq!(
+            parse_range("bytes=-2000", 1000),
+            RangeRequest::Satisfiable(vec![range(0, 1000)])
+        );
+        assert_eq!(
+            parse_range("Bytes= 0-0 , 990-1999, 2000-3000", 1000),
+            RangeRequest::Satisfiable(vec![range(0, 1), range(990, 10)])
+        );
+        assert_eq!(range(990, 10).to_content_range(1000), "bytes 990-999/1000");
+        assert_eq!(
+            parse_range("bytes=1000-", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(parse_range("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    pub fn invalid_range() {
+        assert_eq!(parse_range("items=0-1", 1000), RangeRequest::Ignored);
+        assert_eq!(parse_range("bytes=", 1000), RangeRequest::Ignored);
+        assert_eq!(parse_range("bytes=5-1", 1000), RangeRequest::Ignored);
+        assert_eq!(parse_range("bytes=a-b", 1000), RangeRequest::Ignored);
+        assert_eq!(parse_range("bytes=+1-2", 1000), RangeRequest::Ignored);
+        assert_eq!(parse_range("bytes=1", 1000), RangeRequest::Ignored);
+        assert_eq!(
+            parse_range(&format!("bytes={}", "0-0,".repeat(MAX_RANGES + 1)), 1000),
+            RangeRequest::Ignored
+        );
+    }
+}
@@ -1,11 +1,44 @@
+use super::charset::*;
+use super::collections::*;
 use super::common::*;
+use super::conditional::*;
+use super::config::{config, parse_upload_ttl};
+use super::digest::*;
+use super::documents::*;
+use super::encoding::*;
 use super::files::*;
+use super::json::*;
+use super::multipart::FormData;
+use super::patch::*;
+use super::progress::*;
+use super::quota::*;
+use super::response::ResponseStatus;
+use super::resumable::*;
+use super::routes::*;
+use super::sniff::*;
+use super::state::*;
+use super::store::*;
+use super::uploads::*;
+use super::urlencoded::*;
 const BUFF_READ_SIZE: usize = 32768;
 
 #[derive(Debug, PartialEq)]
 pub struct RequestPayload {
     pub content_type: ContentType,
     pub content_length: ContentLength,
+    pub media_type: Option<MediaType>,
+}
+
+impl RequestPayload {
+    // The encoding of the body's text, as its 'Content-Type' says (see `Charset::of_media_type`)
+    pub fn charset(&self) -> Option<Charset> {
+        return Charset::of_media_type(self.media_type.as_ref()?);
+    }
+
+    // The 'boundary' parameter of the 'Content-Type', if any (multipart bodies)
+    pub fn boundary(&self) -> Option<&str> {
+        return self.media_type.as_ref()?.parameter("boundary");
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -29,33 +62,73 @@ impl RequestHttpVersion {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum RequestMethod {
     Get,
+    Head,
     Post,
+    Put,
+    Patch,
+    Delete,
+    Options,
     Unknown,
 }
 
+impl RequestMethod {
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            Self::Get => "GET",
+            Self::Head => "HEAD",
+            Self::Post => "POST",
+            Self::Put => "PUT",
+            Self::Patch => "PATCH",
+            Self::Delete => "DELETE",
+            Self::Options => "OPTIONS",
+            Self::Unknown => "",
+        }
+    }
+
+    // Safe methods only retrieve data (RFC 9110, section 9.2.1)
+    pub fn is_safe(&self) -> bool {
+        matches!(self, Self::Get | Self::Head | Self::Options)
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct RequestHeader {
     pub syntax: RequestSyntax,
     pub http_version: RequestHttpVersion,
     pub method: RequestMethod,
+    // The request target without the query, e.g. "/api/status" or "*"
+    pub target: String,
+    pub query: Option<String>,
+    // The decoded fields of the query, `None` if it doesn't decode to UTF-8: only the targets
+    // that read it fail then
+    pub parameters: Option<FormFields>,
     pub address: &'static str,
     pub payload: RequestPayload,
+    pub headers: Vec<(String, String)>,
+    // The IP address of the client, once the connection is known
+    pub client_address: String,
 }
 
 impl RequestHeader {
-    pub fn new(text: &String) -> Self {
+    pub fn new(text: &str) -> Self {
         let mut ret_request_header = Self {
             syntax: RequestSyntax::Unknown,
             http_version: RequestHttpVersion::Unknown,
             method: RequestMethod::Unknown,
+            target: String::new(),
+            query: None,
+            parameters: Some(Vec::new()),
             address: "",
             payload: RequestPayload {
                 content_type: ContentType::Unknown,
                 content_length: 0,
+                media_type: None,
             },
+            headers: Vec::new(),
+            client_address: String::new(),
         };
         let split_text = text.lines().collect::<Vec<&str>>();
         let first_line = split_text[0];
@@ -72,12 +145,31 @@ impl RequestHeader {
 
         ret_request_header.method = validate_method(request_method_str);
         ret_request_header.http_version = validate_version(request_version_str);
-        ret_request_header.address = from_address_to_path(request_address_str);
+        let (target, query) = match request_address_str.split_once('?') {
+            Some((target, query)) => (target, Some(String::from(query))),
+            None => (request_address_str, None),
+        };
+        ret_request_header.target = String::from(target);
+        if let Some(query) = &query {
+            ret_request_header.parameters = parse_urlencoded(query);
+        }
+        ret_request_header.query = query;
+        ret_request_header.address = from_address_to_path(target);
         // --- check for interesting parameters ---
         if split_text.len() > 1 {
             for line in split_text[1..].iter() {
+                if let Some((name, value)) = line.split_once(':') {
+                    ret_request_header
+                        .headers
+                        .push((String::from(name.trim()), String::from(value.trim())));
+                }
                 if line.to_uppercase().starts_with("CONTENT-TYPE:") {
-                    ret_request_header.payload.content_type = validate_content_type(line);
+                    ret_request_header.payload.media_type = validate_media_type(line);
+                    ret_request_header.payload.content_type =
+                        match &ret_request_header.payload.media_type {
+                            Some(media_type) => ContentType::from_media_type(media_type),
+                            None => ContentType::Unknown,
+                        };
                 }
                 if line.to_uppercase().starts_with("CONTENT-LENGTH:") {
                     ret_request_header.payload.content_length = validate_content_length(line);
@@ -86,9 +178,19 @@ impl RequestHeader {
         }
         return ret_request_header;
     }
+
+    // The value of the first header with the given (case-insensitive) name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        for (key, value) in self.headers.iter() {
+            if key.eq_ignore_ascii_case(name) {
+                return Some(value.as_str());
+            }
+        }
+        return None;
+    }
 }
 
-pub fn validate_syntax(input: &Vec<&str>) -> RequestSyntax {
+pub fn validate_syntax(input: &[&str]) -> RequestSyntax {
     if input.len() != 3 {
         return RequestSyntax::Unknown;
     }
@@ -98,7 +200,12 @@ pub fn validate_syntax(input: &Vec<&str>) -> RequestSyntax {
 pub fn validate_method(input: &str) -> RequestMethod {
     match input {
         "GET" => RequestMethod::Get,
+        "HEAD" => RequestMethod::Head,
         "POST" => RequestMethod::Post,
+        "PUT" => RequestMethod::Put,
+        "PATCH" => RequestMethod::Patch,
+        "DELETE" => RequestMethod::Delete,
+        "OPTIONS" => RequestMethod::Options,
         _ => return RequestMethod::Unknown,
     }
 }
@@ -110,73 +217,733 @@ pub fn validate_version(version: &str) -> RequestHttpVersion {
     return RequestHttpVersion::Unknown;
 }
 
-pub fn validate_content_type(content_type_str: &str) -> ContentType {
-    let split_line = content_type_str.split(":").collect::<Vec<&str>>();
-    if split_line.len() == 2 {
-        return ContentType::from_content_type_str(split_line[1].trim());
-    } else {
-        return ContentType::Unknown;
-    }
+// The value of a header line, i.e., everything after the first ':'
+pub fn header_value(header_line: &str) -> Option<&str> {
+    let (_, value) = header_line.split_once(':')?;
+    return Some(value.trim());
+}
+
+pub fn validate_media_type(content_type_str: &str) -> Option<MediaType> {
+    return MediaType::parse(header_value(content_type_str)?);
 }
 
 pub fn validate_content_length(content_length_str: &str) -> ContentLength {
-    let split_line = content_length_str.split(":").collect::<Vec<&str>>();
-    if split_line.len() == 2 {
-        match split_line[1].trim().parse::<ContentLength>() {
+    match header_value(content_length_str) {
+        Some(value) => match value.parse::<ContentLength>() {
             Ok(value) => {
                 return value;
             }
             Err(_) => {
                 return 0;
             }
+        },
+        None => {
+            return 0;
         }
     }
-    return 0;
 }
 
-pub struct RequestBody {}
+// The outcome of reading the request's body, which the response reports on
+#[derive(Debug, PartialEq)]
+pub enum RequestBody {
+    // There was no body to process
+    Empty,
+    // The body was written to `path`, which didn't exist before if `created`
+    Stored {
+        path: String,
+        created: bool,
+        digest: Sha256Digest,
+    },
+    // The body was saved as a new upload
+    Uploaded {
+        id: String,
+        digest: Sha256Digest,
+    },
+    // A resumable upload was created or received a chunk. It has `offset` of its `length` bytes,
+    // and is complete (a new upload with that digest) once they are all in.
+    Resumable {
+        id: String,
+        offset: u64,
+        length: u64,
+        digest: Option<Sha256Digest>,
+    },
+    // The body was a form, its files were saved as new uploads
+    Form {
+        form: FormData,
+        digest: Sha256Digest,
+    },
+    // The body was merged into the state of the JSON API, which is now this
+    State(JsonValue),
+    // The body was meant to be JSON and isn't
+    MalformedJson(JsonError),
+    // The body changed a JSON document of the API, which is now `value`, tagged `etag`
+    Document {
+        value: JsonValue,
+        etag: EntityTag,
+    },
+    // The body was stored as an item of a JSON collection, served at `location`
+    Item {
+        location: String,
+        item: JsonValue,
+        created: bool,
+    },
+    // The file of the target was deleted, the request had no body to read
+    Deleted,
+    // The body was not accepted, `ResponseStatus` says why
+    Rejected(ResponseStatus),
+}
+
+// The body as read from the connection: never past 'Content-Length', checked against the digests
+// announced by the client, reported on as it arrives, and decompressed if it was sent compressed
+type BodyReader<'a> =
+    DecodedBody<DigestReader<ProgressReader<&'a mut std::io::BufReader<std::net::TcpStream>>>>;
+
+impl BodyReader<'_> {
+    // The SHA-256 digest of the body as sent, once it was entirely read
+    fn digest(&self) -> Option<Sha256Digest> {
+        return self.get_ref().digest();
+    }
+
+    fn transfer(&self) -> &Transfer {
+        return self.get_ref().get_ref().transfer();
+    }
+}
+
+// Where the uploads of a request go. The client may choose how many seconds they are kept with an
+// 'Upload-TTL' header, instead of the server's default; an invalid value gives `None`.
+fn upload_store(request_header: &RequestHeader) -> Option<UploadStore> {
+    let mut ret_store = UploadStore::configured();
+    if let Some(value) = request_header.header("Upload-TTL") {
+        ret_store.ttl = Some(parse_upload_ttl(value)?);
+    }
+    return Some(ret_store);
+}
 
 impl RequestBody {
     pub fn process(
         request_header: &RequestHeader,
         reader: &mut std::io::BufReader<std::net::TcpStream>,
-    ) {
-        use std::io::prelude::Read;
+    ) -> Self {
         use std::io::Write;
-        if request_header.method == RequestMethod::Post && request_header.payload.content_length > 0
+        // Bodies of malformed requests, and those sent to targets that don't accept them, are
+        // left unread: the response is an error and the connection is closed afterwards anyway
+        if request_header.syntax == RequestSyntax::Unknown
+            || request_header.http_version == RequestHttpVersion::Unknown
+            || !allowed_methods(&request_header.target).contains(&request_header.method)
+        {
+            return Self::Empty;
+        }
+        if Self::lacks_if_match(request_header) {
+            return Self::Rejected(ResponseStatus::PreconditionRequired);
+        }
+        // Files are deleted here, as they are stored: the response only reports on it
+        if request_header.method == RequestMethod::Delete
+            && !matches!(
+                collection_from_target(&request_header.target),
+                Some((_, Some(_)))
+            )
+        {
+            return Self::delete_file(request_header);
+        }
+        // Resumable uploads are created without a body, their data comes with 'PATCH'
+        if request_header.method == RequestMethod::Post
+            && request_header.header("Upload-Length").is_some()
+        {
+            return Self::create_resumable(request_header);
+        }
+        if request_header.method != RequestMethod::Put
+            && request_header.method != RequestMethod::Patch
+            && !(request_header.method == RequestMethod::Post
+                && request_header.payload.content_length > 0)
+        {
+            return Self::Empty;
+        }
+        // A file with a name that can't be stored isn't asked for
+        if request_header.method == RequestMethod::Put
+            && collection_from_target(&request_header.target).is_none()
+            && !file_name_from_target(&request_header.target).is_some_and(is_valid_file_name)
+        {
+            return Self::Rejected(ResponseStatus::BadRequest);
+        }
+        // Bodies are decompressed as they are read, except the chunks of resumable uploads: their
+        // offsets are those of the bytes sent
+        let document = Document::from_target(&request_header.target);
+        let encoding = match request_encoding(request_header.header("Content-Encoding")) {
+            Some(ContentEncoding::Identity) => ContentEncoding::Identity,
+            Some(encoding) if request_header.method != RequestMethod::Patch => encoding,
+            Some(encoding) if document.is_some() => encoding,
+            _ => return Self::Rejected(ResponseStatus::UnsupportedMediaType),
+        };
+        let expected = match expected_digests(
+            request_header.header("Repr-Digest"),
+            request_header.header("Digest"),
+            request_header.header("Content-MD5"),
+        ) {
+            Some(expected) => expected,
+            None => return Self::Rejected(ResponseStatus::BadRequest),
+        };
+        // A body that wouldn't fit is refused before it is sent. Compressed, it may be larger
+        // once decoded than it was announced: it is stopped when it outgrows the room left.
+        let (room, _reservation) = match Self::check_storage(request_header) {
+            Ok(storage) => storage,
+            Err(e) => return Self::rejected("Refused body", e),
+        };
+        // Clients sending 'Expect: 100-continue' wait for a go-ahead before sending the body
+        if request_header
+            .header("Expect")
+            .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
         {
-            let mut capacity: usize = request_header.payload.content_length.try_into().unwrap();
+            let _ = reader.get_mut().write_all(b"HTTP/1.1 100 Continue\r\n\r\n");
+        }
+        let transfer = Transfer::start(
+            &request_header.client_address,
+            request_header.payload.content_length,
+        );
+        let body = ProgressReader::new(reader, transfer.clone());
+        let ret_body =
+            match DigestReader::new(body, request_header.payload.content_length, expected) {
+                Ok(body) => Self::receive(
+                    request_header,
+                    &mut DecodedBody::new(
+                        body,
+                        &encoding,
+                        room.min(config().max_decoded_body_size),
+                    ),
+                ),
+                Err(e) => Self::rejected("Rejected body", e),
+            };
+        transfer.finish(!matches!(ret_body, Self::Rejected(_)));
+        return ret_body;
+    }
+
+    // Read the body in the way its target and type call for
+    fn receive(request_header: &RequestHeader, body: &mut BodyReader) -> Self {
+        if let Some((name, id)) = collection_from_target(&request_header.target) {
+            return Self::receive_item(request_header, name, id.as_deref(), body);
+        }
+        if request_header.method == RequestMethod::Put {
+            return Self::store_file(request_header, body);
+        }
+        if request_header.method == RequestMethod::Patch {
+            if let Some(document) = Document::from_target(&request_header.target) {
+                return Self::patch_document(request_header, &document, body);
+            }
+            return Self::resume_upload(request_header, body);
+        }
+        if request_header.target == SET_TARGET {
+            return Self::set_state(request_header, body);
+        }
+        if request_header.payload.content_type == ContentType::FormData {
+            return Self::receive_form(request_header, body);
+        }
+        if request_header.payload.content_type == ContentType::FormUrlEncoded {
+            return Self::receive_urlencoded_form(request_header, body);
+        }
+        return Self::receive_upload(request_header, body);
+    }
+
+    // Whether there is room for the body: files need free space, uploads must also stay within
+    // the quotas. URL-encoded forms and the JSON API's updates are not written to disk. Returns
+    // how many bytes the body may take on the disk, and the room held for an upload while it is
+    // received.
+    fn check_storage(
+        request_header: &RequestHeader,
+    ) -> std::io::Result<(u64, Option<QuotaReservation>)> {
+        let limits = StorageLimits::configured();
+        let length = request_header.payload.content_length;
+        let dir = if collection_from_target(&request_header.target).is_some() {
+            std::path::PathBuf::from(DATA_DIR)
+        } else if request_header.method == RequestMethod::Put {
+            std::path::PathBuf::from(FILES_DIR)
+        } else if request_header.method == RequestMethod::Patch
+            && Document::from_target(&request_header.target).is_some()
+        {
+            std::path::PathBuf::from(DATA_DIR)
+        } else if request_header.method == RequestMethod::Patch {
+            // The chunks of resumable uploads count against the quotas when the upload is created
+            UploadStore::configured().dir
+        } else if request_header.payload.content_type == ContentType::FormUrlEncoded
+            || request_header.target == SET_TARGET
+        {
+            return Ok((u64::MAX, None));
+        } else {
+            let reservation = check_upload(
+                &limits,
+                &UploadStore::configured(),
+                &request_header.client_address,
+                length,
+            )?;
+            return Ok((reservation.room, Some(reservation)));
+        };
+        return Ok((check_free_space(&limits, &dir, length)?, None));
+    }
+
+    // A body that couldn't be received: the client's fault if it is incomplete, malformed or
+    // doesn't match its digest
+    fn rejected(context: &str, e: std::io::Error) -> Self {
+        println!("{}: {}", context, e);
+        return match e.kind() {
+            std::io::ErrorKind::InvalidData
+            | std::io::ErrorKind::InvalidInput
+            | std::io::ErrorKind::UnexpectedEof => Self::Rejected(ResponseStatus::BadRequest),
+            std::io::ErrorKind::FileTooLarge => Self::Rejected(ResponseStatus::PayloadTooLarge),
+            std::io::ErrorKind::Unsupported => Self::Rejected(ResponseStatus::UnsupportedMediaType),
+            std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded => {
+                Self::Rejected(ResponseStatus::InsufficientStorage)
+            }
+            _ => Self::Rejected(ResponseStatus::InternalServerError),
+        };
+    }
+
+    // POST /api/uploads: save the body under a new upload ID. The data is written to a temporary
+    // file which is renamed once complete, so an upload is either entirely there or not at all.
+    // Text must be valid in its charset, and is converted to UTF-8 if the server is set to.
+    fn receive_upload(request_header: &RequestHeader, reader: &mut BodyReader) -> Self {
+        use std::io::prelude::Read;
+        use std::io::Write;
+        let store = match upload_store(request_header) {
+            Some(store) => store,
+            None => return Self::Rejected(ResponseStatus::BadRequest),
+        };
+        let mut upload = match UploadWriter::create(&store) {
+            Ok(upload) => upload,
+            Err(e) => return Self::rejected("Failed to create an upload", e),
+        };
+        reader.transfer().attach(&upload.id);
+        let media_type = request_header.payload.media_type.as_ref();
+        let mut decoder = request_header
+            .payload
+            .charset()
+            .map(|charset| TextDecoder::new(charset, config().transcode_text_uploads));
+        // The reader ends with the body, and fails if the connection closes before
+        let result = (|| -> std::io::Result<()> {
             let mut bytes_read: usize;
             let mut body: [u8; BUFF_READ_SIZE] = [0; BUFF_READ_SIZE];
-            let out_file_path = format!("artifacts/{:?}-data", request_header.payload.content_type);
-            let mut out_file = std::fs::File::options()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(&out_file_path)
-                .unwrap();
+            let mut text: Vec<u8> = Vec::new();
             loop {
-                bytes_read = reader.read(&mut body).unwrap();
-                println!("read {} - remaining {}", bytes_read, capacity);
-                if request_header.payload.content_type.is_binary() {
-                    out_file.write_all(&body[0..bytes_read]).unwrap();
-                } else {
-                    write!(
-                        &mut out_file,
-                        "{}",
-                        std::str::from_utf8(&body[0..bytes_read]).unwrap()
-                    )
-                    .unwrap();
-                }
-                if capacity > bytes_read {
-                    capacity -= bytes_read;
-                } else if capacity == bytes_read {
-                    println!("Upload completed");
+                bytes_read = reader.read(&mut body)?;
+                if bytes_read == 0 {
                     break;
+                }
+                match decoder.as_mut() {
+                    Some(decoder) => {
+                        text.clear();
+                        decoder.decode(&body[0..bytes_read], &mut text)?;
+                        upload.write_all(&text)?;
+                    }
+                    None => upload.write_all(&body[0..bytes_read])?,
+                }
+            }
+            if let Some(decoder) = &decoder {
+                decoder.finish()?;
+            }
+            println!("Upload completed");
+            return Ok(());
+        })();
+        if let Err(e) = result {
+            upload.abort();
+            return Self::rejected("Failed to receive upload", e);
+        }
+        let stored_type = match (&decoder, media_type) {
+            (Some(decoder), Some(media_type)) => decoder.stored_media_type(media_type),
+            _ => None,
+        };
+        let mut metadata = UploadMetadata::new(
+            stored_type
+                .as_deref()
+                .or(request_header.header("Content-Type"))
+                .unwrap_or(""),
+        );
+        metadata.client_address = request_header.client_address.clone();
+        let policy = sniff_policy(&request_header.method, &request_header.target);
+        if let Err(e) = check_content_type(&mut metadata, upload.head(), policy) {
+            upload.abort();
+            return Self::rejected("Refused upload", e);
+        }
+        match upload.finish(&mut metadata) {
+            Ok(id) => {
+                // Decompressed, what was stored isn't what was sent
+                let digest = if reader.is_identity() {
+                    reader.digest()
                 } else {
-                    panic!("Received more data than expected! This should not happen");
+                    sha256_from_hex(&metadata.sha256)
+                };
+                return Self::Uploaded {
+                    id,
+                    digest: digest.unwrap_or_default(),
+                };
+            }
+            Err(e) => return Self::rejected("Failed to save upload", e),
+        }
+    }
+
+    // POST /api/uploads with 'Upload-Length' and no body: start a resumable upload. The client may
+    // name the file and its type in 'Upload-Metadata'. The whole length must fit in the quotas.
+    fn create_resumable(request_header: &RequestHeader) -> Self {
+        let length = request_header
+            .header("Upload-Length")
+            .and_then(|length| length.parse::<u64>().ok());
+        let upload_metadata =
+            parse_upload_metadata(request_header.header("Upload-Metadata").unwrap_or(""));
+        let (length, (filename, filetype), store) =
+            match (length, upload_metadata, upload_store(request_header)) {
+                (Some(length), Some(upload_metadata), Some(store))
+                    if request_header.payload.content_length == 0 =>
+                {
+                    (length, upload_metadata, store)
                 }
+                _ => return Self::Rejected(ResponseStatus::BadRequest),
+            };
+        let client_address = request_header.client_address.clone();
+        let limits = StorageLimits::configured();
+        // Held until the upload exists, from then on it counts with its announced length
+        let _reservation = match check_upload(&limits, &store, &client_address, length) {
+            Ok(reservation) => reservation,
+            Err(e) => return Self::rejected("Refused resumable upload", e),
+        };
+        let mut metadata = UploadMetadata::new(filetype.as_deref().unwrap_or(""));
+        metadata.filename = filename.unwrap_or_default();
+        metadata.client_address = client_address;
+        match ResumableUpload::create(&store, length, metadata) {
+            Ok(upload) => {
+                return Self::Resumable {
+                    id: upload.id,
+                    offset: 0,
+                    length,
+                    digest: None,
+                };
+            }
+            Err(e) => return Self::rejected("Failed to create a resumable upload", e),
+        }
+    }
+
+    // PATCH /api/uploads/<id>: append a chunk to a resumable upload, at the offset it reached.
+    // The last chunk completes it. A chunk announcing its digest is kept only if it is whole and
+    // matches, others keep whatever arrived before the connection broke.
+    fn resume_upload(request_header: &RequestHeader, reader: &mut BodyReader) -> Self {
+        if request_header.payload.content_type != ContentType::OffsetOctetStream {
+            return Self::Rejected(ResponseStatus::UnsupportedMediaType);
+        }
+        let id = match upload_id_from_target(&request_header.target) {
+            Some(id) if is_valid_upload_id(id) => id,
+            _ => return Self::Rejected(ResponseStatus::NotFound),
+        };
+        let offset = match request_header
+            .header("Upload-Offset")
+            .and_then(|offset| offset.parse::<u64>().ok())
+        {
+            Some(offset) => offset,
+            None => return Self::Rejected(ResponseStatus::BadRequest),
+        };
+        let store = UploadStore::configured();
+        let mut upload = match ResumableUpload::open(&store, id) {
+            Ok(upload) => upload,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Self::Rejected(ResponseStatus::NotFound);
+            }
+            Err(e) => return Self::rejected("Failed to open a resumable upload", e),
+        };
+        // Only one chunk at a time, at the right offset
+        if offset != upload.offset || !reader.transfer().resume(id, upload.offset, upload.length) {
+            return Self::Rejected(ResponseStatus::Conflict);
+        }
+        let all_or_nothing = ["Repr-Digest", "Digest", "Content-MD5"]
+            .iter()
+            .any(|name| request_header.header(name).is_some());
+        if let Err(e) = upload.append(
+            &store,
+            reader,
+            request_header.payload.content_length,
+            all_or_nothing,
+        ) {
+            return Self::rejected(&format!("Failed to resume upload {}", id), e);
+        }
+        if !upload.is_complete() {
+            return Self::Resumable {
+                id: String::from(id),
+                offset: upload.offset,
+                length: upload.length,
+                digest: None,
+            };
+        }
+        let (offset, length) = (upload.offset, upload.length);
+        match upload.finish(
+            &store,
+            sniff_policy(&request_header.method, &request_header.target),
+        ) {
+            Ok(metadata) => {
+                return Self::Resumable {
+                    id: String::from(id),
+                    offset,
+                    length,
+                    digest: sha256_from_hex(&metadata.sha256),
+                };
             }
+            Err(e) => return Self::rejected("Failed to save upload", e),
+        }
+    }
+
+    // POST /api/uploads with a 'multipart/form-data' body, as sent by HTML forms
+    fn receive_form(request_header: &RequestHeader, reader: &mut BodyReader) -> Self {
+        let boundary = match request_header.payload.boundary() {
+            Some(boundary) => boundary,
+            None => return Self::Rejected(ResponseStatus::BadRequest),
+        };
+        let store = match upload_store(request_header) {
+            Some(store) => store,
+            None => return Self::Rejected(ResponseStatus::BadRequest),
+        };
+        let transfer = reader.transfer().clone();
+        let form = match FormData::receive(
+            &mut *reader,
+            boundary,
+            &store,
+            &request_header.client_address,
+            &transfer,
+            sniff_policy(&request_header.method, &request_header.target),
+        ) {
+            Ok(form) => form,
+            Err(e) => return Self::rejected("Failed to receive form", e),
+        };
+        // The epilogue after the last part must be read too for the digest to be checked
+        let result = std::io::copy(reader, &mut std::io::sink());
+        match (result, reader.digest()) {
+            (Ok(_), Some(digest)) => return Self::Form { form, digest },
+            (result, _) => {
+                form.discard(&store);
+                let e = result.err().unwrap_or(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "body ended early",
+                ));
+                return Self::rejected("Failed to receive form", e);
+            }
+        }
+    }
+
+    // POST /api/uploads with an 'application/x-www-form-urlencoded' body: the fields are decoded,
+    // there are no files
+    fn receive_urlencoded_form(request_header: &RequestHeader, reader: &mut BodyReader) -> Self {
+        use std::io::prelude::Read;
+        if request_header.payload.content_length > MAX_URLENCODED_BODY_SIZE {
+            return Self::Rejected(ResponseStatus::PayloadTooLarge);
+        }
+        // A compressed body may still be too large once decompressed
+        let mut body: Vec<u8> = Vec::new();
+        if let Err(e) = reader
+            .take(MAX_URLENCODED_BODY_SIZE + 1)
+            .read_to_end(&mut body)
+        {
+            return Self::rejected("Failed to receive form", e);
+        }
+        if body.len() as u64 > MAX_URLENCODED_BODY_SIZE {
+            return Self::Rejected(ResponseStatus::PayloadTooLarge);
+        }
+        let digest = match reader.digest() {
+            Some(digest) => digest,
+            None => return Self::Rejected(ResponseStatus::BadRequest),
+        };
+        // Every byte of a valid body is ASCII, the non-ASCII text being percent-encoded
+        let fields = std::str::from_utf8(&body).ok().and_then(parse_urlencoded);
+        match fields {
+            Some(fields) => {
+                return Self::Form {
+                    form: FormData {
+                        fields,
+                        files: Vec::new(),
+                    },
+                    digest,
+                };
+            }
+            None => return Self::Rejected(ResponseStatus::BadRequest),
+        }
+    }
+
+    // A JSON body of the API, read whole. Fails with the response to send instead.
+    fn read_json_body(
+        request_header: &RequestHeader,
+        reader: &mut BodyReader,
+    ) -> Result<Vec<u8>, Self> {
+        use std::io::prelude::Read;
+        if request_header.payload.content_length > MAX_JSON_BODY_SIZE {
+            return Err(Self::Rejected(ResponseStatus::PayloadTooLarge));
+        }
+        let mut ret_body: Vec<u8> = Vec::new();
+        if let Err(e) = reader
+            .take(MAX_JSON_BODY_SIZE + 1)
+            .read_to_end(&mut ret_body)
+        {
+            return Err(Self::rejected("Failed to receive JSON", e));
+        }
+        if ret_body.len() as u64 > MAX_JSON_BODY_SIZE {
+            return Err(Self::Rejected(ResponseStatus::PayloadTooLarge));
+        }
+        if reader.digest().is_none() {
+            return Err(Self::Rejected(ResponseStatus::BadRequest));
+        }
+        return Ok(ret_body);
+    }
+
+    // The essence of the body's 'Content-Type', if it is JSON text in UTF-8
+    fn json_media_type(request_header: &RequestHeader) -> Option<String> {
+        let media_type = request_header.payload.media_type.as_ref()?;
+        if !media_type.is_text() || request_header.payload.charset() != Some(Charset::Utf8) {
+            return None;
+        }
+        return Some(media_type.essence());
+    }
+
+    // POST /api/set: merge the members of a JSON object into the state
+    fn set_state(request_header: &RequestHeader, reader: &mut BodyReader) -> Self {
+        if request_header.payload.content_type != ContentType::Json
+            || Self::json_media_type(request_header).is_none()
+        {
+            return Self::Rejected(ResponseStatus::UnsupportedMediaType);
+        }
+        let body = match Self::read_json_body(request_header, reader) {
+            Ok(body) => body,
+            Err(rejected) => return rejected,
+        };
+        match parse_update(&body) {
+            Ok(update) => match merge_state(update) {
+                Ok(state) => return Self::State(state),
+                Err(e) => return Self::rejected("Failed to update the state", e),
+            },
+            Err(e) => return Self::MalformedJson(e),
+        }
+    }
+
+    // POST /api/<collection>: add the JSON object of the body to the collection, with a new ID if
+    // it has none. PUT /api/<collection>/<id>: create or replace the item of that ID.
+    fn receive_item(
+        request_header: &RequestHeader,
+        name: &str,
+        id: Option<&str>,
+        reader: &mut BodyReader,
+    ) -> Self {
+        if request_header.payload.content_type != ContentType::Json
+            || Self::json_media_type(request_header).is_none()
+        {
+            return Self::Rejected(ResponseStatus::UnsupportedMediaType);
+        }
+        let body = match Self::read_json_body(request_header, reader) {
+            Ok(body) => body,
+            Err(rejected) => return rejected,
+        };
+        let item = match JsonValue::from_utf8(&body) {
+            Ok(item) => item,
+            Err(e) => return Self::MalformedJson(e),
+        };
+        let result = update_collection(name, |items| match id {
+            Some(id) => return replace_item(items, id, item),
+            None => return insert_item(items, item).map(|item| (item, true)),
+        });
+        match result {
+            Ok((item, created)) => {
+                return Self::Item {
+                    location: item_location(name, &item_id(&item).unwrap_or_default()),
+                    item,
+                    created,
+                }
+            }
+            Err(status) => return Self::Rejected(status),
+        }
+    }
+
+    // Whether the request is a PATCH on a JSON document of the API without 'If-Match': it would
+    // silently overwrite changes made since the client read the document
+    fn lacks_if_match(request_header: &RequestHeader) -> bool {
+        return request_header.method == RequestMethod::Patch
+            && Document::from_target(&request_header.target).is_some()
+            && request_header.header("If-Match").is_none();
+    }
+
+    // PATCH on a JSON document of the API: apply a JSON Patch or a JSON Merge Patch to it, all
+    // or nothing, only if it is still the version the client has seen in 'If-Match'.
+    fn patch_document(
+        request_header: &RequestHeader,
+        document: &Document,
+        reader: &mut BodyReader,
+    ) -> Self {
+        let media_type = Self::json_media_type(request_header).unwrap_or_default();
+        if media_type != JSON_PATCH_CONTENT_TYPE && media_type != MERGE_PATCH_CONTENT_TYPE {
+            return Self::Rejected(ResponseStatus::UnsupportedMediaType);
+        }
+        let body = match Self::read_json_body(request_header, reader) {
+            Ok(body) => body,
+            Err(rejected) => return rejected,
+        };
+        let patch = match JsonValue::from_utf8(&body) {
+            Ok(body) => JsonPatch::parse(&media_type, body),
+            Err(e) => return Self::MalformedJson(e),
+        };
+        let patch = match patch {
+            Some(Ok(patch)) => patch,
+            Some(Err(e)) => {
+                println!("Malformed patch: {:?}", e);
+                return Self::Rejected(ResponseStatus::BadRequest);
+            }
+            None => return Self::Rejected(ResponseStatus::UnsupportedMediaType),
+        };
+        let result = document.update(|current, etag| {
+            if let Some(status) = evaluate_preconditions(request_header, etag, None) {
+                return Err(status);
+            }
+            return patch.apply(current).map_err(|e| {
+                println!("Failed to apply patch: {:?}", e);
+                match e {
+                    PatchError::Malformed(_) => ResponseStatus::BadRequest,
+                    PatchError::Conflict(_) => ResponseStatus::Conflict,
+                    PatchError::TooLarge(_) => ResponseStatus::PayloadTooLarge,
+                }
+            });
+        });
+        match result {
+            Ok((value, etag)) => return Self::Document { value, etag },
+            Err(status) => return Self::Rejected(status),
+        }
+    }
+
+    // PUT /files/<name>: create or replace the named file with the body
+    fn store_file(request_header: &RequestHeader, reader: &mut BodyReader) -> Self {
+        let name = match file_name_from_target(&request_header.target) {
+            Some(name) if is_valid_file_name(name) => name,
+            _ => return Self::Rejected(ResponseStatus::BadRequest),
+        };
+        // Decompressed, the body is as long as it turns out to be
+        let length = reader
+            .is_identity()
+            .then_some(request_header.payload.content_length);
+        // Decompressed, the digest is that of what was stored rather than of what was sent
+        let mut decoded = Sha256Reader::new(&mut *reader);
+        let result = store_file(std::path::Path::new(FILES_DIR), name, &mut decoded, length);
+        let decoded_digest = decoded.digest();
+        match result {
+            Ok(created) => {
+                let digest = if reader.is_identity() {
+                    reader.digest().unwrap_or_default()
+                } else {
+                    decoded_digest
+                };
+                return Self::Stored {
+                    path: format!("{}/{}", FILES_DIR, name),
+                    created,
+                    digest,
+                };
+            }
+            Err(e) => return Self::rejected(&format!("Failed to store {}", name), e),
+        }
+    }
+
+    // DELETE /files/<name>: remove the stored file of that name
+    fn delete_file(request_header: &RequestHeader) -> Self {
+        let name = match file_name_from_target(&request_header.target) {
+            Some(name) if is_valid_file_name(name) => name,
+            _ => return Self::Rejected(ResponseStatus::BadRequest),
+        };
+        match delete_file(std::path::Path::new(FILES_DIR), name) {
+            Ok(true) => return Self::Deleted,
+            Ok(false) => return Self::Rejected(ResponseStatus::NotFound),
+            Err(e) => return Self::rejected(&format!("Failed to delete {}", name), e),
         }
     }
 }
@@ -186,7 +953,7 @@ pub mod test {
     #[test]
     pub fn valid_addresses() {
         let request_header = RequestHeader::new(
-            &vec![
+            &[
                 "GET / HTTP/1.1",
                 "Content-Type: application/json",
                 "Content-Length: 5",
@@ -226,6 +993,75 @@ pub mod test {
         assert_eq!(request_header.address, "");
     }
 
+    #[test]
+    pub fn content_type_parameters() {
+        let request_header = RequestHeader::new(
+            &[
+                "POST /api/set HTTP/1.1",
+                "content-type: multipart/form-data; boundary=\"----x:y\"",
+                "Content-Length: 5",
+                "\r\n",
+            ]
+            .join("\r\n"),
+        );
+        assert_eq!(request_header.payload.content_type, ContentType::FormData);
+        assert_eq!(request_header.payload.boundary(), Some("----x:y"));
+        assert_eq!(request_header.payload.charset(), None);
+        assert_eq!(request_header.payload.content_length, 5);
+
+        let request_header = RequestHeader::new(
+            &[
+                "POST /api/set HTTP/1.1",
+                "Content-Type: application/json; charset=utf-8",
+                "\r\n",
+            ]
+            .join("\r\n"),
+        );
+        assert_eq!(request_header.payload.content_type, ContentType::Json);
+        assert_eq!(request_header.payload.charset(), Some(Charset::Utf8));
+        assert_eq!(
+            request_header.header("CONTENT-TYPE"),
+            Some("application/json; charset=utf-8")
+        );
+        assert_eq!(request_header.header("Accept-Encoding"), None);
+
+        // Text is UTF-8 unless it says otherwise
+        let request_header =
+            RequestHeader::new("POST /api/uploads HTTP/1.1\r\nContent-Type: text/csv\r\n");
+        assert_eq!(request_header.payload.charset(), Some(Charset::Utf8));
+        let request_header = RequestHeader::new(
+            "POST /api/uploads HTTP/1.1\r\nContent-Type: text/csv; charset=latin1\r\n",
+        );
+        assert_eq!(request_header.payload.charset(), Some(Charset::Latin1));
+    }
+
+    #[test]
+    pub fn request_target() {
+        let request_header = RequestHeader::new("HEAD /index.html?x=1&y HTTP/1.1\r\n");
+        assert_eq!(request_header.method, RequestMethod::Head);
+        assert_eq!(request_header.target, "/index.html");
+        assert_eq!(request_header.query, Some(String::from("x=1&y")));
+        assert_eq!(
+            request_header.parameters,
+            Some(vec![
+                (String::from("x"), String::from("1")),
+                (String::from("y"), String::new())
+            ])
+        );
+        assert_eq!(request_header.address, "www/index.html");
+
+        let request_header = RequestHeader::new("OPTIONS * HTTP/1.1\r\n");
+        assert_eq!(request_header.method, RequestMethod::Options);
+        assert_eq!(request_header.target, "*");
+        assert_eq!(request_header.query, None);
+        assert_eq!(request_header.address, "");
+
+        // A query that isn't UTF-8 is only a problem for the targets reading it
+        let request_header = RequestHeader::new("GET /index.html?name=%E9 HTTP/1.1\r\n");
+        assert_eq!(request_header.syntax, RequestSyntax::Known);
+        assert_eq!(request_header.parameters, None);
+    }
+
     #[test]
     pub fn bad_request() {
         let request_header = RequestHeader::new(&String::from("GET /missing_parameter\r\n"));
@@ -251,7 +1087,7 @@ pub mod test {
 
     #[test]
     pub fn method_not_allowed() {
-        let request_header = RequestHeader::new(&String::from("PUT /index.html HTTP/1.1\r\n"));
+        let request_header = RequestHeader::new(&String::from("TRACE /index.html HTTP/1.1\r\n"));
         assert_eq!(request_header.syntax, RequestSyntax::Known);
         assert_eq!(request_header.method, RequestMethod::Unknown);
         assert_eq!(request_header.http_version, RequestHttpVersion::Http11);
@@ -271,4 +1107,60 @@ pub mod test {
         assert_eq!(request_header.http_version, RequestHttpVersion::Http11);
         assert_eq!(request_header.address, "");
     }
+
+    #[test]
+    pub fn document_patch_preconditions() {
+        let request_header = RequestHeader::new(
+            "PATCH /api/status HTTP/1.1\r\nContent-Type: application/merge-patch+json\r\n",
+        );
+        assert!(RequestBody::lacks_if_match(&request_header));
+        assert_eq!(
+            ResponseStatus::PreconditionRequired.to_str(),
+            "428 Precondition Required"
+        );
+        let request_header =
+            RequestHeader::new("PATCH /api/status HTTP/1.1\r\nIf-Match: \"abc\"\r\n\r\n");
+        assert!(!RequestBody::lacks_if_match(&request_header));
+        let request_header = RequestHeader::new("PATCH /api/status HTTP/1.1\r\nIf-Match: *\r\n");
+        assert!(!RequestBody::lacks_if_match(&request_header));
+        // The chunks of resumable uploads have 'Upload-Offset' to keep them in order
+        let request_header = RequestHeader::new("PATCH /api/uploads/0123 HTTP/1.1\r\n");
+        assert!(!RequestBody::lacks_if_match(&request_header));
+    }
+
+    #[test]
+    pub fn file_deletion() {
+        let request_header = RequestHeader::new("DELETE /files/missing.txt HTTP/1.1\r\n");
+        assert_eq!(
+            RequestBody::delete_file(&request_header),
+            RequestBody::Rejected(ResponseStatus::NotFound)
+        );
+        let request_header = RequestHeader::new("DELETE /files/.hidden HTTP/1.1\r\n");
+        assert_eq!(
+            RequestBody::delete_file(&request_header),
+            RequestBody::Rejected(ResponseStatus::BadRequest)
+        );
+    }
+
+    #[test]
+    pub fn storage_errors() {
+        let e = std::io::Error::new(std::io::ErrorKind::FileTooLarge, "over quota");
+        assert_eq!(
+            RequestBody::rejected("Refused body", e),
+            RequestBody::Rejected(ResponseStatus::PayloadTooLarge)
+        );
+        // A disk filling up mid-write is an error like any other, not a panic
+        if std::path::Path::new("/dev/full").exists() {
+            let mut full = std::fs::File::options()
+                .write(true)
+                .open("/dev/full")
+                .unwrap();
+            let e = copy_body(&mut "hello".as_bytes(), &mut full, 5).unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::StorageFull);
+            assert_eq!(
+                RequestBody::rejected("Failed to receive upload", e),
+                RequestBody::Rejected(ResponseStatus::InsufficientStorage)
+            );
+        }
+    }
 }
@@ -1,16 +1,46 @@
+use super::blobs::*;
+use super::collections::*;
 use super::common::*;
+use super::conditional::*;
+use super::date::{format_http_date, format_unix_rfc3339, parse_http_date, unix_seconds};
+use super::digest::*;
+use super::documents::*;
+use super::encoding::*;
+use super::json::*;
+use super::multipart::FormData;
+use super::patch::*;
+use super::progress::*;
+use super::range::*;
+use super::resumable::ResumableUpload;
+use super::routes::*;
+use super::state::*;
+use super::uploads::*;
+use super::urlencoded::field_value;
 use crate::http_handler::request::{
-    RequestHeader, RequestHttpVersion, RequestMethod, RequestSyntax,
+    RequestBody, RequestHeader, RequestHttpVersion, RequestMethod, RequestSyntax,
 };
 
-#[derive(Debug, PartialEq)]
+const DEFAULT_UPLOAD_LIST_LIMIT: usize = 100;
+const MAX_UPLOAD_LIST_LIMIT: usize = 1000;
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum ResponseStatus {
     Ok,
+    Created,
     NoContent,
+    PartialContent,
+    NotModified,
     BadRequest,
     NotFound,
     MethodNotAllowed,
+    Conflict,
+    PreconditionFailed,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    PreconditionRequired,
     HttpVersionNotSupported,
+    InsufficientStorage,
     InternalServerError,
 }
 
@@ -20,9 +50,18 @@ impl ResponseStatus {
             ResponseStatus::Ok => {
                 return "200 OK";
             }
+            ResponseStatus::Created => {
+                return "201 Created";
+            }
             ResponseStatus::NoContent => {
                 return "204 No Content";
             }
+            ResponseStatus::PartialContent => {
+                return "206 Partial Content";
+            }
+            ResponseStatus::NotModified => {
+                return "304 Not Modified";
+            }
             ResponseStatus::BadRequest => {
                 return "400 Bad Request";
             }
@@ -32,72 +71,1673 @@ impl ResponseStatus {
             ResponseStatus::MethodNotAllowed => {
                 return "405 Method Not Allowed";
             }
+            ResponseStatus::Conflict => {
+                return "409 Conflict";
+            }
+            ResponseStatus::PreconditionFailed => {
+                return "412 Precondition Failed";
+            }
+            ResponseStatus::PayloadTooLarge => {
+                return "413 Payload Too Large";
+            }
+            ResponseStatus::UnsupportedMediaType => {
+                return "415 Unsupported Media Type";
+            }
+            ResponseStatus::RangeNotSatisfiable => {
+                return "416 Range Not Satisfiable";
+            }
+            ResponseStatus::PreconditionRequired => {
+                return "428 Precondition Required";
+            }
             ResponseStatus::InternalServerError => {
                 return "500 Internal Server Error";
             }
             ResponseStatus::HttpVersionNotSupported => {
                 return "505 HTTP Version Not Supported";
             }
+            ResponseStatus::InsufficientStorage => {
+                return "507 Insufficient Storage";
+            }
+        }
+    }
+}
+
+impl ResponseStatus {
+    // The page sent along with an error status
+    pub fn error_page(&self) -> &'static str {
+        match self {
+            ResponseStatus::BadRequest => "www/bad_request.html",
+            ResponseStatus::NotFound => "www/not_found.html",
+            ResponseStatus::MethodNotAllowed => "www/method_not_allowed.html",
+            ResponseStatus::Conflict => "www/conflict.html",
+            ResponseStatus::PreconditionFailed => "www/precondition_failed.html",
+            ResponseStatus::PayloadTooLarge => "www/payload_too_large.html",
+            ResponseStatus::UnsupportedMediaType => "www/unsupported_media_type.html",
+            ResponseStatus::RangeNotSatisfiable => "www/range_not_satisfiable.html",
+            ResponseStatus::PreconditionRequired => "www/precondition_required.html",
+            ResponseStatus::HttpVersionNotSupported => "www/http_version_not_supported.html",
+            ResponseStatus::InsufficientStorage => "www/insufficient_storage.html",
+            _ => "www/internal_server_error.html",
         }
     }
 }
 
 #[derive(Debug, PartialEq)]
 pub struct ResponsePayload {
-    pub path: &'static str,
+    pub path: String,
     pub content_type: ContentType,
+    // The 'Content-Type' value to send verbatim instead of `content_type`'s, e.g. an upload's as
+    // declared by its sender
+    pub media_type: Option<String>,
     pub content_length: u64,
+    // When set, the body is sent from memory (e.g., compressed content) instead of the file
+    pub data: Option<Vec<u8>>,
+    // When not empty, the body is made of these segments instead of the whole file
+    pub segments: Vec<PayloadSegment>,
+    // Set when the body is a 'multipart/byteranges' with this boundary
+    pub multipart_boundary: Option<String>,
+    // Set when the body is a stream of events on these transfers, of unknown length
+    pub event_stream: Option<ProgressFeed>,
+}
+
+impl ResponsePayload {
+    pub fn content_type_value(&self) -> &str {
+        return match &self.media_type {
+            Some(media_type) => media_type.as_str(),
+            None => self.content_type.to_str(),
+        };
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PayloadSegment {
+    Data(Vec<u8>),
+    // A slice of the payload's file, sent through `sendfile`
+    File { offset: u64, length: u64 },
 }
 
 #[derive(Debug, PartialEq)]
 pub struct Response {
     pub status: ResponseStatus,
     pub payload: ResponsePayload,
+    // Additional header fields, besides 'Content-Length' and 'Content-Type'
+    pub headers: Vec<(String, String)>,
 }
 
 impl Response {
-    pub fn new(request_data: &RequestHeader) -> Self {
-        let mut content_length = 0;
-        let (status, path) = if request_data.syntax == RequestSyntax::Unknown {
-            (ResponseStatus::BadRequest, "www/bad_request.html")
-        } else if request_data.method == RequestMethod::Unknown {
-            (
-                ResponseStatus::MethodNotAllowed,
-                "www/method_not_allowed.html",
-            )
+    pub fn new(request_data: &RequestHeader, request_body: &RequestBody) -> Self {
+        let allowed = allowed_methods(&request_data.target);
+        let status = if request_data.syntax == RequestSyntax::Unknown {
+            ResponseStatus::BadRequest
+        } else if request_data.method == RequestMethod::Unknown && !allowed.is_empty() {
+            ResponseStatus::MethodNotAllowed
         } else if request_data.http_version == RequestHttpVersion::Unknown {
-            (
-                ResponseStatus::HttpVersionNotSupported,
-                "www/http_version_not_supported.html",
-            )
+            ResponseStatus::HttpVersionNotSupported
+        } else if allowed.is_empty() {
+            ResponseStatus::NotFound
+        } else if !allowed.contains(&request_data.method) {
+            ResponseStatus::MethodNotAllowed
         } else {
-            // The syntax is ok. Post method don't have content -> successful request
-            if request_data.method == RequestMethod::Post {
-                (ResponseStatus::NoContent, "")
-            } else if request_data.address == "" {
-                (ResponseStatus::NotFound, "www/not_found.html")
-            } else {
-                // Get requests want a file -> check that the file exists
-                if std::path::Path::new(request_data.address).exists() {
-                    (ResponseStatus::Ok, request_data.address)
-                } else {
-                    (
-                        ResponseStatus::InternalServerError,
-                        "www/internal_server_error.html",
-                    )
+            match request_data.method {
+                RequestMethod::Get | RequestMethod::Head => {
+                    if request_data.target == UPLOADS_TARGET {
+                        return Self::upload_list(request_data);
+                    }
+                    if request_data.target == UPLOADS_PROGRESS_TARGET
+                        || progress_id_from_target(&request_data.target).is_some()
+                    {
+                        return Self::progress(request_data);
+                    }
+                    if request_data.target == STATUS_TARGET {
+                        match current_state() {
+                            Ok(state) => return Self::json_query(request_data, &state),
+                            Err(e) => {
+                                println!("Failed to load the state: {}", e);
+                                ResponseStatus::InternalServerError
+                            }
+                        }
+                    } else if let Some((name, id)) = collection_from_target(&request_data.target) {
+                        return Self::collection_query(request_data, name, id.as_deref());
+                    } else {
+                        return Self::file(request_data);
+                    }
                 }
+                RequestMethod::Put => match request_body {
+                    RequestBody::Stored { created: true, .. } => ResponseStatus::Created,
+                    RequestBody::Item {
+                        location,
+                        item,
+                        created,
+                    } => return Self::item_stored(location, item, *created),
+                    RequestBody::MalformedJson(e) => {
+                        return Self::json(ResponseStatus::BadRequest, &e.to_json());
+                    }
+                    RequestBody::Rejected(status) => status.clone(),
+                    _ => ResponseStatus::NoContent,
+                },
+                RequestMethod::Post => match request_body {
+                    RequestBody::Uploaded { id, digest } => {
+                        return Self::uploaded(id, digest, ResponseStatus::Created);
+                    }
+                    RequestBody::Resumable {
+                        id,
+                        offset,
+                        length,
+                        digest,
+                    } => return Self::resumable(id, *offset, *length, digest, true),
+                    RequestBody::Form { form, digest } => {
+                        return Self::form_received(form, digest);
+                    }
+                    RequestBody::Item {
+                        location,
+                        item,
+                        created,
+                    } => return Self::item_stored(location, item, *created),
+                    // A collection's new item is the body
+                    RequestBody::Empty
+                        if collection_from_target(&request_data.target).is_some() =>
+                    {
+                        ResponseStatus::BadRequest
+                    }
+                    RequestBody::State(state) => {
+                        let mut ret_response = Self::json(ResponseStatus::Ok, state);
+                        ret_response.add_header("ETag", &document_etag(state).to_header_value());
+                        return ret_response;
+                    }
+                    RequestBody::MalformedJson(e) => {
+                        return Self::json(ResponseStatus::BadRequest, &e.to_json());
+                    }
+                    RequestBody::Rejected(status) => status.clone(),
+                    _ => ResponseStatus::NoContent,
+                },
+                RequestMethod::Patch => match request_body {
+                    RequestBody::Document { value, etag } => {
+                        let mut ret_response = Self::json(ResponseStatus::Ok, value);
+                        ret_response.add_header("ETag", &etag.to_header_value());
+                        return ret_response;
+                    }
+                    RequestBody::MalformedJson(e) => {
+                        return Self::json(ResponseStatus::BadRequest, &e.to_json());
+                    }
+                    RequestBody::Resumable {
+                        id,
+                        offset,
+                        length,
+                        digest,
+                    } => return Self::resumable(id, *offset, *length, digest, false),
+                    RequestBody::Rejected(status) => status.clone(),
+                    _ => ResponseStatus::NoContent,
+                },
+                RequestMethod::Delete => match collection_from_target(&request_data.target) {
+                    Some((name, Some(id))) => Self::delete_item(name, &id),
+                    _ => match request_body {
+                        RequestBody::Rejected(status) => status.clone(),
+                        _ => ResponseStatus::NoContent,
+                    },
+                },
+                // The syntax is ok. Options method doesn't have content -> successful request
+                _ => ResponseStatus::NoContent,
             }
         };
-        if status != ResponseStatus::NoContent {
-            content_length = std::fs::metadata(path).unwrap().len();
+        let mut ret_response = Self::empty(ResponseStatus::NoContent);
+        match status {
+            ResponseStatus::Created | ResponseStatus::NoContent => ret_response.status = status,
+            _ => ret_response.set_error(status, ""),
+        }
+        if ret_response.status == ResponseStatus::Created {
+            ret_response.add_header("Location", &request_data.target);
+        }
+        if let RequestBody::Stored { digest, .. } = request_body {
+            ret_response.add_header("Repr-Digest", &repr_digest_value(digest));
         }
+        if ret_response.status == ResponseStatus::MethodNotAllowed
+            || request_data.method == RequestMethod::Options
+        {
+            ret_response.add_header("Allow", &allow_header(&allowed));
+        }
+        // Tell the patch formats of the JSON documents (RFC 5789, section 3.1)
+        if Document::from_target(&request_data.target).is_some()
+            && (request_data.method == RequestMethod::Options
+                || ret_response.status == ResponseStatus::UnsupportedMediaType)
+        {
+            ret_response.add_header(
+                "Accept-Patch",
+                &format!("{}, {}", JSON_PATCH_CONTENT_TYPE, MERGE_PATCH_CONTENT_TYPE),
+            );
+        }
+        ret_response.encode(request_data.header("Accept-Encoding"));
+        return ret_response;
+    }
+
+    // A response without content
+    fn empty(status: ResponseStatus) -> Self {
         return Self {
             status,
             payload: ResponsePayload {
-                path,
-                content_type: ContentType::from_file_name(path),
-                content_length,
+                path: String::new(),
+                content_type: ContentType::Unknown,
+                media_type: None,
+                content_length: 0,
+                data: None,
+                segments: Vec::new(),
+                multipart_boundary: None,
+                event_stream: None,
+            },
+            headers: Vec::new(),
+        };
+    }
+
+    // A new upload was stored: tell the client its ID, where to fetch it, the digest of what was
+    // received and the type it is served with, which may not be the one it was sent with
+    fn uploaded(id: &str, digest: &Sha256Digest, status: ResponseStatus) -> Self {
+        let mut ret_response = Self::empty(status);
+        let mut fields = vec![
+            (String::from("id"), JsonValue::from_str_value(id)),
+            (String::from("sha256"), JsonValue::String(to_hex(digest))),
+        ];
+        if let Ok(metadata) = UploadMetadata::read(&UploadStore::configured().dir.join(id)) {
+            fields.push((
+                String::from("content_type"),
+                JsonValue::from_str_value(&metadata.content_type),
+            ));
+            if let Some(detected_type) = &metadata.detected_type {
+                fields.push((
+                    String::from("detected_type"),
+                    JsonValue::from_str_value(detected_type),
+                ));
+            }
+            if let Some(expires_at) = metadata.expires_at {
+                fields.push((
+                    String::from("expires_at"),
+                    JsonValue::String(format_unix_rfc3339(expires_at)),
+                ));
+            }
+        }
+        let content = JsonValue::Object(fields).to_json_string();
+        if ret_response.status == ResponseStatus::Created {
+            ret_response.add_header("Location", &format!("{}{}", UPLOADS_PREFIX, id));
+        }
+        ret_response.add_header("Repr-Digest", &repr_digest_value(digest));
+        ret_response.payload.content_type = ContentType::Json;
+        ret_response.payload.content_length = content.len() as u64;
+        ret_response.payload.data = Some(content.into_bytes());
+        return ret_response;
+    }
+
+    // Where a resumable upload stands: just created, waiting for more data, or complete, in which
+    // case it is described like any other upload. Its state must not be cached.
+    fn resumable(
+        id: &str,
+        offset: u64,
+        length: u64,
+        digest: &Option<Sha256Digest>,
+        created: bool,
+    ) -> Self {
+        let status = if created {
+            ResponseStatus::Created
+        } else if digest.is_some() {
+            ResponseStatus::Ok
+        } else {
+            ResponseStatus::NoContent
+        };
+        let mut ret_response = match digest {
+            Some(digest) => Self::uploaded(id, digest, status),
+            None => Self::empty(status),
+        };
+        if created && digest.is_none() {
+            ret_response.add_header("Location", &format!("{}{}", UPLOADS_PREFIX, id));
+        }
+        ret_response.add_header("Upload-Offset", &offset.to_string());
+        ret_response.add_header("Upload-Length", &length.to_string());
+        ret_response.add_header("Cache-Control", "no-store");
+        return ret_response;
+    }
+
+    // A form was received: its fields are echoed and its files listed with their location. A
+    // single file is "the" created resource.
+    fn form_received(form: &FormData, digest: &Sha256Digest) -> Self {
+        let mut ret_response = Self::empty(if form.files.is_empty() {
+            ResponseStatus::Ok
+        } else {
+            ResponseStatus::Created
+        });
+        if form.files.len() == 1 {
+            ret_response.add_header(
+                "Location",
+                &format!("{}{}", UPLOADS_PREFIX, form.files[0].id),
+            );
+        }
+        let fields = form
+            .fields
+            .iter()
+            .map(|(name, value)| {
+                JsonValue::Object(vec![
+                    (String::from("name"), JsonValue::from_str_value(name)),
+                    (String::from("value"), JsonValue::from_str_value(value)),
+                ])
+            })
+            .collect::<Vec<JsonValue>>();
+        let files = form
+            .files
+            .iter()
+            .map(|file| {
+                JsonValue::Object(vec![
+                    (
+                        String::from("field"),
+                        JsonValue::from_str_value(&file.field),
+                    ),
+                    (String::from("id"), JsonValue::from_str_value(&file.id)),
+                    (
+                        String::from("filename"),
+                        JsonValue::from_str_value(&file.filename),
+                    ),
+                    (String::from("size"), JsonValue::Number(file.size as f64)),
+                    (
+                        String::from("sha256"),
+                        JsonValue::from_str_value(&file.sha256),
+                    ),
+                    (
+                        String::from("location"),
+                        JsonValue::String(format!("{}{}", UPLOADS_PREFIX, file.id)),
+                    ),
+                ])
+            })
+            .collect::<Vec<JsonValue>>();
+        let content = JsonValue::Object(vec![
+            (String::from("fields"), JsonValue::Array(fields)),
+            (String::from("files"), JsonValue::Array(files)),
+        ])
+        .to_json_string();
+        ret_response.add_header("Repr-Digest", &repr_digest_value(digest));
+        ret_response.payload.content_type = ContentType::Json;
+        ret_response.payload.content_length = content.len() as u64;
+        ret_response.payload.data = Some(content.into_bytes());
+        return ret_response;
+    }
+
+    // A document of the JSON API, or why it couldn't be updated. It changes at any time, so it
+    // must not be cached.
+    fn json(status: ResponseStatus, value: &JsonValue) -> Self {
+        let mut ret_response = Self::empty(status);
+        let content = value.to_json_string();
+        ret_response.add_header("Cache-Control", "no-store");
+        ret_response.payload.content_type = ContentType::Json;
+        ret_response.payload.content_length = content.len() as u64;
+        ret_response.payload.data = Some(content.into_bytes());
+        return ret_response;
+    }
+
+    // The response to a query that doesn't decode, for the targets reading it
+    fn bad_query() -> Self {
+        let mut ret_response = Self::empty(ResponseStatus::BadRequest);
+        ret_response.set_error(ResponseStatus::BadRequest, "");
+        return ret_response;
+    }
+
+    // A document of the JSON API, or only the value its query's 'pointer' selects: 400 if that
+    // isn't a JSON Pointer, 404 if it doesn't lead anywhere
+    fn json_query(request_data: &RequestHeader, document: &JsonValue) -> Self {
+        let parameters = match &request_data.parameters {
+            Some(parameters) => parameters,
+            None => return Self::bad_query(),
+        };
+        let pointer = match field_value(parameters, "pointer") {
+            Some(pointer) => pointer,
+            // The whole document can be patched with 'If-Match'
+            None => {
+                let mut ret_response = Self::json(ResponseStatus::Ok, document);
+                ret_response.add_header("ETag", &document_etag(document).to_header_value());
+                return ret_response;
+            }
+        };
+        let status = match parse_pointer(pointer).map(|tokens| document.pointer(&tokens)) {
+            Some(Some(value)) => return Self::json(ResponseStatus::Ok, value),
+            Some(None) => ResponseStatus::NotFound,
+            None => ResponseStatus::BadRequest,
+        };
+        let mut ret_response = Self::empty(status.clone());
+        ret_response.set_error(status, "");
+        return ret_response;
+    }
+
+    // GET /api/<collection>: the items the query selects, see `select_items`.
+    // GET /api/<collection>/<id>: the item of that ID.
+    fn collection_query(request_data: &RequestHeader, name: &str, id: Option<&str>) -> Self {
+        let status = match read_collection(name) {
+            Ok(items) => match id {
+                Some(id) => match items
+                    .into_iter()
+                    .find(|item| item_id(item).as_deref() == Some(id))
+                {
+                    Some(item) => return Self::json(ResponseStatus::Ok, &item),
+                    None => ResponseStatus::NotFound,
+                },
+                None => match request_data
+                    .parameters
+                    .as_ref()
+                    .and_then(|parameters| select_items(items, parameters))
+                {
+                    Some(items) => return Self::json(ResponseStatus::Ok, &JsonValue::Array(items)),
+                    None => ResponseStatus::BadRequest,
+                },
             },
+            Err(status) => status,
+        };
+        let mut ret_response = Self::empty(status.clone());
+        ret_response.set_error(status, "");
+        return ret_response;
+    }
+
+    // An item of a JSON collection was stored: send it back as it was, with its ID
+    fn item_stored(location: &str, item: &JsonValue, created: bool) -> Self {
+        if !created {
+            return Self::json(ResponseStatus::Ok, item);
+        }
+        let mut ret_response = Self::json(ResponseStatus::Created, item);
+        ret_response.add_header("Location", location);
+        return ret_response;
+    }
+
+    // GET /api/uploads: the complete uploads, oldest first. The query selects a page with
+    // 'offset' and 'limit', and may keep only a 'content_type', e.g. "image/png" or "image/*".
+    fn upload_list(request_data: &RequestHeader) -> Self {
+        let mut ret_response = Self::empty(ResponseStatus::Ok);
+        let parameters = match &request_data.parameters {
+            Some(parameters) => parameters,
+            None => return Self::bad_query(),
+        };
+        let offset = field_value(parameters, "offset").map_or(Some(0), |v| v.parse::<usize>().ok());
+        let limit = field_value(parameters, "limit")
+            .map_or(Some(DEFAULT_UPLOAD_LIST_LIMIT), |v| v.parse::<usize>().ok())
+            .filter(|limit| (1..=MAX_UPLOAD_LIST_LIMIT).contains(limit));
+        let filter = match field_value(parameters, "content_type") {
+            Some(filter) => MediaType::parse(filter).map(Some),
+            None => Some(None),
+        };
+        let (offset, limit, filter) = match (offset, limit, filter) {
+            (Some(offset), Some(limit), Some(filter)) => (offset, limit, filter),
+            _ => {
+                ret_response.set_error(ResponseStatus::BadRequest, "");
+                return ret_response;
+            }
+        };
+        let uploads = match UploadStore::configured().list() {
+            Ok(uploads) => uploads,
+            Err(e) => {
+                println!("Failed to list uploads: {}", e);
+                ret_response.set_error(ResponseStatus::InternalServerError, "");
+                return ret_response;
+            }
+        };
+        let now = unix_seconds(std::time::SystemTime::now());
+        let uploads = uploads
+            .into_iter()
+            .filter(|(_, metadata)| !metadata.is_expired(now))
+            .filter(|(_, metadata)| match &filter {
+                Some(filter) => {
+                    MediaType::parse(&metadata.content_type).is_some_and(|media_type| {
+                        media_type.main_type == filter.main_type
+                            && (filter.sub_type == "*" || media_type.sub_type == filter.sub_type)
+                    })
+                }
+                None => true,
+            })
+            .collect::<Vec<(String, UploadMetadata)>>();
+        let page = uploads
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .map(|(id, metadata)| {
+                let mut fields = vec![
+                    (String::from("id"), JsonValue::from_str_value(id)),
+                    (
+                        String::from("filename"),
+                        JsonValue::from_str_value(&metadata.filename),
+                    ),
+                    (
+                        String::from("content_type"),
+                        JsonValue::from_str_value(&metadata.content_type),
+                    ),
+                    (
+                        String::from("size"),
+                        JsonValue::Number(metadata.size as f64),
+                    ),
+                    (
+                        String::from("sha256"),
+                        JsonValue::from_str_value(&metadata.sha256),
+                    ),
+                    (
+                        String::from("uploaded_at"),
+                        JsonValue::String(format_unix_rfc3339(metadata.uploaded_at)),
+                    ),
+                    (
+                        String::from("client_address"),
+                        JsonValue::from_str_value(&metadata.client_address),
+                    ),
+                    (
+                        String::from("location"),
+                        JsonValue::String(format!("{}{}", UPLOADS_PREFIX, id)),
+                    ),
+                ];
+                if let Some(detected_type) = &metadata.detected_type {
+                    fields.push((
+                        String::from("detected_type"),
+                        JsonValue::from_str_value(detected_type),
+                    ));
+                }
+                if let Some(expires_at) = metadata.expires_at {
+                    fields.push((
+                        String::from("expires_at"),
+                        JsonValue::String(format_unix_rfc3339(expires_at)),
+                    ));
+                }
+                JsonValue::Object(fields)
+            })
+            .collect::<Vec<JsonValue>>();
+        let mut fields = vec![
+            (
+                String::from("total"),
+                JsonValue::Number(uploads.len() as f64),
+            ),
+            (String::from("offset"), JsonValue::Number(offset as f64)),
+            (String::from("limit"), JsonValue::Number(limit as f64)),
+            (String::from("uploads"), JsonValue::Array(page)),
+        ];
+        // The target of the following page, if any
+        if offset.saturating_add(limit) < uploads.len() {
+            let mut next = format!(
+                "{}?offset={}&limit={}",
+                UPLOADS_TARGET,
+                offset + limit,
+                limit
+            );
+            if let Some(filter) = field_value(parameters, "content_type") {
+                next.push_str(&format!("&content_type={}", filter.replace('+', "%2B")));
+            }
+            fields.push((String::from("next"), JsonValue::String(next)));
+        }
+        let content = JsonValue::Object(fields).to_json_string();
+        ret_response.payload.content_type = ContentType::Json;
+        ret_response.payload.content_length = content.len() as u64;
+        ret_response.payload.data = Some(content.into_bytes());
+        return ret_response;
+    }
+
+    // GET /api/uploads/<id>/progress: how far an upload got, as JSON or, for clients accepting
+    // 'text/event-stream', as events until it is over. GET /api/uploads/progress: events on every
+    // transfer of the client, e.g. for a page which can't know the ID of what it is uploading.
+    fn progress(request_data: &RequestHeader) -> Self {
+        let mut ret_response = Self::empty(ResponseStatus::Ok);
+        let accepts_events = request_data
+            .header("Accept")
+            .is_some_and(|accept| accept.contains("text/event-stream"));
+        let feed = match progress_id_from_target(&request_data.target) {
+            Some(id) => {
+                let now = unix_seconds(std::time::SystemTime::now());
+                let store = UploadStore::configured();
+                let snapshot = upload_progress(id).or_else(|| {
+                    UploadMetadata::read(&store.dir.join(id))
+                        .ok()
+                        .filter(|metadata| !metadata.is_expired(now))
+                        .map(|metadata| ProgressSnapshot::complete(id, metadata.size))
+                });
+                let snapshot = match snapshot {
+                    Some(snapshot) if is_valid_upload_id(id) => snapshot,
+                    _ => {
+                        ret_response.set_error(ResponseStatus::NotFound, "");
+                        return ret_response;
+                    }
+                };
+                let content = if !accepts_events {
+                    ret_response.payload.content_type = ContentType::Json;
+                    snapshot.to_json().to_json_string()
+                } else if snapshot.is_finished() {
+                    ret_response.payload.content_type = ContentType::EventStream;
+                    progress_event(&snapshot)
+                } else {
+                    String::new()
+                };
+                if !content.is_empty() {
+                    ret_response.add_header("Cache-Control", "no-store");
+                    ret_response.payload.content_length = content.len() as u64;
+                    ret_response.payload.data = Some(content.into_bytes());
+                    return ret_response;
+                }
+                ProgressFeed::Upload(String::from(id))
+            }
+            None => ProgressFeed::Client(request_data.client_address.clone()),
         };
+        ret_response.payload.content_type = ContentType::EventStream;
+        ret_response.payload.event_stream = Some(feed);
+        ret_response.add_header("Cache-Control", "no-store");
+        return ret_response;
+    }
+
+    // GET and HEAD: send the file the target refers to
+    fn file(request_data: &RequestHeader) -> Self {
+        let mut ret_response = Self::empty(ResponseStatus::Ok);
+        let path = match resolve_file(&request_data.target) {
+            Some(path) => path,
+            None => {
+                ret_response.set_error(ResponseStatus::BadRequest, "");
+                return ret_response;
+            }
+        };
+        // Expired uploads are gone for clients, even before they are swept. Resumable uploads
+        // in progress tell how far they got.
+        if let Some(id) = upload_id_from_target(&request_data.target) {
+            let store = UploadStore::configured();
+            if request_data.method == RequestMethod::Head {
+                if let Ok(upload) = ResumableUpload::open(&store, id) {
+                    return Self::resumable(id, upload.offset, upload.length, &None, false);
+                }
+            }
+            let now = unix_seconds(std::time::SystemTime::now());
+            if !store.is_available(id, now) {
+                ret_response.set_error(ResponseStatus::NotFound, "");
+                return ret_response;
+            }
+        }
+        // The JSON documents of the data directory can be queried for a single value
+        if request_data
+            .parameters
+            .as_ref()
+            .is_none_or(|parameters| field_value(parameters, "pointer").is_some())
+            && std::path::Path::new(&path).starts_with(DATA_DIR)
+            && ContentType::from_file_name(&path) == ContentType::Json
+        {
+            let document = std::fs::read_to_string(&path)
+                .ok()
+                .and_then(|text| JsonValue::parse(&text).ok());
+            match document {
+                Some(document) => return Self::json_query(request_data, &document),
+                None => {
+                    ret_response.set_error(ResponseStatus::InternalServerError, "");
+                    return ret_response;
+                }
+            }
+        }
+        match std::fs::metadata(&path) {
+            Ok(metadata) => {
+                ret_response.payload.content_type = ContentType::from_file_name(&path);
+                ret_response.payload.content_length = metadata.len();
+                ret_response.payload.path = path.clone();
+            }
+            // Files in 'tuples.rs' are supposed to be there, the others come and go
+            Err(_) if !request_data.address.is_empty() => {
+                ret_response.set_error(ResponseStatus::InternalServerError, "");
+            }
+            Err(_) => ret_response.set_error(ResponseStatus::NotFound, ""),
+        }
+        // Uploads keep the type declared by the client who sent them, parameters included, and
+        // are downloaded under their original name, whole or in parts
+        let mut download_name = None;
+        if let Some(id) = upload_id_from_target(&request_data.target) {
+            if let Ok(metadata) = UploadMetadata::read(&UploadStore::configured().dir.join(id)) {
+                if let Some(media_type) = MediaType::parse(&metadata.content_type) {
+                    ret_response.payload.content_type = ContentType::from_media_type(&media_type);
+                    ret_response.payload.media_type = Some(metadata.content_type.clone());
+                }
+                if !metadata.filename.is_empty() {
+                    download_name = Some(metadata.filename);
+                }
+            }
+        }
+        // A blob's content is its name: it can be cached forever
+        if blob_hash_from_target(&request_data.target).is_some()
+            && ret_response.status == ResponseStatus::Ok
+        {
+            ret_response.add_header("Cache-Control", "public, max-age=31536000, immutable");
+        }
+        ret_response.encode(request_data.header("Accept-Encoding"));
+        if ret_response.status == ResponseStatus::Ok {
+            ret_response.evaluate_preconditions(request_data, &path);
+        }
+        if ret_response.status == ResponseStatus::Ok {
+            ret_response.select_ranges(request_data);
+        }
+        if let Some(name) = download_name {
+            if ret_response.status == ResponseStatus::Ok
+                || ret_response.status == ResponseStatus::PartialContent
+            {
+                ret_response.add_header("Content-Disposition", &content_disposition(&name));
+            }
+        }
+        return ret_response;
+    }
+
+    // DELETE /api/<collection>/<id>: remove the item of that ID from its collection
+    fn delete_item(name: &str, id: &str) -> ResponseStatus {
+        let result = update_collection(name, |items| match remove_item(items, id) {
+            true => return Ok(()),
+            false => return Err(ResponseStatus::NotFound),
+        });
+        match result {
+            Ok(()) => ResponseStatus::NoContent,
+            Err(status) => status,
+        }
+    }
+
+    // Whether the payload goes on the wire: a response to HEAD carries the same header fields as
+    // the response to the corresponding GET, but no content
+    pub fn has_body(&self, request_data: &RequestHeader) -> bool {
+        return request_data.method != RequestMethod::Head
+            && (self.payload.content_length > 0 || self.payload.event_stream.is_some());
+    }
+
+    // Status line and header fields, terminated by the empty line that precedes the body
+    pub fn header_to_string(&self, http_version: &RequestHttpVersion) -> String {
+        let mut ret_header = format!("{} {}\r\n", http_version.to_str(), self.status.to_str());
+        // A 304 response describes the representation it would have sent: a length of 0 would
+        // be a lie. An event stream lasts until the connection is closed.
+        if self.status != ResponseStatus::NotModified && self.payload.event_stream.is_none() {
+            ret_header.push_str(&format!(
+                "Content-Length: {}\r\n",
+                self.payload.content_length
+            ));
+        }
+        if let Some(boundary) = &self.payload.multipart_boundary {
+            ret_header.push_str(&format!(
+                "Content-Type: multipart/byteranges; boundary={}\r\n",
+                boundary
+            ));
+        } else if self.payload.content_length > 0 || self.payload.event_stream.is_some() {
+            ret_header.push_str(&format!(
+                "Content-Type: {}\r\n",
+                self.payload.content_type_value()
+            ));
+        }
+        for (name, value) in self.headers.iter() {
+            ret_header.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        ret_header.push_str("\r\n");
+        return ret_header;
+    }
+
+    // The value of the first additional header with the given (case-insensitive) name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        for (key, value) in self.headers.iter() {
+            if key.eq_ignore_ascii_case(name) {
+                return Some(value.as_str());
+            }
+        }
+        return None;
+    }
+
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    // Add the 'ETag' and 'Last-Modified' validators of the file at `path` and turn the response
+    // into a 304 or 412 if the request's preconditions say so
+    fn evaluate_preconditions(&mut self, request_data: &RequestHeader, path: &str) {
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return,
+        };
+        // The JSON documents have the tag their 'PATCH' is checked against. Other content
+        // compressed on the fly is not guaranteed to be byte-for-byte identical across
+        // compressor versions, hence only weakly validated.
+        let etag = if is_document_path(std::path::Path::new(path)) {
+            match std::fs::read(path) {
+                Ok(content) => EntityTag::from_content(&content),
+                Err(_) => return,
+            }
+        } else {
+            let mut etag = EntityTag::from_metadata(&metadata, self.payload.data.is_some());
+            if let Some(hash) = blob_hash_from_target(&request_data.target) {
+                etag.opaque_tag = String::from(hash);
+            }
+            if let Some(encoding) = self.header("Content-Encoding") {
+                etag.opaque_tag = format!("{}-{}", etag.opaque_tag, encoding);
+            }
+            etag
+        };
+        let last_modified = metadata.modified().ok();
+        self.add_header("ETag", &etag.to_header_value());
+        if let Some(last_modified) = last_modified {
+            self.add_header("Last-Modified", &format_http_date(last_modified));
+        }
+        match evaluate_preconditions(request_data, &etag, last_modified) {
+            Some(ResponseStatus::NotModified) => {
+                self.status = ResponseStatus::NotModified;
+                self.remove_header("Content-Encoding");
+                self.payload.path = String::new();
+                self.payload.content_length = 0;
+                self.payload.data = None;
+            }
+            Some(status) => self.set_error(status, ""),
+            None => {}
+        }
+    }
+
+    // Replace the response with an error page, the status' default one if `path` is empty
+    fn set_error(&mut self, status: ResponseStatus, path: &str) {
+        let path = if path.is_empty() {
+            status.error_page()
+        } else {
+            path
+        };
+        self.status = status;
+        self.headers.clear();
+        self.payload = ResponsePayload {
+            path: String::from(path),
+            content_type: ContentType::from_file_name(path),
+            media_type: None,
+            content_length: std::fs::metadata(path).unwrap().len(),
+            data: None,
+            segments: Vec::new(),
+            multipart_boundary: None,
+            event_stream: None,
+        };
+    }
+
+    // 'If-Range' makes the 'Range' conditional: if the representation has changed, the client
+    // gets the whole of it instead of pieces that wouldn't fit together
+    fn if_range_holds(&self, if_range: &str) -> bool {
+        let if_range = if_range.trim();
+        if if_range.starts_with('"') || if_range.starts_with("W/") {
+            let current = self.header("ETag").and_then(EntityTagList::parse);
+            return match (EntityTagList::parse(if_range), current) {
+                (Some(EntityTagList::Tags(tags)), Some(EntityTagList::Tags(current))) => {
+                    tags.len() == 1 && current.len() == 1 && tags[0].strong_eq(&current[0])
+                }
+                _ => false,
+            };
+        }
+        let last_modified = self.header("Last-Modified").and_then(parse_http_date);
+        return last_modified.is_some() && last_modified == parse_http_date(if_range);
+    }
+
+    // Turn a successful response into a 206 (or 416) according to the 'Range' header. Only
+    // file-backed payloads are served in pieces, content generated in memory is sent in full.
+    fn select_ranges(&mut self, request_data: &RequestHeader) {
+        if self.payload.data.is_some() {
+            return;
+        }
+        self.add_header("Accept-Ranges", "bytes");
+        let range_str = match request_data.header("Range") {
+            Some(range_str) => range_str,
+            None => return,
+        };
+        // HEAD describes what GET would send, only without the body
+        if request_data.method != RequestMethod::Get && request_data.method != RequestMethod::Head {
+            return;
+        }
+        if let Some(if_range) = request_data.header("If-Range") {
+            if !self.if_range_holds(if_range) {
+                return;
+            }
+        }
+        let complete_length = self.payload.content_length;
+        let ranges = match parse_range(range_str, complete_length) {
+            RangeRequest::Ignored => return,
+            RangeRequest::Unsatisfiable => {
+                self.set_error(ResponseStatus::RangeNotSatisfiable, "");
+                self.add_header("Content-Range", &format!("bytes */{}", complete_length));
+                return;
+            }
+            RangeRequest::Satisfiable(ranges) => ranges,
+        };
+        self.status = ResponseStatus::PartialContent;
+        if ranges.len() == 1 {
+            let range = &ranges[0];
+            self.add_header("Content-Range", &range.to_content_range(complete_length));
+            self.payload.content_length = range.length;
+            self.payload.segments = vec![PayloadSegment::File {
+                offset: range.start,
+                length: range.length,
+            }];
+            return;
+        }
+        // The boundary only has to be absent from the parts' headers and content: a value
+        // derived from the current time is unlikely enough to appear in a file
+        let boundary = format!(
+            "{:032x}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos()
+        );
+        let mut content_length = 0;
+        for range in ranges.iter() {
+            let part_header = format!(
+                "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                boundary,
+                self.payload.content_type_value(),
+                range.to_content_range(complete_length)
+            );
+            content_length += part_header.len() as u64 + range.length;
+            self.payload
+                .segments
+                .push(PayloadSegment::Data(part_header.into_bytes()));
+            self.payload.segments.push(PayloadSegment::File {
+                offset: range.start,
+                length: range.length,
+            });
+        }
+        let closing_delimiter = format!("\r\n--{}--\r\n", boundary);
+        content_length += closing_delimiter.len() as u64;
+        self.payload
+            .segments
+            .push(PayloadSegment::Data(closing_delimiter.into_bytes()));
+        self.payload.content_length = content_length;
+        self.payload.multipart_boundary = Some(boundary);
+    }
+
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.headers.push((String::from(name), String::from(value)));
+    }
+
+    // Pick a compressed representation of the payload if the client accepts one: a precompressed
+    // sidecar file (e.g., 'index.html.br') is preferred, since it can still be sent through
+    // `sendfile`. Otherwise, the payload is compressed in memory if it's worth it.
+    fn encode(&mut self, accept_encoding: Option<&str>) {
+        if !self.payload.content_type.is_compressible() {
+            return;
+        }
+        // The representation depends on 'Accept-Encoding' even when it's sent uncompressed
+        self.add_header("Vary", "Accept-Encoding");
+        let mut available = precompressed_encodings(&self.payload.path);
+        if !available.is_empty() {
+            available.push(ContentEncoding::Identity);
+            let encoding = negotiate_encoding(accept_encoding, &available);
+            if encoding != ContentEncoding::Identity {
+                let sidecar_path = sidecar_path(&self.payload.path, &encoding);
+                match std::fs::metadata(&sidecar_path) {
+                    Ok(metadata) => {
+                        self.payload.path = sidecar_path;
+                        self.payload.content_length = metadata.len();
+                        self.add_header("Content-Encoding", encoding.to_str());
+                        return;
+                    }
+                    Err(e) => println!("Failed to access {}: {}", sidecar_path, e),
+                }
+            }
+        }
+        self.compress(accept_encoding);
+    }
+
+    fn compress(&mut self, accept_encoding: Option<&str>) {
+        if self.payload.data.is_some()
+            || self.payload.content_length < COMPRESSION_MIN_SIZE
+            || self.payload.content_length > COMPRESSION_MAX_SIZE
+        {
+            return;
+        }
+        let encoding = negotiate_encoding(
+            accept_encoding,
+            &[
+                ContentEncoding::Brotli,
+                ContentEncoding::Gzip,
+                ContentEncoding::Identity,
+            ],
+        );
+        if encoding == ContentEncoding::Identity {
+            return;
+        }
+        let compressed = match std::fs::read(&self.payload.path)
+            .and_then(|content| compress(&content, &encoding))
+        {
+            Ok(compressed) => compressed,
+            Err(e) => {
+                println!("Failed to compress {}: {}", self.payload.path, e);
+                return;
+            }
+        };
+        if compressed.len() as u64 >= self.payload.content_length {
+            return;
+        }
+        self.payload.content_length = compressed.len() as u64;
+        self.payload.data = Some(compressed);
+        self.add_header("Content-Encoding", encoding.to_str());
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::http_handler::multipart::FormFile;
+
+    fn text_response(file_name: &str, content: &str) -> Response {
+        let path = std::env::temp_dir().join(file_name);
+        std::fs::write(&path, content).unwrap();
+        return text_file_response(&path);
+    }
+
+    // A response serving a text file as it is on the disk
+    fn text_file_response(path: &std::path::Path) -> Response {
+        return Response {
+            status: ResponseStatus::Ok,
+            payload: ResponsePayload {
+                path: String::from(path.to_str().unwrap()),
+                content_type: ContentType::Text,
+                media_type: None,
+                content_length: std::fs::metadata(path).unwrap().len(),
+                data: None,
+                segments: Vec::new(),
+                multipart_boundary: None,
+                event_stream: None,
+            },
+            headers: Vec::new(),
+        };
+    }
+
+    fn has_header(response: &Response, name: &str, value: &str) -> bool {
+        return response
+            .headers
+            .contains(&(String::from(name), String::from(value)));
+    }
+
+    #[test]
+    pub fn compressed_response() {
+        let request_header = RequestHeader::new(&String::from(
+            "GET /index.html HTTP/1.1\r\nAccept-Encoding: gzip\r\n",
+        ));
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        let file_size = std::fs::metadata("www/index.html").unwrap().len();
+        assert_eq!(response.status, ResponseStatus::Ok);
+        assert!(response.payload.content_length < file_size);
+        // Compressed in memory, or sent from the sidecar generated by the build
+        match &response.payload.data {
+            Some(data) => assert_eq!(data.len() as u64, response.payload.content_length),
+            None => assert_eq!(response.payload.path, "www/index.html.gz"),
+        }
+        assert!(has_header(&response, "Content-Encoding", "gzip"));
+        assert!(has_header(&response, "Vary", "Accept-Encoding"));
+
+        let content = "Hello, TCP! ".repeat(100);
+        let mut response = text_response("http-server-rust-compressed.txt", &content);
+        response.encode(Some("gzip"));
+        assert!(response.payload.content_length < content.len() as u64);
+        assert_eq!(
+            response.payload.data.as_ref().unwrap().len() as u64,
+            response.payload.content_length
+        );
+        assert!(has_header(&response, "Content-Encoding", "gzip"));
+        assert!(has_header(&response, "Vary", "Accept-Encoding"));
+
+        // Small payloads are not worth compressing
+        let mut response = text_response("http-server-rust-small.txt", "Hello, TCP!");
+        response.encode(Some("gzip"));
+        assert_eq!(response.payload.data, None);
+        assert_eq!(response.payload.content_length, 11);
+        assert!(has_header(&response, "Vary", "Accept-Encoding"));
+
+        // Binary files are never compressed
+        let request_header = RequestHeader::new(&String::from(
+            "GET /img/test.png HTTP/1.1\r\nAccept-Encoding: gzip\r\n",
+        ));
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.payload.data, None);
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.header("Vary"), None);
+    }
+
+    #[test]
+    pub fn document_validators() {
+        let path = std::path::Path::new(DATA_DIR)
+            .join(format!("http-server-rust-etag-{}.json", std::process::id()));
+        let path_str = String::from(path.to_str().unwrap());
+        let content = format!("{{\"text\":\"{}\"}}", "a".repeat(1000));
+        std::fs::write(&path, &content).unwrap();
+        let get = |accept_encoding: &str| {
+            let request = RequestHeader::new(&format!(
+                "GET /api/status HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n",
+                accept_encoding
+            ));
+            let mut response = Response::empty(ResponseStatus::Ok);
+            response.payload.path = path_str.clone();
+            response.payload.content_type = ContentType::Json;
+            response.payload.content_length = std::fs::metadata(&path).unwrap().len();
+            response.encode(Some(accept_encoding));
+            response.evaluate_preconditions(&request, &path_str);
+            return response;
+        };
+        // Compressed or not, a document has the tag its 'PATCH' is checked against
+        let response = get("gzip");
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        let etag = String::from(response.header("ETag").unwrap());
+        assert_eq!(get("identity").header("ETag"), Some(etag.as_str()));
+
+        let patch = |etag: &str| {
+            let request = RequestHeader::new(&format!(
+                "PATCH /api/status HTTP/1.1\r\nIf-Match: {}\r\n\r\n",
+                etag
+            ));
+            return Document::File(path.clone()).update(|current, etag| {
+                if let Some(status) = evaluate_preconditions(&request, etag, None) {
+                    return Err(status);
+                }
+                let mut changed = current.clone();
+                changed.set("patched", JsonValue::Bool(true));
+                return Ok(changed);
+            });
+        };
+        let (_, new_etag) = patch(&etag).unwrap();
+        assert_eq!(
+            get("gzip").header("ETag"),
+            Some(new_etag.to_header_value().as_str())
+        );
+        assert_eq!(
+            patch(&etag).unwrap_err(),
+            ResponseStatus::PreconditionFailed
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    pub fn conditional_response() {
+        let request_header = RequestHeader::new("GET /index.html HTTP/1.1\r\n");
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::Ok);
+        let etag = String::from(response.header("ETag").unwrap());
+        let last_modified = String::from(response.header("Last-Modified").unwrap());
+
+        let request_header = RequestHeader::new(&format!(
+            "GET /index.html HTTP/1.1\r\nIf-None-Match: \"other\", {}\r\n",
+            etag
+        ));
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::NotModified);
+        assert_eq!(response.payload.content_length, 0);
+        assert_eq!(response.header("ETag"), Some(etag.as_str()));
+
+        let request_header = RequestHeader::new(&format!(
+            "GET /index.html HTTP/1.1\r\nIf-Modified-Since: {}\r\n",
+            last_modified
+        ));
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::NotModified);
+
+        // A different representation has a different validator
+        let request_header = RequestHeader::new(&format!(
+            "GET /index.html HTTP/1.1\r\nAccept-Encoding: gzip\r\nIf-None-Match: {}\r\n",
+            etag
+        ));
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::Ok);
+        assert_ne!(response.header("ETag"), Some(etag.as_str()));
+
+        let request_header =
+            RequestHeader::new("GET /index.html HTTP/1.1\r\nIf-Match: \"outdated\"\r\n");
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::PreconditionFailed);
+        assert_eq!(response.payload.path, "www/precondition_failed.html");
+    }
+
+    #[test]
+    pub fn undecodable_query() {
+        // Static files never read the query
+        let request_header = RequestHeader::new("GET /index.html?x=%ff HTTP/1.1\r\n");
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::Ok);
+
+        // The targets reading it refuse it
+        let request_header = RequestHeader::new("GET /api/uploads?x=%ff HTTP/1.1\r\n");
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::BadRequest);
+    }
+
+    #[test]
+    pub fn range_response() {
+        let file_size = std::fs::metadata("www/img/test.png").unwrap().len();
+        let request_header =
+            RequestHeader::new("GET /img/test.png HTTP/1.1\r\nRange: bytes=10-19\r\n");
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::PartialContent);
+        assert_eq!(response.payload.content_length, 10);
+        assert_eq!(
+            response.payload.segments,
+            vec![PayloadSegment::File {
+                offset: 10,
+                length: 10
+            }]
+        );
+        let content_range = format!("bytes 10-19/{}", file_size);
+        assert_eq!(
+            response.header("Content-Range"),
+            Some(content_range.as_str())
+        );
+        let request_header =
+            RequestHeader::new("HEAD /img/test.png HTTP/1.1\r\nRange: bytes=10-19\r\n");
+        let head_response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(head_response.status, ResponseStatus::PartialContent);
+        assert_eq!(
+            head_response.header_to_string(&RequestHttpVersion::Http11),
+            response.header_to_string(&RequestHttpVersion::Http11)
+        );
+        assert!(!head_response.has_body(&request_header));
+
+        let request_header =
+            RequestHeader::new("GET /img/test.png HTTP/1.1\r\nRange: bytes=0-0,-1\r\n");
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::PartialContent);
+        let boundary = response.payload.multipart_boundary.clone().unwrap();
+        assert_eq!(response.payload.segments.len(), 5);
+        let segments_length: u64 = response
+            .payload
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                PayloadSegment::Data(data) => data.len() as u64,
+                PayloadSegment::File { length, .. } => *length,
+            })
+            .sum();
+        assert_eq!(segments_length, response.payload.content_length);
+        assert!(response
+            .header_to_string(&RequestHttpVersion::Http11)
+            .contains(&format!("multipart/byteranges; boundary={}", boundary)));
+
+        let request_header = RequestHeader::new(&format!(
+            "GET /img/test.png HTTP/1.1\r\nRange: bytes={}-\r\n",
+            file_size
+        ));
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::RangeNotSatisfiable);
+        let content_range = format!("bytes */{}", file_size);
+        assert_eq!(
+            response.header("Content-Range"),
+            Some(content_range.as_str())
+        );
+
+        // The whole file is sent when 'If-Range' doesn't match
+        let request_header = RequestHeader::new(
+            "GET /img/test.png HTTP/1.1\r\nRange: bytes=10-19\r\nIf-Range: \"old\"\r\n",
+        );
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::Ok);
+        assert_eq!(response.payload.content_length, file_size);
+        assert!(response.payload.segments.is_empty());
+        let request_header = RequestHeader::new(&format!(
+            "GET /img/test.png HTTP/1.1\r\nRange: bytes=10-19\r\nIf-Range: {}\r\n",
+            response.header("ETag").unwrap()
+        ));
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::PartialContent);
+    }
+
+    #[test]
+    pub fn head_and_options() {
+        let get_response = Response::new(
+            &RequestHeader::new("GET /index.html HTTP/1.1\r\nAccept-Encoding: br\r\n"),
+            &RequestBody::Empty,
+        );
+        let request_header =
+            RequestHeader::new("HEAD /index.html HTTP/1.1\r\nAccept-Encoding: br\r\n");
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(
+            response.header_to_string(&RequestHttpVersion::Http11),
+            get_response.header_to_string(&RequestHttpVersion::Http11)
+        );
+        assert!(!response.has_body(&request_header));
+
+        let request_header = RequestHeader::new("OPTIONS /index.html HTTP/1.1\r\n");
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::NoContent);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, OPTIONS"));
+
+        let request_header = RequestHeader::new("OPTIONS * HTTP/1.1\r\n");
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::NoContent);
+        assert_eq!(
+            response.header("Allow"),
+            Some("GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS")
+        );
+
+        let request_header = RequestHeader::new("POST /index.html HTTP/1.1\r\n");
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::MethodNotAllowed);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, OPTIONS"));
+
+        let request_header = RequestHeader::new("GET /api/set HTTP/1.1\r\n");
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::MethodNotAllowed);
+        assert_eq!(response.header("Allow"), Some("POST, OPTIONS"));
+
+        let request_header = RequestHeader::new("DELETE /index.html HTTP/1.1\r\n");
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::MethodNotAllowed);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, OPTIONS"));
+
+        let request_header = RequestHeader::new("POST /api/not_found HTTP/1.1\r\n");
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::NotFound);
+
+        // A method the server doesn't know, on a target with and without methods
+        let request_header = RequestHeader::new("BREW /index.html HTTP/1.1\r\n");
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::MethodNotAllowed);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, OPTIONS"));
+        let request_header = RequestHeader::new("BREW /not_found.html HTTP/1.1\r\n");
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::NotFound);
+        assert_eq!(response.header("Allow"), None);
+    }
+
+    #[test]
+    pub fn file_store_responses() {
+        let request_header = RequestHeader::new("PUT /files/a.txt HTTP/1.1\r\n");
+        let response = Response::new(
+            &request_header,
+            &RequestBody::Stored {
+                path: String::from("artifacts/files/a.txt"),
+                created: true,
+                digest: [0; 32],
+            },
+        );
+        assert_eq!(response.status, ResponseStatus::Created);
+        assert_eq!(response.header("Location"), Some("/files/a.txt"));
+        assert_eq!(
+            response.header("Repr-Digest"),
+            Some("sha-256=:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=:")
+        );
+        let response = Response::new(
+            &request_header,
+            &RequestBody::Stored {
+                path: String::from("artifacts/files/a.txt"),
+                created: false,
+                digest: [0; 32],
+            },
+        );
+        assert_eq!(response.status, ResponseStatus::NoContent);
+        assert_eq!(response.header("Location"), None);
+        let response = Response::new(
+            &request_header,
+            &RequestBody::Rejected(ResponseStatus::BadRequest),
+        );
+        assert_eq!(response.status, ResponseStatus::BadRequest);
+        assert_eq!(response.payload.path, "www/bad_request.html");
+
+        let request_header = RequestHeader::new("GET /files/..%2Fsecret HTTP/1.1\r\n");
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::BadRequest);
+        let request_header = RequestHeader::new("GET /files/missing.txt HTTP/1.1\r\n");
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::NotFound);
+        let request_header = RequestHeader::new("DELETE /files/a.txt HTTP/1.1\r\n");
+        let response = Response::new(&request_header, &RequestBody::Deleted);
+        assert_eq!(response.status, ResponseStatus::NoContent);
+        let response = Response::new(
+            &request_header,
+            &RequestBody::Rejected(ResponseStatus::NotFound),
+        );
+        assert_eq!(response.status, ResponseStatus::NotFound);
+    }
+
+    #[test]
+    pub fn upload_responses() {
+        let request_header = RequestHeader::new("POST /api/uploads HTTP/1.1\r\n");
+        let id = "0123456789abcdef01234567";
+        let response = Response::new(
+            &request_header,
+            &RequestBody::Uploaded {
+                id: String::from(id),
+                digest: [0xab; 32],
+            },
+        );
+        assert_eq!(response.status, ResponseStatus::Created);
+        let location = format!("/api/uploads/{}", id);
+        assert_eq!(response.header("Location"), Some(location.as_str()));
+        assert_eq!(
+            response.payload.data,
+            Some(format!("{{\"id\":\"{}\",\"sha256\":\"{}\"}}", id, "ab".repeat(32)).into_bytes())
+        );
+        assert_eq!(
+            response.header("Repr-Digest"),
+            Some("sha-256=:q6urq6urq6urq6urq6urq6urq6urq6urq6urq6urq6s=:")
+        );
+        assert_eq!(response.payload.content_type, ContentType::Json);
+
+        let form = FormData {
+            fields: vec![(String::from("firstName"), String::from("Ada \"A\""))],
+            files: vec![FormFile {
+                field: String::from("upload"),
+                id: String::from(id),
+                filename: String::from("a.txt"),
+                size: 5,
+                sha256: String::from("2cf2"),
+            }],
+        };
+        let response = Response::new(
+            &request_header,
+            &RequestBody::Form {
+                form,
+                digest: [0; 32],
+            },
+        );
+        assert_eq!(response.status, ResponseStatus::Created);
+        assert_eq!(response.header("Location"), Some(location.as_str()));
+        assert_eq!(
+            String::from_utf8(response.payload.data.unwrap()).unwrap(),
+            format!(
+                "{{\"fields\":[{{\"name\":\"firstName\",\"value\":\"Ada \\\"A\\\"\"}}],\
+                 \"files\":[{{\"field\":\"upload\",\"id\":\"{}\",\"filename\":\"a.txt\",\"size\":5,\
+                 \"sha256\":\"2cf2\",\"location\":\"{}\"}}]}}",
+                id, location
+            )
+        );
+        let response = Response::new(
+            &request_header,
+            &RequestBody::Form {
+                form: FormData::default(),
+                digest: [0; 32],
+            },
+        );
+        assert_eq!(response.status, ResponseStatus::Ok);
+        assert_eq!(response.header("Location"), None);
+
+        let request_header = RequestHeader::new("GET /api/uploads/../../tuples.rs HTTP/1.1\r\n");
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::BadRequest);
+        let request_header = RequestHeader::new(&format!("GET {} HTTP/1.1\r\n", location));
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::NotFound);
+        let request_header = RequestHeader::new("GET /api/blobs/../../../tuples.rs HTTP/1.1\r\n");
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::BadRequest);
+        let request_header =
+            RequestHeader::new(&format!("HEAD /api/blobs/{} HTTP/1.1\r\n", "0".repeat(64)));
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::NotFound);
+        assert_eq!(response.header("Cache-Control"), None);
+        let request_header =
+            RequestHeader::new(&format!("PUT /api/blobs/{} HTTP/1.1\r\n", "0".repeat(64)));
+        let response = Response::new(&request_header, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::MethodNotAllowed);
+    }
+
+    #[test]
+    pub fn upload_list_and_media_type() {
+        let mut response = text_response("http-server-rust-media-type.txt", "hello");
+        response.payload.media_type = Some(String::from("text/plain; charset=latin1"));
+        let header = response.header_to_string(&RequestHttpVersion::Http11);
+        assert!(header.contains("Content-Type: text/plain; charset=latin1\r\n"));
+
+        // Invalid pages and filters are rejected before the store is read
+        for query in [
+            "limit=0",
+            "limit=1001",
+            "offset=-1",
+            "limit=x",
+            "content_type=image",
+        ] {
+            let request = RequestHeader::new(&format!(
+                "GET /api/uploads?{} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+                query
+            ));
+            let response = Response::upload_list(&request);
+            assert_eq!(response.status, ResponseStatus::BadRequest, "{}", query);
+        }
+    }
+
+    #[test]
+    pub fn json_api_responses() {
+        let request = RequestHeader::new("POST /api/set HTTP/1.1\r\n\r\n");
+        let state = JsonValue::Object(vec![(String::from("on"), JsonValue::Bool(true))]);
+        let response = Response::new(&request, &RequestBody::State(state));
+        assert_eq!(response.status, ResponseStatus::Ok);
+        assert_eq!(
+            response.payload.data.as_deref(),
+            Some(&b"{\"on\":true}"[..])
+        );
+        assert_eq!(response.header("Cache-Control"), Some("no-store"));
+
+        let e = JsonValue::parse("{\"on\": yes}").unwrap_err();
+        let response = Response::new(&request, &RequestBody::MalformedJson(e));
+        assert_eq!(response.status, ResponseStatus::BadRequest);
+        assert_eq!(response.payload.content_type, ContentType::Json);
+        assert_eq!(
+            response.payload.data.as_deref(),
+            Some(
+                &b"{\"error\":\"malformed JSON\",\"message\":\"unexpected character, expected a \
+                   value\",\"line\":1,\"column\":8,\"offset\":7}"[..]
+            )
+        );
+
+        let request = RequestHeader::new("GET /api/status HTTP/1.1\r\n\r\n");
+        let response = Response::new(&request, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::Ok);
+        assert_eq!(response.payload.content_type, ContentType::Json);
+
+        // The state can be patched, with the entity tag it is served with
+        assert!(response.header("ETag").is_some());
+        let request = RequestHeader::new("OPTIONS /api/status HTTP/1.1\r\n\r\n");
+        let response = Response::new(&request, &RequestBody::Empty);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, PATCH, OPTIONS"));
+        assert_eq!(
+            response.header("Accept-Patch"),
+            Some("application/json-patch+json, application/merge-patch+json")
+        );
+        let request = RequestHeader::new("PATCH /api/status HTTP/1.1\r\n\r\n");
+        let value = JsonValue::Object(Vec::new());
+        let etag = document_etag(&value);
+        let response = Response::new(&request, &RequestBody::Document { value, etag });
+        assert_eq!(response.status, ResponseStatus::Ok);
+        assert_eq!(response.payload.data.as_deref(), Some(&b"{}"[..]));
+        assert_eq!(
+            response.header("ETag"),
+            Some("\"44136fa355b3678a1146ad16f7e8649e\"")
+        );
+        let response = Response::new(
+            &request,
+            &RequestBody::Rejected(ResponseStatus::UnsupportedMediaType),
+        );
+        assert!(response.header("Accept-Patch").is_some());
+
+        // New items of a collection are told where they are served
+        let item = JsonValue::parse("{\"id\":3}").unwrap();
+        let response = Response::item_stored("/api/books/3", &item, true);
+        assert_eq!(response.status, ResponseStatus::Created);
+        assert_eq!(response.header("Location"), Some("/api/books/3"));
+        assert_eq!(response.payload.data.as_deref(), Some(&b"{\"id\":3}"[..]));
+        let response = Response::item_stored("/api/books/3", &item, false);
+        assert_eq!(response.status, ResponseStatus::Ok);
+        assert_eq!(response.header("Location"), None);
+
+        // A JSON Pointer selects a single value
+        let request = RequestHeader::new("GET /api/status?pointer=/test_status HTTP/1.1\r\n\r\n");
+        let response = Response::new(&request, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::Ok);
+        assert!(response.payload.data.is_some());
+        for (pointer, status) in [
+            ("/missing", ResponseStatus::NotFound),
+            ("/test_status/0", ResponseStatus::NotFound),
+            ("test_status", ResponseStatus::BadRequest),
+            ("/a~2", ResponseStatus::BadRequest),
+        ] {
+            let request = format!("GET /api/status?pointer={} HTTP/1.1\r\n\r\n", pointer);
+            let response = Response::new(&RequestHeader::new(&request), &RequestBody::Empty);
+            assert_eq!(response.status, status);
+        }
+    }
+
+    #[test]
+    pub fn progress_responses() {
+        let id = "0000000000000000000000bb";
+        let target = format!("/api/uploads/{}/progress", id);
+        let request = RequestHeader::new(&format!("GET {} HTTP/1.1\r\n\r\n", target));
+        let response = Response::new(&request, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::NotFound);
+
+        let transfer = Transfer::start("192.0.2.20", 10);
+        transfer.attach(id);
+        transfer.advance(4);
+        let response = Response::new(&request, &RequestBody::Empty);
+        assert_eq!(response.status, ResponseStatus::Ok);
+        assert_eq!(response.payload.content_type, ContentType::Json);
+        assert_eq!(response.header("Cache-Control"), Some("no-store"));
+
+        // Events go on until the transfer is over, then there is only the outcome to tell
+        let request = RequestHeader::new(&format!(
+            "GET {} HTTP/1.1\r\nAccept: text/event-stream\r\n\r\n",
+            target
+        ));
+        let response = Response::new(&request, &RequestBody::Empty);
+        assert_eq!(
+            response.payload.event_stream,
+            Some(ProgressFeed::Upload(String::from(id)))
+        );
+        assert!(response.has_body(&request));
+        let header = response.header_to_string(&RequestHttpVersion::Http11);
+        assert!(header.contains("Content-Type: text/event-stream\r\n"));
+        assert!(!header.contains("Content-Length"));
+        transfer.finish(true);
+        let response = Response::new(&request, &RequestBody::Empty);
+        assert_eq!(response.payload.event_stream, None);
+        assert_eq!(response.payload.content_type, ContentType::EventStream);
+        assert!(response.payload.content_length > 0);
+
+        let mut request = RequestHeader::new("GET /api/uploads/progress HTTP/1.1\r\n\r\n");
+        request.client_address = String::from("192.0.2.20");
+        let response = Response::new(&request, &RequestBody::Empty);
+        assert_eq!(
+            response.payload.event_stream,
+            Some(ProgressFeed::Client(String::from("192.0.2.20")))
+        );
+    }
+
+    #[test]
+    pub fn precompressed_response() {
+        let dir = std::env::temp_dir().join(format!(
+            "http-server-rust-precompressed-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.txt");
+        let content = "Hello, TCP! ".repeat(100);
+        std::fs::write(&path, &content).unwrap();
+        let gzip_path = sidecar_path(path.to_str().unwrap(), &ContentEncoding::Gzip);
+        std::fs::write(&gzip_path, "gzip sidecar").unwrap();
+
+        let mut response = text_file_response(&path);
+        response.encode(Some("br, gzip"));
+        assert_eq!(response.payload.path, gzip_path);
+        assert_eq!(response.payload.content_length, 12);
+        assert_eq!(response.payload.data, None);
+        assert!(has_header(&response, "Content-Encoding", "gzip"));
+
+        // The original is kept for clients that don't accept the sidecar's encoding
+        let mut response = text_file_response(&path);
+        response.encode(Some("identity"));
+        assert_eq!(response.payload.path, path.to_str().unwrap());
+        assert_eq!(response.payload.content_length, content.len() as u64);
+        assert_eq!(response.header("Content-Encoding"), None);
+
+        // A sidecar older than the original is stale: the original is compressed instead
+        std::fs::File::options()
+            .write(true)
+            .open(&gzip_path)
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH)
+            .unwrap();
+        let mut response = text_file_response(&path);
+        response.encode(Some("gzip"));
+        assert_eq!(response.payload.path, path.to_str().unwrap());
+        assert!(response.payload.data.is_some());
+        assert!(has_header(&response, "Content-Encoding", "gzip"));
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,230 @@
+use super::common::MediaType;
+use super::digest::base64_decode;
+use super::sniff::*;
+use super::store::copy_body;
+use super::uploads::*;
+use std::io::{Read, Write};
+
+// Resumable uploads, in the style of the tus protocol: the client announces the total length
+// with `POST /api/uploads` and 'Upload-Length', then sends the data in as many
+// `PATCH /api/uploads/<id>` requests as it takes, each starting at the 'Upload-Offset' reached so
+// far, which `HEAD` tells after a broken connection. Once the last byte is in, the upload joins
+// the others.
+//
+// Until then the upload's directory holds its data so far and what the client announced, the
+// expected length being the metadata's 'size'. It has no metadata file, so it isn't listed nor
+// served, and is swept if abandoned.
+pub const UPLOAD_INFO_FILE: &str = "resumable";
+
+#[derive(Debug, PartialEq)]
+pub struct ResumableUpload {
+    pub id: String,
+    pub length: u64,
+    pub offset: u64,
+    // How many seconds the upload will be kept once complete, as chosen when it was created
+    pub ttl: Option<u64>,
+    metadata: UploadMetadata,
+}
+
+// The 'filename' and 'filetype' of an 'Upload-Metadata' value, comma-separated "key value" pairs
+// with base64-encoded values. Other keys are ignored.
+pub fn parse_upload_metadata(input: &str) -> Option<(Option<String>, Option<String>)> {
+    let mut filename: Option<String> = None;
+    let mut filetype: Option<String> = None;
+    for pair in input.split(',').map(|pair| pair.trim()) {
+        if pair.is_empty() {
+            continue;
+        }
+        let (key, value) = match pair.split_once(' ') {
+            Some((key, value)) => (key, String::from_utf8(base64_decode(value.trim())?).ok()?),
+            None => (pair, String::new()),
+        };
+        match key {
+            "filename" => filename = Some(sanitize_file_name(&value)),
+            "filetype" => {
+                MediaType::parse(&value)?;
+                filetype = Some(value);
+            }
+            _ => {}
+        }
+    }
+    return Some((filename, filetype));
+}
+
+impl ResumableUpload {
+    // Start a resumable upload of `length` bytes, `metadata` describing what it will be
+    pub fn create(
+        store: &UploadStore,
+        length: u64,
+        metadata: UploadMetadata,
+    ) -> std::io::Result<Self> {
+        let (id, upload_dir) = reserve_upload(&store.dir)?;
+        let mut ret_upload = Self {
+            id,
+            length,
+            offset: 0,
+            ttl: store.ttl,
+            metadata,
+        };
+        ret_upload.metadata.size = length;
+        let result = std::fs::File::create(upload_dir.join(UPLOAD_PARTIAL_FILE))
+            .and_then(|_| ret_upload.to_text())
+            .and_then(|text| std::fs::write(upload_dir.join(UPLOAD_INFO_FILE), text));
+        if let Err(e) = result {
+            let _ = std::fs::remove_dir_all(&upload_dir);
+            return Err(e);
+        }
+        return Ok(ret_upload);
+    }
+
+    // A resumable upload in progress. Fails with `NotFound` if there is none with this ID, e.g.
+    // because it is complete.
+    pub fn open(store: &UploadStore, id: &str) -> std::io::Result<Self> {
+        let upload_dir = store.dir.join(id);
+        let text = std::fs::read_to_string(upload_dir.join(UPLOAD_INFO_FILE))?;
+        let metadata = UploadMetadata::parse(&text);
+        let ttl = text
+            .lines()
+            .find_map(|line| line.strip_prefix("ttl:"))
+            .and_then(|ttl| ttl.trim().parse::<u64>().ok());
+        let offset = std::fs::metadata(upload_dir.join(UPLOAD_PARTIAL_FILE))?.len();
+        return Ok(Self {
+            id: String::from(id),
+            length: metadata.size,
+            offset,
+            ttl,
+            metadata,
+        });
+    }
+
+    fn to_text(&self) -> std::io::Result<String> {
+        return Ok(format!(
+            "{}ttl: {}\n",
+            self.metadata.to_text()?,
+            self.ttl.map(|ttl| ttl.to_string()).unwrap_or_default()
+        ));
+    }
+
+    pub fn is_complete(&self) -> bool {
+        return self.offset == self.length;
+    }
+
+    // Append a chunk of `length` bytes. What is received stays even if the chunk is cut short,
+    // so that the client can resume from there, unless `all_or_nothing`.
+    pub fn append(
+        &mut self,
+        store: &UploadStore,
+        reader: &mut impl Read,
+        length: u64,
+        all_or_nothing: bool,
+    ) -> std::io::Result<()> {
+        if self.offset.saturating_add(length) > self.length {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{} bytes at offset {} exceed the length of {}",
+                    length, self.offset, self.length
+                ),
+            ));
+        }
+        let mut file = std::fs::File::options()
+            .append(true)
+            .open(store.dir.join(&self.id).join(UPLOAD_PARTIAL_FILE))?;
+        let result = copy_body(reader, &mut file, length).and_then(|_| file.flush());
+        if result.is_err() && all_or_nothing {
+            file.set_len(self.offset)?;
+        }
+        file.sync_data()?;
+        self.offset = file.metadata()?.len();
+        return result;
+    }
+
+    // Move the complete data into the store, its type checked under `policy`. Returns the
+    // metadata of the new upload.
+    pub fn finish(
+        self,
+        store: &UploadStore,
+        policy: SniffPolicy,
+    ) -> std::io::Result<UploadMetadata> {
+        let mut store = store.clone();
+        store.ttl = self.ttl;
+        let writer = UploadWriter::from_partial(&store, &self.id)?;
+        let mut metadata = self.metadata;
+        if let Err(e) = check_content_type(&mut metadata, writer.head(), policy) {
+            writer.abort();
+            return Err(e);
+        }
+        std::fs::remove_file(store.dir.join(&self.id).join(UPLOAD_INFO_FILE))?;
+        writer.finish(&mut metadata)?;
+        return Ok(metadata);
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::http_handler::uploads::test::TempUploadStore;
+
+    #[test]
+    pub fn upload_metadata_header() {
+        assert_eq!(
+            parse_upload_metadata("filename cmVwb3J0LnBkZg==, filetype YXBwbGljYXRpb24vcGRm,is"),
+            Some((
+                Some(String::from("report.pdf")),
+                Some(String::from("application/pdf"))
+            ))
+        );
+        assert_eq!(
+            parse_upload_metadata("filename Li4vLi4vZXRjL3Bhc3N3ZA=="),
+            Some((Some(String::from("passwd")), None))
+        );
+        assert_eq!(parse_upload_metadata(""), Some((None, None)));
+        assert_eq!(parse_upload_metadata("filename !!"), None);
+        assert_eq!(parse_upload_metadata("filetype dGV4dA=="), None);
+    }
+
+    #[test]
+    pub fn resumable_upload() {
+        let store = TempUploadStore::new("resumable", false, Some(60));
+        let mut metadata = UploadMetadata::new("image/png");
+        metadata.filename = String::from("hello.txt");
+        let upload = ResumableUpload::create(&store, 11, metadata).unwrap();
+        let id = upload.id.clone();
+        assert_eq!(upload.offset, 0);
+        assert!(store.list().unwrap().is_empty());
+        let mut store = store.clone();
+        store.ttl = None;
+
+        // A broken connection keeps what was received, unless the chunk must be whole
+        let mut upload = ResumableUpload::open(&store, &id).unwrap();
+        assert_eq!(upload.ttl, Some(60));
+        assert!(upload
+            .append(&store, &mut "hel".as_bytes(), 5, false)
+            .is_err());
+        assert_eq!(upload.offset, 3);
+        assert!(upload
+            .append(&store, &mut "lo".as_bytes(), 5, true)
+            .is_err());
+        assert_eq!(ResumableUpload::open(&store, &id).unwrap().offset, 3);
+        let e = upload.append(&store, &mut "lo world!".as_bytes(), 9, false);
+        assert_eq!(e.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        upload
+            .append(&store, &mut "lo world".as_bytes(), 8, false)
+            .unwrap();
+        assert!(upload.is_complete());
+
+        let metadata = upload.finish(&store, SniffPolicy::Correct).unwrap();
+        assert_eq!(metadata.size, 11);
+        assert_eq!(metadata.content_type, "text/plain");
+        assert_eq!(metadata.detected_type.as_deref(), Some("text/plain"));
+        assert_eq!(metadata.filename, "hello.txt");
+        assert_eq!(metadata.expires_at, Some(metadata.uploaded_at + 60));
+        assert_eq!(
+            std::fs::read_to_string(store.data_path(&id)).unwrap(),
+            "hello world"
+        );
+        assert_eq!(store.list().unwrap().len(), 1);
+        let e = ResumableUpload::open(&store, &id).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,158 @@
+use super::blobs::*;
+use super::collections::collection_from_target;
+use super::documents::Document;
+use super::files::*;
+use super::request::RequestMethod;
+use super::sniff::SniffPolicy;
+use super::state::SET_TARGET;
+use super::store::*;
+use super::uploads::*;
+
+// Every method the server knows how to handle, as advertised by 'OPTIONS *'
+const SERVER_METHODS: [RequestMethod; 7] = [
+    RequestMethod::Get,
+    RequestMethod::Head,
+    RequestMethod::Post,
+    RequestMethod::Put,
+    RequestMethod::Patch,
+    RequestMethod::Delete,
+    RequestMethod::Options,
+];
+
+// The methods allowed on a request target. An empty list means the target doesn't exist.
+pub fn allowed_methods(target: &str) -> Vec<RequestMethod> {
+    if target == "*" {
+        return SERVER_METHODS.to_vec();
+    }
+    if target == SET_TARGET {
+        return vec![RequestMethod::Post, RequestMethod::Options];
+    }
+    if target == UPLOADS_TARGET {
+        return vec![
+            RequestMethod::Get,
+            RequestMethod::Head,
+            RequestMethod::Post,
+            RequestMethod::Options,
+        ];
+    }
+    if target == UPLOADS_PROGRESS_TARGET || progress_id_from_target(target).is_some() {
+        return vec![
+            RequestMethod::Get,
+            RequestMethod::Head,
+            RequestMethod::Options,
+        ];
+    }
+    // Resumable uploads receive their data in chunks
+    if upload_id_from_target(target).is_some() {
+        return vec![
+            RequestMethod::Get,
+            RequestMethod::Head,
+            RequestMethod::Patch,
+            RequestMethod::Options,
+        ];
+    }
+    if blob_hash_from_target(target).is_some() {
+        return vec![
+            RequestMethod::Get,
+            RequestMethod::Head,
+            RequestMethod::Options,
+        ];
+    }
+    if file_name_from_target(target).is_some() {
+        return vec![
+            RequestMethod::Get,
+            RequestMethod::Head,
+            RequestMethod::Put,
+            RequestMethod::Delete,
+            RequestMethod::Options,
+        ];
+    }
+    // The items of the JSON collections are created in their collection, or under their ID
+    match collection_from_target(target) {
+        Some((_, None)) => {
+            return vec![
+                RequestMethod::Get,
+                RequestMethod::Head,
+                RequestMethod::Post,
+                RequestMethod::Options,
+            ];
+        }
+        Some((_, Some(_))) => {
+            return vec![
+                RequestMethod::Get,
+                RequestMethod::Head,
+                RequestMethod::Put,
+                RequestMethod::Delete,
+                RequestMethod::Options,
+            ];
+        }
+        None => {}
+    }
+    // The JSON documents of the API can be patched
+    if Document::from_target(target).is_some() {
+        return vec![
+            RequestMethod::Get,
+            RequestMethod::Head,
+            RequestMethod::Patch,
+            RequestMethod::Options,
+        ];
+    }
+    if !from_address_to_path(target).is_empty() {
+        return vec![
+            RequestMethod::Get,
+            RequestMethod::Head,
+            RequestMethod::Options,
+        ];
+    }
+    return Vec::new();
+}
+
+// How the uploads of a route are checked against their content. Bodies posted in one go must be
+// what they claim; the chunks of a resumable upload are accepted as they come, so only its type
+// can still be set right once complete.
+pub fn sniff_policy(method: &RequestMethod, target: &str) -> SniffPolicy {
+    if *method == RequestMethod::Patch && upload_id_from_target(target).is_some() {
+        return SniffPolicy::Correct;
+    }
+    return SniffPolicy::Reject;
+}
+
+// The value of the 'Allow' header field for a list of methods, e.g. "GET, HEAD, OPTIONS"
+pub fn allow_header(methods: &[RequestMethod]) -> String {
+    return methods
+        .iter()
+        .map(|method| method.to_str())
+        .collect::<Vec<&str>>()
+        .join(", ");
+}
+
+// The file served by GET on a request target, if any: either a static address from 'tuples.rs',
+// a file of the store, an upload or a blob
+pub fn resolve_file(target: &str) -> Option<String> {
+    let address = from_address_to_path(target);
+    if !address.is_empty() {
+        return Some(String::from(address));
+    }
+    if let Some(id) = upload_id_from_target(target) {
+        if !is_valid_upload_id(id) {
+            return None;
+        }
+        return UploadStore::configured()
+            .data_path(id)
+            .to_str()
+            .map(String::from);
+    }
+    if let Some(hash) = blob_hash_from_target(target) {
+        if !is_valid_blob_hash(hash) {
+            return None;
+        }
+        return blob_path(std::path::Path::new(BLOBS_DIR), hash)
+            .to_str()
+            .map(String::from);
+    }
+    let name = file_name_from_target(target)?;
+    if !is_valid_file_name(name) {
+        return None;
+    }
+    return Some(format!("{}/{}", FILES_DIR, name));
+}
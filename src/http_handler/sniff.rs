@@ -0,0 +1,211 @@
+use super::common::MediaType;
+use super::uploads::UploadMetadata;
+
+// How many bytes at the start of a body are looked at to tell what it is
+pub const SNIFF_LENGTH: usize = 512;
+const GENERIC_CONTENT_TYPE: &str = "application/octet-stream";
+const TEXT_CONTENT_TYPE: &str = "text/plain";
+
+// What happens to an upload whose content isn't what its 'Content-Type' claims
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SniffPolicy {
+    // The upload is refused with 415 Unsupported Media Type
+    Reject,
+    // The upload is kept, with the detected type instead
+    Correct,
+}
+
+// The bytes found at given offsets of every file of a format, its "magic number"
+type Signature = &'static [(usize, &'static [u8])];
+
+// The formats recognized by their signature
+const SIGNATURES: [(&str, Signature); 15] = [
+    ("image/png", &[(0, b"\x89PNG\r\n\x1a\n")]),
+    ("image/jpeg", &[(0, b"\xff\xd8\xff")]),
+    ("image/gif", &[(0, b"GIF87a")]),
+    ("image/gif", &[(0, b"GIF89a")]),
+    ("image/webp", &[(0, b"RIFF"), (8, b"WEBP")]),
+    ("application/pdf", &[(0, b"%PDF-")]),
+    ("application/zip", &[(0, b"PK\x03\x04")]),
+    ("application/gzip", &[(0, b"\x1f\x8b\x08")]),
+    ("audio/mpeg", &[(0, b"ID3")]),
+    // MP3 without an ID3v2 tag, starting with the sync word of an MPEG-1 or MPEG-2 Layer III frame
+    ("audio/mpeg", &[(0, b"\xff\xfb")]),
+    ("audio/mpeg", &[(0, b"\xff\xf3")]),
+    ("audio/mpeg", &[(0, b"\xff\xf2")]),
+    ("audio/wav", &[(0, b"RIFF"), (8, b"WAVE")]),
+    ("audio/ogg", &[(0, b"OggS\x00")]),
+    ("video/mp4", &[(4, b"ftyp")]),
+];
+
+// Bytes that never occur in text, control characters other than whitespace and escape
+// (WHATWG MIME Sniffing, section 3)
+fn is_binary_byte(byte: u8) -> bool {
+    return matches!(byte, 0x00..=0x08 | 0x0b | 0x0e..=0x1a | 0x1c..=0x1f);
+}
+
+// What the first bytes of a body say it is: one of the formats with a signature, "text/plain"
+// if there is no binary byte (or a UTF-16 byte order mark), or `None` for other binary data
+pub fn sniff(head: &[u8]) -> Option<&'static str> {
+    for (content_type, signature) in SIGNATURES.iter() {
+        if signature
+            .iter()
+            .all(|(offset, bytes)| head.get(*offset..offset + bytes.len()) == Some(*bytes))
+        {
+            return Some(content_type);
+        }
+    }
+    if head.starts_with(b"\xfe\xff") || head.starts_with(b"\xff\xfe") {
+        return Some(TEXT_CONTENT_TYPE);
+    }
+    if !head.is_empty() && !head.iter().any(|byte| is_binary_byte(*byte)) {
+        return Some(TEXT_CONTENT_TYPE);
+    }
+    return None;
+}
+
+// Whether content detected as `detected` can't be of the claimed type: a format with a signature
+// must have it, text must not be binary (UTF-16 text being checked as it is received). Types the
+// server knows nothing about, such as formats built on top of ZIP, are taken at their word.
+fn contradicts(claimed: &MediaType, detected: Option<&str>) -> bool {
+    let essence = claimed.essence();
+    if SIGNATURES
+        .iter()
+        .any(|(content_type, _)| *content_type == essence)
+    {
+        return detected != Some(essence.as_str());
+    }
+    if claimed.is_text() {
+        let is_utf16 = claimed
+            .parameter("charset")
+            .is_some_and(|charset| charset.to_lowercase().starts_with("utf-16"));
+        return detected != Some(TEXT_CONTENT_TYPE) && !is_utf16;
+    }
+    return false;
+}
+
+// Check the 'Content-Type' an upload was sent with against its first bytes, recording what was
+// detected in its metadata. A contradiction fails with `Unsupported` under `SniffPolicy::Reject`;
+// under `SniffPolicy::Correct` the detected type replaces the claimed one, as it does a generic
+// "application/octet-stream". An empty body contradicts nothing.
+pub fn check_content_type(
+    metadata: &mut UploadMetadata,
+    head: &[u8],
+    policy: SniffPolicy,
+) -> std::io::Result<()> {
+    let detected = sniff(head);
+    metadata.detected_type = detected.map(String::from);
+    if head.is_empty() {
+        return Ok(());
+    }
+    let claimed = match MediaType::parse(&metadata.content_type) {
+        Some(claimed) => claimed,
+        None => return Ok(()),
+    };
+    if claimed.essence() == GENERIC_CONTENT_TYPE {
+        if let Some(detected) = detected.filter(|detected| *detected != TEXT_CONTENT_TYPE) {
+            if policy == SniffPolicy::Correct {
+                metadata.content_type = String::from(detected);
+            }
+        }
+        return Ok(());
+    }
+    if !contradicts(&claimed, detected) {
+        return Ok(());
+    }
+    let (claimed, detected) = (claimed.essence(), detected.unwrap_or(GENERIC_CONTENT_TYPE));
+    match policy {
+        SniffPolicy::Reject => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("content claimed to be {} looks like {}", claimed, detected),
+            ));
+        }
+        SniffPolicy::Correct => {
+            println!("Correcting content type {} to {}", claimed, detected);
+            metadata.content_type = String::from(detected);
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn magic_numbers() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some("image/png"));
+        assert_eq!(sniff(b"\xff\xd8\xff\xe0\0\x10JFIF"), Some("image/jpeg"));
+        assert_eq!(sniff(b"GIF89a\x01\0"), Some("image/gif"));
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WAVEfmt "), Some("audio/wav"));
+        assert_eq!(sniff(b"\0\0\0\x18ftypmp42"), Some("video/mp4"));
+        assert_eq!(sniff(b"ID3\x04\0\0\0\0\0\x23"), Some("audio/mpeg"));
+        assert_eq!(sniff(b"\xff\xfb\x90\x64\0\0"), Some("audio/mpeg"));
+        assert_eq!(sniff(b"\xff\xf3\x48\xc4\0\0"), Some("audio/mpeg"));
+        assert_eq!(sniff(b"%PDF-1.7\n"), Some("application/pdf"));
+        assert_eq!(sniff(b"hello\r\n\tworld\x1b[0m"), Some("text/plain"));
+        assert_eq!(sniff("h\u{e9}llo".as_bytes()), Some("text/plain"));
+        assert_eq!(sniff(b"\xff\xfeh\0i\0"), Some("text/plain"));
+        assert_eq!(sniff(b"\x7fELF\x02\x01\x01\0"), None);
+        assert_eq!(sniff(b"RIFF\0\0\0\0AVI "), None);
+        assert_eq!(sniff(b""), None);
+    }
+
+    #[test]
+    pub fn content_type_policies() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let mut metadata = UploadMetadata::new("image/png");
+        check_content_type(&mut metadata, png, SniffPolicy::Reject).unwrap();
+        assert_eq!(metadata.content_type, "image/png");
+        assert_eq!(metadata.detected_type.as_deref(), Some("image/png"));
+
+        // Text claiming to be an image, and the other way round
+        let mut metadata = UploadMetadata::new("image/png");
+        let e = check_content_type(&mut metadata, b"not a picture", SniffPolicy::Reject);
+        assert_eq!(e.unwrap_err().kind(), std::io::ErrorKind::Unsupported);
+        check_content_type(&mut metadata, b"not a picture", SniffPolicy::Correct).unwrap();
+        assert_eq!(metadata.content_type, "text/plain");
+        let mut metadata = UploadMetadata::new("text/plain; charset=utf-8");
+        assert!(check_content_type(&mut metadata, png, SniffPolicy::Reject).is_err());
+        check_content_type(&mut metadata, b"\x7fELF\x02", SniffPolicy::Correct).unwrap();
+        assert_eq!(metadata.content_type, "application/octet-stream");
+        assert_eq!(metadata.detected_type, None);
+
+        // Text of any kind passes, so do types that can't be checked
+        let mut metadata = UploadMetadata::new("application/ld+json");
+        check_content_type(&mut metadata, b"{}", SniffPolicy::Reject).unwrap();
+        assert_eq!(metadata.content_type, "application/ld+json");
+        let mut metadata = UploadMetadata::new("text/plain; charset=utf-16le");
+        check_content_type(&mut metadata, b"h\0i\0", SniffPolicy::Reject).unwrap();
+        let docx = "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+        let mut metadata = UploadMetadata::new(docx);
+        check_content_type(&mut metadata, b"PK\x03\x04\x14\0", SniffPolicy::Reject).unwrap();
+        assert_eq!(metadata.content_type, docx);
+        assert_eq!(metadata.detected_type.as_deref(), Some("application/zip"));
+
+        // A generic type is only ever improved upon
+        let mut metadata = UploadMetadata::new("");
+        check_content_type(&mut metadata, png, SniffPolicy::Reject).unwrap();
+        assert_eq!(metadata.content_type, "application/octet-stream");
+        check_content_type(&mut metadata, png, SniffPolicy::Correct).unwrap();
+        assert_eq!(metadata.content_type, "image/png");
+        let mut metadata = UploadMetadata::new("");
+        check_content_type(&mut metadata, b"text", SniffPolicy::Correct).unwrap();
+        assert_eq!(metadata.content_type, "application/octet-stream");
+        assert_eq!(metadata.detected_type.as_deref(), Some("text/plain"));
+
+        // Nothing to contradict in an empty file
+        for content_type in ["text/plain", "image/png", ""] {
+            let mut metadata = UploadMetadata::new(content_type);
+            check_content_type(&mut metadata, b"", SniffPolicy::Reject).unwrap();
+            check_content_type(&mut metadata, b"", SniffPolicy::Correct).unwrap();
+            assert_eq!(
+                metadata.content_type,
+                UploadMetadata::new(content_type).content_type
+            );
+            assert_eq!(metadata.detected_type, None);
+        }
+    }
+}
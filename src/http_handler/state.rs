@@ -0,0 +1,461 @@
+use super::config::config;
+use super::files::from_address_to_path;
+use super::json::*;
+use super::response::ResponseStatus;
+use super::store::{sync_parent_dir, write_atomically};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// The key-value store of the JSON API: `POST /api/set` merges the members of the object it is
+// sent into the state, `GET /api/status` returns the whole state
+pub const STATUS_TARGET: &str = "/api/status";
+pub const SET_TARGET: &str = "/api/set";
+// Where the JSON documents of the API are, which can be queried with a JSON Pointer
+pub const DATA_DIR: &str = "data";
+// The largest JSON body accepted by `POST /api/set`
+pub const MAX_JSON_BODY_SIZE: u64 = 1024 * 1024;
+
+// The state is kept in two files: a snapshot, the file `GET /api/status` used to serve, and a log
+// of the updates made since, one compact JSON object per line. An update is appended to the log
+// before it is applied; every so many updates the state is written to a new snapshot, which
+// replaces the old one in one rename, and the log starts over. On startup the log is replayed
+// over the snapshot. Updates only ever set members, so replaying one that already made it into
+// the snapshot is harmless.
+pub const STATE_LOG_FILE: &str = "data/status.log";
+const SNAPSHOT_INTERVAL: usize = 1000;
+// How often the log is synced under `StateSync::Periodic`
+const STATE_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+// When the updates written to the log are flushed to the disk
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StateSync {
+    // Before the update is acknowledged: no acknowledged update is ever lost
+    Always,
+    // Once a second, from a thread of its own (see `start_state_sync`): a crash loses at most
+    // the updates of the last second
+    Periodic,
+    // When the operating system sees fit; snapshots are still synced
+    Never,
+}
+
+impl StateSync {
+    pub fn from_str(value: &str) -> Option<Self> {
+        match value {
+            "always" => return Some(Self::Always),
+            "periodic" => return Some(Self::Periodic),
+            "never" => return Some(Self::Never),
+            _ => return None,
+        }
+    }
+}
+
+fn invalid_state(message: String) -> std::io::Error {
+    return std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+}
+
+#[derive(Debug)]
+pub struct StateStore {
+    snapshot_path: PathBuf,
+    log_path: PathBuf,
+    sync: StateSync,
+    // How many updates are logged before a snapshot is taken
+    snapshot_interval: usize,
+    value: JsonValue,
+    // The updates logged since the last snapshot
+    records: usize,
+    // Opened with the first update, so that a server that only reads the state writes nothing
+    log: Option<std::fs::File>,
+    // Whether updates were logged since the log was last synced
+    unsynced: bool,
+}
+
+impl StateStore {
+    // Load the snapshot, an empty object if there is none, and replay the log over it. A last
+    // record cut short by a crash, without its newline, is dropped from the log. Fails with
+    // `InvalidData` if the snapshot isn't an object, or if a complete record is corrupt: the log
+    // is then left as it is, for the records after it to be recovered by hand.
+    pub fn open(snapshot_path: &Path, log_path: &Path, sync: StateSync) -> std::io::Result<Self> {
+        let value = match std::fs::read(snapshot_path) {
+            Ok(snapshot) => JsonValue::Object(parse_update(&snapshot).map_err(|e| {
+                invalid_state(format!(
+                    "{} at line {}, column {} of {}",
+                    e.message,
+                    e.line,
+                    e.column,
+                    snapshot_path.display()
+                ))
+            })?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => JsonValue::Object(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut ret_store = Self {
+            snapshot_path: snapshot_path.to_path_buf(),
+            log_path: log_path.to_path_buf(),
+            sync,
+            snapshot_interval: SNAPSHOT_INTERVAL,
+            value,
+            records: 0,
+            log: None,
+            unsynced: false,
+        };
+        ret_store.replay()?;
+        return Ok(ret_store);
+    }
+
+    pub fn configured() -> std::io::Result<Self> {
+        return Self::open(
+            Path::new(from_address_to_path(STATUS_TARGET)),
+            Path::new(STATE_LOG_FILE),
+            config().state_sync,
+        );
+    }
+
+    fn replay(&mut self) -> std::io::Result<()> {
+        let log = match std::fs::read(&self.log_path) {
+            Ok(log) => log,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut position = 0;
+        while let Some(end) = log[position..].iter().position(|byte| *byte == b'\n') {
+            let update = parse_update(&log[position..position + end]).map_err(|e| {
+                invalid_state(format!(
+                    "corrupt record at byte {} of {}: {}",
+                    position,
+                    self.log_path.display(),
+                    e.message
+                ))
+            })?;
+            self.apply(update);
+            self.records += 1;
+            position += end + 1;
+        }
+        if position < log.len() {
+            println!(
+                "Dropping {} bytes of an incomplete record from {}",
+                log.len() - position,
+                self.log_path.display()
+            );
+            let log_file = std::fs::File::options().write(true).open(&self.log_path)?;
+            log_file.set_len(position as u64)?;
+            log_file.sync_all()?;
+        }
+        return Ok(());
+    }
+
+    fn apply(&mut self, update: Vec<(String, JsonValue)>) {
+        for (name, value) in update {
+            self.value.set(&name, value);
+        }
+    }
+
+    pub fn value(&self) -> &JsonValue {
+        return &self.value;
+    }
+
+    // Log the update, then apply it: set the members of `update`, replacing those that exist and
+    // adding the others. The state is unchanged if the update couldn't be logged.
+    pub fn merge(&mut self, update: Vec<(String, JsonValue)>) -> std::io::Result<&JsonValue> {
+        let update = JsonValue::Object(update);
+        let record = format!("{}\n", update.to_json_string());
+        let log = match &mut self.log {
+            Some(log) => log,
+            None => {
+                if let Some(parent) = self.log_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let log = std::fs::File::options()
+                    .create(true)
+                    .append(true)
+                    .open(&self.log_path)?;
+                // A log just created is only found again once its directory entry is flushed
+                sync_parent_dir(&self.log_path)?;
+                self.log.insert(log)
+            }
+        };
+        // A record half written, followed by others, would make the log fail to replay. One that
+        // couldn't be synced isn't acknowledged, so it mustn't come back on replay either.
+        let length = log.metadata()?.len();
+        let result = log
+            .write_all(record.as_bytes())
+            .and_then(|_| match self.sync {
+                StateSync::Always => log.sync_data(),
+                StateSync::Periodic | StateSync::Never => Ok(()),
+            });
+        if let Err(e) = result {
+            let _ = log.set_len(length);
+            return Err(e);
+        }
+        self.unsynced = self.sync == StateSync::Periodic;
+        if let JsonValue::Object(members) = update {
+            self.apply(members);
+        }
+        self.records += 1;
+        // The update is safe in the log whether or not the snapshot can be written
+        if self.records >= self.snapshot_interval {
+            if let Err(e) = self.snapshot() {
+                println!("Failed to write the state snapshot: {}", e);
+            }
+        }
+        return Ok(&self.value);
+    }
+
+    // Flush the updates logged since the last sync to the disk
+    pub fn sync(&mut self) -> std::io::Result<()> {
+        if let Some(log) = self.log.as_ref().filter(|_| self.unsynced) {
+            log.sync_data()?;
+            self.unsynced = false;
+        }
+        return Ok(());
+    }
+
+    // Replace the whole state, e.g. with a patched version. The log can only tell which members
+    // were set, so the new state goes straight to a snapshot; it is kept only if that succeeds.
+    pub fn replace(&mut self, value: JsonValue) -> std::io::Result<&JsonValue> {
+        // Replayed over the new snapshot after a crash, the log could bring back members the
+        // change removed: it is first emptied into a snapshot of the current state
+        if self.records > 0 {
+            self.snapshot()?;
+        }
+        let previous = std::mem::replace(&mut self.value, value);
+        if let Err(e) = self.snapshot() {
+            self.value = previous;
+            return Err(e);
+        }
+        return Ok(&self.value);
+    }
+
+    // Write the state to a new snapshot, and empty the log it makes redundant. The snapshot is
+    // on the disk before the log is emptied, or a crash could bring back the old one without the
+    // records acknowledged since.
+    pub fn snapshot(&mut self) -> std::io::Result<()> {
+        let snapshot = format!("{}\n", self.value.to_json_string());
+        write_atomically(
+            &self.snapshot_path,
+            &mut snapshot.as_bytes(),
+            Some(snapshot.len() as u64),
+        )?;
+        match &self.log {
+            Some(log) => {
+                log.set_len(0)?;
+                log.sync_all()?;
+                self.unsynced = false;
+            }
+            // Records replayed on startup, not appended to since
+            None if self.records > 0 => {
+                std::fs::remove_file(&self.log_path)?;
+                sync_parent_dir(&self.log_path)?;
+            }
+            None => {}
+        }
+        self.records = 0;
+        return Ok(());
+    }
+}
+
+// The server's state, loaded on first use
+static STATE: Mutex<Option<StateStore>> = Mutex::new(None);
+
+fn with_state<T>(action: impl FnOnce(&mut StateStore) -> std::io::Result<T>) -> std::io::Result<T> {
+    let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+    if state.is_none() {
+        *state = Some(StateStore::configured()?);
+    }
+    return action(state.as_mut().unwrap());
+}
+
+// Load the state, replaying the log: done before the server takes requests, so that a broken
+// snapshot is noticed at once. Returns how many logged updates were replayed.
+pub fn load_state() -> std::io::Result<usize> {
+    return with_state(|state| Ok(state.records));
+}
+
+// Sync the log every `STATE_SYNC_INTERVAL` in a thread of its own, if the server is set to sync
+// it periodically
+pub fn start_state_sync() -> std::io::Result<()> {
+    if config().state_sync != StateSync::Periodic {
+        return Ok(());
+    }
+    std::thread::Builder::new()
+        .name(String::from("state-sync"))
+        .spawn(|| loop {
+            std::thread::sleep(STATE_SYNC_INTERVAL);
+            if let Err(e) = with_state(|state| state.sync()) {
+                println!("Failed to sync the state log: {}", e);
+            }
+        })?;
+    return Ok(());
+}
+
+pub fn current_state() -> std::io::Result<JsonValue> {
+    return with_state(|state| Ok(state.value().clone()));
+}
+
+// Merge an update into the state. Returns the state with the update.
+pub fn merge_state(update: Vec<(String, JsonValue)>) -> std::io::Result<JsonValue> {
+    return with_state(|state| state.merge(update).cloned());
+}
+
+// Replace the state with what `change` makes of it. Returns the new state.
+pub fn replace_state(
+    change: impl FnOnce(&JsonValue) -> Result<JsonValue, ResponseStatus>,
+) -> Result<JsonValue, ResponseStatus> {
+    let result = with_state(|state| match change(state.value()) {
+        Ok(changed) => return state.replace(changed).cloned().map(Ok),
+        Err(status) => return Ok(Err(status)),
+    });
+    match result {
+        Ok(result) => return result,
+        Err(e) => {
+            println!("Failed to replace the state: {}", e);
+            return Err(ResponseStatus::InternalServerError);
+        }
+    }
+}
+
+// The members of a body sent to `POST /api/set`, which must be a JSON object in UTF-8
+pub fn parse_update(body: &[u8]) -> Result<Vec<(String, JsonValue)>, JsonError> {
+    match JsonValue::from_utf8(body)? {
+        JsonValue::Object(members) => return Ok(members),
+        _ => {
+            // A valid JSON text is valid UTF-8
+            let text = std::str::from_utf8(body).unwrap_or_default();
+            let offset = text.len() - text.trim_start().len();
+            return Err(JsonError::at(text, offset, "expected an object"));
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn update(text: &str) -> Vec<(String, JsonValue)> {
+        return parse_update(text.as_bytes()).unwrap();
+    }
+
+    #[test]
+    pub fn state_updates() {
+        let e = parse_update(b"\n  [1, 2]").unwrap_err();
+        assert_eq!((e.offset, e.line, e.column), (3, 2, 3));
+        let e = parse_update(b"{\"a\": \"\xff\"}").unwrap_err();
+        assert_eq!((e.message.as_str(), e.offset), ("invalid UTF-8", 7));
+        let e = parse_update(b"{\"a\": 1,}").unwrap_err();
+        assert_eq!((e.offset, e.line, e.column), (8, 1, 9));
+
+        let dir =
+            std::env::temp_dir().join(format!("http-server-rust-state-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (snapshot_path, log_path) = (dir.join("status.json"), dir.join("status.log"));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&snapshot_path, "{\n    \"test_status\": 1\n}\n").unwrap();
+        let mut state = StateStore::open(&snapshot_path, &log_path, StateSync::Always).unwrap();
+        assert!(!log_path.exists());
+        state.snapshot_interval = 3;
+        state
+            .merge(update("{\"test_status\": 2, \"name\": \"a\"}"))
+            .unwrap();
+        let value = state.merge(update("{\"name\": null}")).unwrap();
+        assert_eq!(value.to_json_string(), "{\"test_status\":2,\"name\":null}");
+        let log = std::fs::read_to_string(&log_path).unwrap();
+        assert_eq!(log.lines().count(), 2);
+        drop(state);
+        let state = StateStore::open(&snapshot_path, &log_path, StateSync::Never).unwrap();
+        assert_eq!(
+            state.value().to_json_string(),
+            "{\"test_status\":2,\"name\":null}"
+        );
+
+        // The third update triggers a snapshot, which the log starts over from
+        let mut state = StateStore::open(&snapshot_path, &log_path, StateSync::Always).unwrap();
+        state.snapshot_interval = 3;
+        state.merge(update("{\"on\": true}")).unwrap();
+        assert_eq!(std::fs::metadata(&log_path).unwrap().len(), 0);
+        assert_eq!(
+            std::fs::read_to_string(&snapshot_path).unwrap(),
+            "{\"test_status\":2,\"name\":null,\"on\":true}\n"
+        );
+        state.merge(update("{\"on\": false}")).unwrap();
+        drop(state);
+
+        // A replaced state is written to a snapshot at once, and the log replayed on startup
+        // doesn't bring back what it removed
+        let mut state = StateStore::open(&snapshot_path, &log_path, StateSync::Always).unwrap();
+        assert_eq!(state.records, 1);
+        let replaced = JsonValue::parse("{\"test_status\":3}").unwrap();
+        state.replace(replaced).unwrap();
+        assert!(!log_path.exists());
+        state.merge(update("{\"on\": true}")).unwrap();
+        drop(state);
+        let state = StateStore::open(&snapshot_path, &log_path, StateSync::Always).unwrap();
+        assert_eq!(
+            state.value().to_json_string(),
+            "{\"test_status\":3,\"on\":true}"
+        );
+
+        // A snapshot that isn't an object is refused rather than replaced
+        std::fs::write(&snapshot_path, "[]").unwrap();
+        let e = StateStore::open(&snapshot_path, &log_path, StateSync::Always).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    pub fn state_log_recovery() {
+        let dir = std::env::temp_dir().join(format!(
+            "http-server-rust-state-recovery-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (snapshot_path, log_path) = (dir.join("status.json"), dir.join("status.log"));
+        let mut state = StateStore::open(&snapshot_path, &log_path, StateSync::Always).unwrap();
+        for i in 0..3 {
+            state
+                .merge(update(&format!("{{\"count\": {}, \"k{}\": \"v\"}}", i, i)))
+                .unwrap();
+        }
+        drop(state);
+        let log = std::fs::read(&log_path).unwrap();
+        let complete = log.len() - "{\"count\":2,\"k2\":\"v\"}\n".len();
+
+        // A crash in the middle of the last record: it is dropped, the others are replayed
+        for cut in [1, 8, 20] {
+            std::fs::write(&log_path, &log[..complete + cut]).unwrap();
+            let state = StateStore::open(&snapshot_path, &log_path, StateSync::Always).unwrap();
+            assert_eq!(
+                state.value().to_json_string(),
+                "{\"count\":1,\"k0\":\"v\",\"k1\":\"v\"}"
+            );
+            assert_eq!(state.records, 2);
+            assert_eq!(std::fs::metadata(&log_path).unwrap().len(), complete as u64);
+        }
+
+        // Updates logged after the recovery follow the last complete record
+        std::fs::write(&log_path, &log[..complete + 8]).unwrap();
+        let mut state = StateStore::open(&snapshot_path, &log_path, StateSync::Periodic).unwrap();
+        state.merge(update("{\"count\": 3}")).unwrap();
+        // Left to the timer, even when no update follows
+        assert!(state.unsynced);
+        state.sync().unwrap();
+        assert!(!state.unsynced);
+        drop(state);
+        let state = StateStore::open(&snapshot_path, &log_path, StateSync::Always).unwrap();
+        let expected = "{\"count\":3,\"k0\":\"v\",\"k1\":\"v\"}";
+        assert_eq!(state.value().to_json_string(), expected);
+
+        // A crash between a snapshot and the emptying of the log replays updates already in it
+        std::fs::write(&snapshot_path, format!("{}\n", expected)).unwrap();
+        let state = StateStore::open(&snapshot_path, &log_path, StateSync::Always).unwrap();
+        assert_eq!(state.value().to_json_string(), expected);
+
+        // A corrupt record isn't a crash: the state isn't loaded, and the log is kept whole
+        let log = "{\"count\": 4}\n{\"count\" 5}\n{\"count\": 6}\n";
+        std::fs::write(&log_path, log).unwrap();
+        let e = StateStore::open(&snapshot_path, &log_path, StateSync::Always).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read_to_string(&log_path).unwrap(), log);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
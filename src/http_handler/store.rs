@@ -0,0 +1,170 @@
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+// Named files managed through PUT/GET/DELETE on `FILES_PREFIX<name>`
+pub const FILES_DIR: &str = "artifacts/files";
+pub const FILES_PREFIX: &str = "/files/";
+const MAX_FILE_NAME_LENGTH: usize = 255;
+const BUFF_COPY_SIZE: usize = 32768;
+
+// Only plain names are accepted: no separators, no hidden files (temporary files are hidden),
+// nothing that could escape the store's directory
+pub fn is_valid_file_name(name: &str) -> bool {
+    return !name.is_empty()
+        && name.len() <= MAX_FILE_NAME_LENGTH
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-');
+}
+
+// The name addressed by a request target, e.g. "/files/report.txt" -> "report.txt"
+pub fn file_name_from_target(target: &str) -> Option<&str> {
+    return target.strip_prefix(FILES_PREFIX);
+}
+
+// A hidden file next to `path`, unique to this process and moment, to write into before renaming
+pub fn temp_path(path: &Path) -> PathBuf {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    return path.with_file_name(format!(
+        ".{}.{}-{}.tmp",
+        file_name,
+        std::process::id(),
+        nanos
+    ));
+}
+
+// Copy exactly `length` bytes from `reader` to `writer`. A connection closed early is an error.
+pub fn copy_body(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    length: u64,
+) -> std::io::Result<()> {
+    let mut buffer: [u8; BUFF_COPY_SIZE] = [0; BUFF_COPY_SIZE];
+    let mut remaining = length;
+    while remaining > 0 {
+        let chunk_size = remaining.min(BUFF_COPY_SIZE as u64) as usize;
+        let bytes_read = reader.read(&mut buffer[0..chunk_size])?;
+        if bytes_read == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("body ended {} bytes early", remaining),
+            ));
+        }
+        writer.write_all(&buffer[0..bytes_read])?;
+        remaining -= bytes_read as u64;
+    }
+    return Ok(());
+}
+
+// Flush the entries of the directory that holds `path`, e.g. after a rename: until then, the
+// change may not survive a crash even though the file's data did
+pub fn sync_parent_dir(path: &Path) -> std::io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    return std::fs::File::open(parent)?.sync_all();
+}
+
+// Write `length` bytes from `reader` to `path`, or all of it if the length isn't known, so that
+// readers only ever see the old content or the complete new one: the data goes to a temporary
+// file which is then renamed over `path`. Once this returns, the new content survives a crash.
+pub fn write_atomically(
+    path: &Path,
+    reader: &mut impl Read,
+    length: Option<u64>,
+) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let temp_path = temp_path(path);
+    let result = std::fs::File::create(&temp_path).and_then(|mut temp_file| {
+        match length {
+            Some(length) => copy_body(reader, &mut temp_file, length)?,
+            None => {
+                std::io::copy(reader, &mut temp_file)?;
+            }
+        }
+        temp_file.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
+        sync_parent_dir(path)
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    return result;
+}
+
+// Store a named file in `dir`. Returns whether the file was created (as opposed to replaced).
+pub fn store_file(
+    dir: &Path,
+    name: &str,
+    reader: &mut impl Read,
+    length: Option<u64>,
+) -> std::io::Result<bool> {
+    let path = dir.join(name);
+    let created = !path.exists();
+    write_atomically(&path, reader, length)?;
+    return Ok(created);
+}
+
+// Remove a named file from `dir`. Returns whether there was a file to remove.
+pub fn delete_file(dir: &Path, name: &str) -> std::io::Result<bool> {
+    match std::fs::remove_file(dir.join(name)) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn file_names() {
+        assert!(is_valid_file_name("report-2024_01.tar.gz"));
+        assert!(!is_valid_file_name(""));
+        assert!(!is_valid_file_name(".hidden"));
+        assert!(!is_valid_file_name(".."));
+        assert!(!is_valid_file_name("../etc/passwd"));
+        assert!(!is_valid_file_name("a/b"));
+        assert!(!is_valid_file_name("a%2Fb"));
+        assert!(!is_valid_file_name(&"a".repeat(MAX_FILE_NAME_LENGTH + 1)));
+        assert_eq!(file_name_from_target("/files/a.txt"), Some("a.txt"));
+        assert_eq!(file_name_from_target("/index.html"), None);
+    }
+
+    #[test]
+    pub fn put_and_delete() {
+        let dir =
+            std::env::temp_dir().join(format!("http-server-rust-store-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let created = store_file(&dir, "a.txt", &mut "hello world".as_bytes(), Some(5)).unwrap();
+        assert!(created);
+        assert_eq!(std::fs::read_to_string(dir.join("a.txt")).unwrap(), "hello");
+        let created = store_file(&dir, "a.txt", &mut "bye".as_bytes(), None).unwrap();
+        assert!(!created);
+        assert_eq!(std::fs::read_to_string(dir.join("a.txt")).unwrap(), "bye");
+
+        // A truncated body leaves the previous content untouched and no temporary file behind
+        assert!(store_file(&dir, "a.txt", &mut "short".as_bytes(), Some(10)).is_err());
+        assert_eq!(std::fs::read_to_string(dir.join("a.txt")).unwrap(), "bye");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        assert!(delete_file(&dir, "a.txt").unwrap());
+        assert!(!delete_file(&dir, "a.txt").unwrap());
+        // A bare file name is in the current directory
+        assert!(sync_parent_dir(&dir.join("a.txt")).is_ok());
+        assert!(sync_parent_dir(Path::new("Cargo.toml")).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
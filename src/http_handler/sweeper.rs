@@ -0,0 +1,148 @@
+use super::date::unix_seconds;
+use super::uploads::*;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+// A partial upload nobody wrote to for that long was left behind by a connection that broke off
+// (or a server that stopped) before the end of the body
+pub const ABANDONED_UPLOAD_AGE: Duration = Duration::from_secs(3600);
+
+// The uploads removed by a sweep
+#[derive(Debug, Default, PartialEq)]
+pub struct SweepReport {
+    pub expired: Vec<String>,
+    pub abandoned: Vec<String>,
+}
+
+impl SweepReport {
+    pub fn is_empty(&self) -> bool {
+        return self.expired.is_empty() && self.abandoned.is_empty();
+    }
+}
+
+// The last time anything in an upload's directory changed
+fn last_modified(upload_dir: &Path) -> std::io::Result<SystemTime> {
+    let mut ret_modified = std::fs::metadata(upload_dir)?.modified()?;
+    for entry in std::fs::read_dir(upload_dir)? {
+        let modified = entry?.metadata()?.modified()?;
+        ret_modified = ret_modified.max(modified);
+    }
+    return Ok(ret_modified);
+}
+
+// Remove the uploads expired at `now`, and the partial uploads untouched for `abandoned_after`.
+// An upload that can't be removed is reported and left for the next sweep.
+pub fn sweep(
+    store: &UploadStore,
+    now: SystemTime,
+    abandoned_after: Duration,
+) -> std::io::Result<SweepReport> {
+    let mut ret_report = SweepReport::default();
+    let entries = match std::fs::read_dir(&store.dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ret_report),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let id = match entry?.file_name().into_string() {
+            Ok(id) if is_valid_upload_id(&id) => id,
+            _ => continue,
+        };
+        let upload_dir = store.dir.join(&id);
+        let removed = match UploadMetadata::read(&upload_dir) {
+            Ok(metadata) if metadata.is_expired(unix_seconds(now)) => &mut ret_report.expired,
+            Ok(_) => continue,
+            // Uploads get their metadata once complete
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                match last_modified(&upload_dir) {
+                    Ok(modified)
+                        if now.duration_since(modified).unwrap_or_default() >= abandoned_after =>
+                    {
+                        &mut ret_report.abandoned
+                    }
+                    _ => continue,
+                }
+            }
+            Err(e) => {
+                println!("Failed to read upload {}: {}", id, e);
+                continue;
+            }
+        };
+        match store.remove(&id) {
+            Ok(()) => removed.push(id),
+            Err(e) => println!("Failed to remove upload {}: {}", id, e),
+        }
+    }
+    return Ok(ret_report);
+}
+
+// Sweep the store every `interval` in a thread of its own, for as long as the server runs
+pub fn start_sweeper(store: UploadStore, interval: Duration) -> std::io::Result<()> {
+    std::thread::Builder::new()
+        .name(String::from("upload-sweeper"))
+        .spawn(move || loop {
+            std::thread::sleep(interval);
+            match sweep(&store, SystemTime::now(), ABANDONED_UPLOAD_AGE) {
+                Ok(report) if !report.is_empty() => println!(
+                    "Swept {} expired upload(s) {:?} and {} abandoned upload(s) {:?}",
+                    report.expired.len(),
+                    report.expired,
+                    report.abandoned.len(),
+                    report.abandoned
+                ),
+                Ok(_) => {}
+                Err(e) => println!("Failed to sweep uploads: {}", e),
+            }
+        })?;
+    return Ok(());
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::http_handler::blobs::{blob_path, blob_references};
+    use crate::http_handler::uploads::test::TempUploadStore;
+
+    #[test]
+    pub fn sweep_uploads() {
+        use std::io::Write;
+        let mut store = TempUploadStore::new("sweeper", true, Some(60));
+        let now = SystemTime::now();
+        assert_eq!(
+            sweep(&store, now, ABANDONED_UPLOAD_AGE).unwrap(),
+            SweepReport::default()
+        );
+
+        let mut ids: Vec<String> = Vec::new();
+        for ttl in [Some(60), None, Some(3600)] {
+            store.ttl = ttl;
+            let mut writer = UploadWriter::create(&store).unwrap();
+            writer.write_all(b"hello").unwrap();
+            ids.push(writer.finish(&mut UploadMetadata::new("")).unwrap());
+        }
+        let mut partial = UploadWriter::create(&store).unwrap();
+        partial.write_all(b"hel").unwrap();
+
+        // Nothing is due yet, the partial upload is still being written
+        let report = sweep(&store, now, ABANDONED_UPLOAD_AGE).unwrap();
+        assert!(report.is_empty());
+
+        let hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let report = sweep(&store, now + Duration::from_secs(120), ABANDONED_UPLOAD_AGE).unwrap();
+        assert_eq!(report.expired, vec![ids[0].clone()]);
+        assert!(report.abandoned.is_empty());
+        assert!(!store.dir.join(&ids[0]).exists());
+        assert_eq!(blob_references(&store.blobs_dir, hash).unwrap(), 2);
+
+        let later = now + ABANDONED_UPLOAD_AGE + Duration::from_secs(120);
+        let report = sweep(&store, later, ABANDONED_UPLOAD_AGE).unwrap();
+        assert_eq!(report.expired, vec![ids[2].clone()]);
+        assert_eq!(report.abandoned, vec![partial.id.clone()]);
+        assert!(!store.dir.join(&partial.id).exists());
+        assert!(store.dir.join(&ids[1]).exists());
+        assert_eq!(blob_references(&store.blobs_dir, hash).unwrap(), 1);
+
+        store.remove(&ids[1]).unwrap();
+        assert!(!blob_path(&store.blobs_dir, hash).exists());
+    }
+}
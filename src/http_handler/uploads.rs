@@ -0,0 +1,666 @@
+use super::blobs::*;
+use super::config::config;
+use super::date::unix_seconds;
+use super::digest::to_hex;
+use super::sniff::SNIFF_LENGTH;
+use super::store::temp_path;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// Every upload lives in its own directory, `UPLOADS_DIR/<id>`, holding its metadata and, unless
+// stored as a blob, its data
+pub const UPLOADS_DIR: &str = "artifacts/uploads";
+pub const UPLOADS_TARGET: &str = "/api/uploads";
+pub const UPLOADS_PREFIX: &str = "/api/uploads/";
+pub const UPLOADS_PROGRESS_TARGET: &str = "/api/uploads/progress";
+const PROGRESS_SUFFIX: &str = "/progress";
+pub const UPLOAD_DATA_FILE: &str = "data";
+pub const UPLOAD_METADATA_FILE: &str = "meta";
+// The data received so far by a resumable upload
+pub const UPLOAD_PARTIAL_FILE: &str = "partial";
+const UPLOAD_ID_LENGTH: usize = 24;
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+const MAX_FILE_NAME_LENGTH: usize = 255;
+
+static UPLOAD_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+// A new identifier: the current time in nanoseconds followed by a per-process counter, so that
+// two uploads in the same nanosecond still get different IDs
+pub fn new_upload_id() -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let counter = UPLOAD_COUNTER.fetch_add(1, Ordering::Relaxed);
+    return format!("{:016x}{:08x}", nanos, counter);
+}
+
+pub fn is_valid_upload_id(id: &str) -> bool {
+    return id.len() == UPLOAD_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c));
+}
+
+// The ID addressed by a request target, e.g. "/api/uploads/<id>" -> "<id>"
+pub fn upload_id_from_target(target: &str) -> Option<&str> {
+    return target.strip_prefix(UPLOADS_PREFIX);
+}
+
+// The ID whose progress a request target asks for, e.g. "/api/uploads/<id>/progress" -> "<id>"
+pub fn progress_id_from_target(target: &str) -> Option<&str> {
+    return target
+        .strip_prefix(UPLOADS_PREFIX)?
+        .strip_suffix(PROGRESS_SUFFIX);
+}
+
+// Create the directory of a new upload in `dir`. Creating a directory fails if it already exists,
+// which makes the reservation of the ID atomic: an existing upload is never clobbered.
+pub fn reserve_upload(dir: &Path) -> std::io::Result<(String, PathBuf)> {
+    std::fs::create_dir_all(dir)?;
+    loop {
+        let id = new_upload_id();
+        let upload_dir = dir.join(&id);
+        match std::fs::create_dir(&upload_dir) {
+            Ok(()) => return Ok((id, upload_dir)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Reduce a client supplied file name to something safe to store and display: directories are
+// stripped and anything but letters, digits, spaces, dots, dashes and underscores is replaced
+pub fn sanitize_file_name(file_name: &str) -> String {
+    let base_name = file_name.rsplit(['/', '\\']).next().unwrap_or("");
+    let sanitized = base_name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == ' ' || c == '.' || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(MAX_FILE_NAME_LENGTH)
+        .collect::<String>();
+    let sanitized = sanitized.trim_start_matches(['.', ' ']).trim_end();
+    if sanitized.is_empty() {
+        return String::from("upload");
+    }
+    return String::from(sanitized);
+}
+
+// The 'Content-Disposition' of an upload downloaded under its sanitized name: the name in UTF-8,
+// percent-encoded, with an ASCII fallback for clients that don't read 'filename*' (RFC 6266)
+pub fn content_disposition(file_name: &str) -> String {
+    let fallback = file_name
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect::<String>();
+    let mut encoded = String::new();
+    for byte in file_name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    return format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    );
+}
+
+// What is known about a stored upload, saved next to its data as "name: value" lines
+#[derive(Debug, PartialEq)]
+pub struct UploadMetadata {
+    pub content_type: String,
+    pub size: u64,
+    // The name of the file on the client's side, if it was sent through a form
+    pub filename: String,
+    // The SHA-256 digest of the data, in hex
+    pub sha256: String,
+    // Whether the data is the blob named after `sha256` rather than a file of its own
+    pub in_blob_store: bool,
+    // When the upload completed, in seconds since the Unix epoch
+    pub uploaded_at: u64,
+    // The IP address of the client who sent it
+    pub client_address: String,
+    // When the upload may be deleted, in seconds since the Unix epoch, if ever
+    pub expires_at: Option<u64>,
+    // What the data looks like, whatever the client said, if it could be told
+    pub detected_type: Option<String>,
+}
+
+impl UploadMetadata {
+    pub fn new(content_type: &str) -> Self {
+        return Self {
+            content_type: String::from(if content_type.is_empty() {
+                DEFAULT_CONTENT_TYPE
+            } else {
+                content_type
+            }),
+            size: 0,
+            filename: String::new(),
+            sha256: String::new(),
+            in_blob_store: false,
+            uploaded_at: 0,
+            client_address: String::new(),
+            expires_at: None,
+            detected_type: None,
+        };
+    }
+
+    // The metadata file. Fails with `InvalidData` if a value has a line break, which would add
+    // lines of its own to the file, e.g. a bare CR left in a header by the client.
+    pub fn to_text(&self) -> std::io::Result<String> {
+        for value in [
+            &self.content_type,
+            &self.filename,
+            &self.sha256,
+            &self.client_address,
+            self.detected_type.as_deref().unwrap_or(""),
+        ] {
+            if value.contains(['\r', '\n']) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("line break in upload metadata {:?}", value),
+                ));
+            }
+        }
+        return Ok(format!(
+            "content-type: {}\nsize: {}\nfilename: {}\nsha-256: {}\nstorage: {}\n\
+             uploaded-at: {}\nclient-address: {}\nexpires-at: {}\ndetected-type: {}\n",
+            self.content_type,
+            self.size,
+            self.filename,
+            self.sha256,
+            if self.in_blob_store { "blob" } else { "file" },
+            self.uploaded_at,
+            self.client_address,
+            self.expires_at.map(|t| t.to_string()).unwrap_or_default(),
+            self.detected_type.as_deref().unwrap_or("")
+        ));
+    }
+
+    pub fn parse(text: &str) -> Self {
+        let mut ret_metadata = Self::new("");
+        for line in text.lines() {
+            if let Some((name, value)) = line.split_once(':') {
+                let value = value.trim();
+                match name.trim() {
+                    "content-type" if !value.is_empty() => {
+                        ret_metadata.content_type = String::from(value)
+                    }
+                    "size" => ret_metadata.size = value.parse::<u64>().unwrap_or(0),
+                    "filename" => ret_metadata.filename = String::from(value),
+                    "sha-256" => ret_metadata.sha256 = String::from(value),
+                    "storage" => ret_metadata.in_blob_store = value == "blob",
+                    "uploaded-at" => ret_metadata.uploaded_at = value.parse::<u64>().unwrap_or(0),
+                    "client-address" => ret_metadata.client_address = String::from(value),
+                    "expires-at" => ret_metadata.expires_at = value.parse::<u64>().ok(),
+                    "detected-type" if !value.is_empty() => {
+                        ret_metadata.detected_type = Some(String::from(value))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        return ret_metadata;
+    }
+
+    // Whether the upload is past its expiry at `now` (in seconds since the Unix epoch): it is no
+    // longer served and waits to be swept
+    pub fn is_expired(&self, now: u64) -> bool {
+        return self.expires_at.is_some_and(|expires_at| expires_at <= now);
+    }
+
+    pub fn read(upload_dir: &Path) -> std::io::Result<Self> {
+        let text = std::fs::read_to_string(upload_dir.join(UPLOAD_METADATA_FILE))?;
+        return Ok(Self::parse(&text));
+    }
+
+    pub fn write(&self, upload_dir: &Path) -> std::io::Result<()> {
+        return std::fs::write(upload_dir.join(UPLOAD_METADATA_FILE), self.to_text()?);
+    }
+}
+
+// Where uploads are kept. With content-addressed storage, the data of new uploads goes to the
+// blob store, where identical uploads share it. New uploads expire `ttl` seconds after they
+// complete, if set.
+#[derive(Debug, Clone)]
+pub struct UploadStore {
+    pub dir: PathBuf,
+    pub blobs_dir: PathBuf,
+    pub content_addressed: bool,
+    pub ttl: Option<u64>,
+}
+
+impl UploadStore {
+    pub fn configured() -> Self {
+        return Self {
+            dir: PathBuf::from(UPLOADS_DIR),
+            blobs_dir: PathBuf::from(BLOBS_DIR),
+            content_addressed: config().content_addressed_uploads,
+            ttl: config().upload_ttl,
+        };
+    }
+
+    // Where the data of an upload is, whichever way it was stored
+    pub fn data_path(&self, id: &str) -> PathBuf {
+        let upload_dir = self.dir.join(id);
+        if let Ok(metadata) = UploadMetadata::read(&upload_dir) {
+            if metadata.in_blob_store && is_valid_blob_hash(&metadata.sha256) {
+                return blob_path(&self.blobs_dir, &metadata.sha256);
+            }
+        }
+        return upload_dir.join(UPLOAD_DATA_FILE);
+    }
+
+    // Whether an upload exists and hasn't expired at `now`
+    pub fn is_available(&self, id: &str, now: u64) -> bool {
+        return UploadMetadata::read(&self.dir.join(id)).is_ok_and(|m| !m.is_expired(now));
+    }
+
+    // The complete uploads, oldest first (IDs start with the time they were created at). Uploads
+    // still being received have no metadata yet and are left out, expired ones are included.
+    pub fn list(&self) -> std::io::Result<Vec<(String, UploadMetadata)>> {
+        let mut ret_uploads: Vec<(String, UploadMetadata)> = Vec::new();
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ret_uploads),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let id = match entry?.file_name().into_string() {
+                Ok(id) if is_valid_upload_id(&id) => id,
+                _ => continue,
+            };
+            if let Ok(metadata) = UploadMetadata::read(&self.dir.join(&id)) {
+                ret_uploads.push((id, metadata));
+            }
+        }
+        ret_uploads.sort_by(|a, b| a.0.cmp(&b.0));
+        return Ok(ret_uploads);
+    }
+
+    // Delete an upload, releasing its blob if it has one
+    pub fn remove(&self, id: &str) -> std::io::Result<()> {
+        let upload_dir = self.dir.join(id);
+        if let Ok(metadata) = UploadMetadata::read(&upload_dir) {
+            if metadata.in_blob_store && is_valid_blob_hash(&metadata.sha256) {
+                release_blob(&self.blobs_dir, &metadata.sha256)?;
+            }
+        }
+        return std::fs::remove_dir_all(upload_dir);
+    }
+}
+
+// A new upload being written: the data goes to a temporary file in the upload's directory and
+// only gets its final name, along with the metadata, once complete. Its digest is computed on
+// the way, and its first bytes are kept to tell what it is.
+pub struct UploadWriter {
+    pub id: String,
+    pub size: u64,
+    upload_dir: PathBuf,
+    temp_path: PathBuf,
+    file: std::fs::File,
+    sha256: Sha256,
+    head: Vec<u8>,
+    // Set for content-addressed storage
+    blobs_dir: Option<PathBuf>,
+    ttl: Option<u64>,
+}
+
+impl UploadWriter {
+    pub fn create(store: &UploadStore) -> std::io::Result<Self> {
+        let (id, upload_dir) = reserve_upload(&store.dir)?;
+        let temp_path = temp_path(&upload_dir.join(UPLOAD_DATA_FILE));
+        match std::fs::File::create(&temp_path) {
+            Ok(file) => {
+                return Ok(Self {
+                    id,
+                    size: 0,
+                    upload_dir,
+                    temp_path,
+                    file,
+                    sha256: Sha256::new(),
+                    head: Vec::new(),
+                    blobs_dir: if store.content_addressed {
+                        Some(store.blobs_dir.clone())
+                    } else {
+                        None
+                    },
+                    ttl: store.ttl,
+                });
+            }
+            Err(e) => {
+                let _ = std::fs::remove_dir_all(&upload_dir);
+                return Err(e);
+            }
+        }
+    }
+
+    // Take over the data received so far by a resumable upload, to finish it: the digest of what
+    // is already there is computed first
+    pub fn from_partial(store: &UploadStore, id: &str) -> std::io::Result<Self> {
+        use std::io::Read;
+        let upload_dir = store.dir.join(id);
+        let temp_path = upload_dir.join(UPLOAD_PARTIAL_FILE);
+        let mut file = std::fs::File::options()
+            .read(true)
+            .append(true)
+            .open(&temp_path)?;
+        let mut head: Vec<u8> = Vec::new();
+        (&mut file)
+            .take(SNIFF_LENGTH as u64)
+            .read_to_end(&mut head)?;
+        let mut sha256 = Sha256::new();
+        sha256.update(&head);
+        let size = head.len() as u64 + std::io::copy(&mut file, &mut sha256)?;
+        return Ok(Self {
+            id: String::from(id),
+            size,
+            upload_dir,
+            temp_path,
+            file,
+            sha256,
+            head,
+            blobs_dir: if store.content_addressed {
+                Some(store.blobs_dir.clone())
+            } else {
+                None
+            },
+            ttl: store.ttl,
+        });
+    }
+
+    // The first bytes of the data, up to `SNIFF_LENGTH`
+    pub fn head(&self) -> &[u8] {
+        return &self.head;
+    }
+
+    // Move the data in place and record its metadata. Returns the upload's ID.
+    pub fn finish(self, metadata: &mut UploadMetadata) -> std::io::Result<String> {
+        let result = self.file.sync_all().and_then(|_| {
+            metadata.size = self.size;
+            metadata.sha256 = to_hex(&self.sha256.clone().finalize());
+            metadata.uploaded_at = unix_seconds(std::time::SystemTime::now());
+            metadata.expires_at = self.ttl.map(|ttl| metadata.uploaded_at.saturating_add(ttl));
+            match &self.blobs_dir {
+                Some(blobs_dir) => {
+                    if !add_blob(blobs_dir, &self.temp_path, &metadata.sha256)? {
+                        println!("Upload {} duplicates blob {}", self.id, metadata.sha256);
+                    }
+                    metadata.in_blob_store = true;
+                }
+                None => std::fs::rename(&self.temp_path, self.upload_dir.join(UPLOAD_DATA_FILE))?,
+            }
+            metadata.write(&self.upload_dir)
+        });
+        match result {
+            Ok(()) => return Ok(self.id),
+            Err(e) => {
+                if let Some(blobs_dir) = self.blobs_dir.as_ref().filter(|_| metadata.in_blob_store)
+                {
+                    let _ = release_blob(blobs_dir, &metadata.sha256);
+                }
+                let _ = std::fs::remove_dir_all(&self.upload_dir);
+                return Err(e);
+            }
+        }
+    }
+
+    // Give up on the upload, leaving nothing behind
+    pub fn abort(self) {
+        let _ = std::fs::remove_dir_all(&self.upload_dir);
+    }
+}
+
+impl std::io::Write for UploadWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.sha256.update(&buf[0..written]);
+        if self.head.len() < SNIFF_LENGTH {
+            let missing = (SNIFF_LENGTH - self.head.len()).min(written);
+            self.head.extend_from_slice(&buf[0..missing]);
+        }
+        self.size += written as u64;
+        return Ok(written);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.file.flush();
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // An upload store of its own for a test, in "http-server-rust-<name>-<pid>" under the
+    // temporary directory, which is removed with everything in it when the store is dropped
+    pub struct TempUploadStore {
+        root: PathBuf,
+        store: UploadStore,
+    }
+
+    impl TempUploadStore {
+        pub fn new(name: &str, content_addressed: bool, ttl: Option<u64>) -> Self {
+            let root = std::env::temp_dir().join(format!(
+                "http-server-rust-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&root);
+            return Self {
+                store: UploadStore {
+                    dir: root.join("uploads"),
+                    blobs_dir: root.join("blobs"),
+                    content_addressed,
+                    ttl,
+                },
+                root,
+            };
+        }
+
+        // The directory holding the uploads and the blobs
+        pub fn root(&self) -> &Path {
+            return &self.root;
+        }
+    }
+
+    impl std::ops::Deref for TempUploadStore {
+        type Target = UploadStore;
+
+        fn deref(&self) -> &UploadStore {
+            return &self.store;
+        }
+    }
+
+    impl std::ops::DerefMut for TempUploadStore {
+        fn deref_mut(&mut self) -> &mut UploadStore {
+            return &mut self.store;
+        }
+    }
+
+    impl Drop for TempUploadStore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    pub fn unique_upload_ids() {
+        let dir =
+            std::env::temp_dir().join(format!("http-server-rust-uploads-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut ids: Vec<String> = Vec::new();
+        for _ in 0..100 {
+            let (id, upload_dir) = reserve_upload(&dir).unwrap();
+            assert!(is_valid_upload_id(&id));
+            assert!(upload_dir.is_dir());
+            ids.push(id);
+        }
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 100);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(!is_valid_upload_id("../../../../etc/passwd"));
+        assert!(!is_valid_upload_id("0123456789ABCDEF01234567"));
+        assert_eq!(upload_id_from_target("/api/uploads/abc"), Some("abc"));
+        assert_eq!(
+            progress_id_from_target("/api/uploads/abc/progress"),
+            Some("abc")
+        );
+        assert_eq!(progress_id_from_target(UPLOADS_PROGRESS_TARGET), None);
+        assert_eq!(progress_id_from_target("/api/uploads/abc"), None);
+    }
+
+    #[test]
+    pub fn upload_metadata() {
+        let metadata = UploadMetadata {
+            content_type: String::from("text/plain; charset=latin1"),
+            size: 42,
+            filename: String::from("notes: v2.txt"),
+            sha256: String::from(
+                "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+            ),
+            in_blob_store: true,
+            uploaded_at: 1792224000,
+            client_address: String::from("::1"),
+            expires_at: Some(1792227600),
+            detected_type: Some(String::from("text/plain")),
+        };
+        assert_eq!(
+            UploadMetadata::parse(&metadata.to_text().unwrap()),
+            metadata
+        );
+        assert!(!metadata.is_expired(1792227599));
+        assert!(metadata.is_expired(1792227600));
+        let metadata = UploadMetadata::new("");
+        assert_eq!(
+            UploadMetadata::parse(&metadata.to_text().unwrap()),
+            metadata
+        );
+        assert!(!metadata.is_expired(u64::MAX));
+        assert_eq!(UploadMetadata::parse("size: x\n"), UploadMetadata::new(""));
+        assert_eq!(UploadMetadata::new("").content_type, DEFAULT_CONTENT_TYPE);
+    }
+
+    // A value that would forge lines of the metadata file fails the upload with `InvalidData`,
+    // answered with 400 Bad Request, and leaves nothing behind
+    #[test]
+    pub fn upload_metadata_line_breaks() {
+        use std::io::Write;
+        let metadata = UploadMetadata::new("text/plain\rdetected-type: image/png");
+        let e = metadata.to_text().unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+
+        let store = TempUploadStore::new("metadata-line-breaks", false, None);
+        let mut writer = UploadWriter::create(&store).unwrap();
+        writer.write_all(b"hello").unwrap();
+        let mut metadata = UploadMetadata::new("text/plain");
+        metadata.filename = String::from("a.txt\r\nsize: 0");
+        let e = writer.finish(&mut metadata).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(store.list().unwrap().len(), 0);
+    }
+
+    #[test]
+    pub fn file_name_sanitization() {
+        assert_eq!(sanitize_file_name("report v2.pdf"), "report v2.pdf");
+        assert_eq!(sanitize_file_name("C:\\Users\\me\\photo.jpg"), "photo.jpg");
+        assert_eq!(sanitize_file_name("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_file_name("..."), "upload");
+        assert_eq!(sanitize_file_name(""), "upload");
+        assert_eq!(sanitize_file_name("a\"b\r\n<c>.txt"), "a_b___c_.txt");
+        assert_eq!(sanitize_file_name("été.txt"), "été.txt");
+    }
+
+    #[test]
+    pub fn download_names() {
+        assert_eq!(
+            content_disposition("report v2.pdf"),
+            "attachment; filename=\"report v2.pdf\"; filename*=UTF-8''report%20v2.pdf"
+        );
+        assert_eq!(
+            content_disposition(&sanitize_file_name("été.txt")),
+            "attachment; filename=\"_t_.txt\"; filename*=UTF-8''%C3%A9t%C3%A9.txt"
+        );
+    }
+
+    #[test]
+    pub fn upload_writer() {
+        use std::io::Write;
+        let store = TempUploadStore::new("upload-writer", false, None);
+        let mut writer = UploadWriter::create(&store).unwrap();
+        writer.write_all(b"hello").unwrap();
+        assert_eq!(writer.head(), b"hello");
+        let mut metadata = UploadMetadata::new("text/plain");
+        let id = writer.finish(&mut metadata).unwrap();
+        assert_eq!(
+            store.data_path(&id),
+            store.dir.join(&id).join(UPLOAD_DATA_FILE)
+        );
+        assert_eq!(
+            std::fs::read_to_string(store.data_path(&id)).unwrap(),
+            "hello"
+        );
+        let metadata = UploadMetadata::read(&store.dir.join(&id)).unwrap();
+        assert_eq!(metadata.size, 5);
+        assert_eq!(
+            metadata.sha256,
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert!(!metadata.in_blob_store);
+        assert!(metadata.uploaded_at > 0);
+        assert_eq!(metadata.expires_at, None);
+        assert!(store.is_available(&id, u64::MAX));
+
+        // Uploads being received are not listed
+        let mut writer = UploadWriter::create(&store).unwrap();
+        writer.write_all(b"partial").unwrap();
+        let uploads = store.list().unwrap();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(uploads[0].0, id);
+        assert_eq!(uploads[0].1.content_type, "text/plain");
+        let partial_id = writer.id.clone();
+        writer.abort();
+        assert!(!store.dir.join(partial_id).exists());
+    }
+
+    #[test]
+    pub fn content_addressed_uploads() {
+        use std::io::Write;
+        let store = TempUploadStore::new("content-addressed", true, Some(60));
+        let mut ids: Vec<String> = Vec::new();
+        for _ in 0..2 {
+            let mut writer = UploadWriter::create(&store).unwrap();
+            writer.write_all(b"hello").unwrap();
+            ids.push(writer.finish(&mut UploadMetadata::new("")).unwrap());
+        }
+        // Both uploads have their own metadata but share the data
+        assert_ne!(ids[0], ids[1]);
+        assert!(!store.dir.join(&ids[0]).join(UPLOAD_DATA_FILE).exists());
+        let hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let blob = blob_path(&store.blobs_dir, hash);
+        assert_eq!(store.data_path(&ids[0]), blob);
+        assert_eq!(store.data_path(&ids[1]), blob);
+        assert_eq!(std::fs::read_to_string(&blob).unwrap(), "hello");
+        assert_eq!(blob_references(&store.blobs_dir, hash).unwrap(), 2);
+        let metadata = UploadMetadata::read(&store.dir.join(&ids[0])).unwrap();
+        assert_eq!(metadata.expires_at, Some(metadata.uploaded_at + 60));
+        assert!(store.is_available(&ids[0], metadata.uploaded_at + 59));
+        assert!(!store.is_available(&ids[0], metadata.uploaded_at + 60));
+
+        store.remove(&ids[0]).unwrap();
+        assert!(!store.dir.join(&ids[0]).exists());
+        assert_eq!(blob_references(&store.blobs_dir, hash).unwrap(), 1);
+        store.remove(&ids[1]).unwrap();
+        assert!(!blob.exists());
+    }
+}
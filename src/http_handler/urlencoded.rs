@@ -0,0 +1,115 @@
+// Decoding of 'application/x-www-form-urlencoded' data, used by query strings and form bodies
+// (https://url.spec.whatwg.org/#application/x-www-form-urlencoded)
+
+// Form bodies are held in memory while decoded
+pub const MAX_URLENCODED_BODY_SIZE: u64 = 1024 * 1024;
+
+// Name/value pairs in their original order. A name may occur several times (e.g. checkboxes).
+pub type FormFields = Vec<(String, String)>;
+
+fn hex_value(byte: u8) -> Option<u8> {
+    return match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    };
+}
+
+// Replace the "%XX" escapes by the bytes they stand for, and '+' by a space if `plus_as_space`.
+// Returns None for a '%' not followed by two hex digits, or if the result isn't UTF-8.
+pub fn percent_decode(input: &str, plus_as_space: bool) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut index: usize = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'%' => {
+                let high = hex_value(*bytes.get(index + 1)?)?;
+                let low = hex_value(*bytes.get(index + 2)?)?;
+                decoded.push(high * 16 + low);
+                index += 3;
+            }
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                index += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+    return String::from_utf8(decoded).ok();
+}
+
+// Split "a=1&b=x+y&c" into its decoded pairs; a name without '=' has an empty value and empty
+// pairs are skipped
+pub fn parse_urlencoded(input: &str) -> Option<FormFields> {
+    let mut ret_fields: FormFields = Vec::new();
+    for pair in input.split('&') {
+        if pair.is_empty() {
+            continue;
+        }
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        ret_fields.push((percent_decode(name, true)?, percent_decode(value, true)?));
+    }
+    return Some(ret_fields);
+}
+
+// The value of the first field with the given name
+pub fn field_value<'a>(fields: &'a FormFields, name: &str) -> Option<&'a str> {
+    return fields
+        .iter()
+        .find(|(field_name, _)| field_name == name)
+        .map(|(_, value)| value.as_str());
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    pub fn percent_decoding() {
+        assert_eq!(
+            percent_decode("a%20b+c", false),
+            Some(String::from("a b+c"))
+        );
+        assert_eq!(percent_decode("a%20b+c", true), Some(String::from("a b c")));
+        assert_eq!(
+            percent_decode("%C3%a9t%C3%A9", true),
+            Some(String::from("été"))
+        );
+        assert_eq!(percent_decode("100%25", true), Some(String::from("100%")));
+        assert_eq!(percent_decode("", true), Some(String::new()));
+        // Truncated or non hex escapes, and bytes that aren't UTF-8
+        assert_eq!(percent_decode("100%", true), None);
+        assert_eq!(percent_decode("%4", true), None);
+        assert_eq!(percent_decode("%zz", true), None);
+        assert_eq!(percent_decode("%+1", true), None);
+        assert_eq!(percent_decode("%C3", true), None);
+        assert_eq!(percent_decode("%FF%FE", true), None);
+    }
+
+    #[test]
+    pub fn urlencoded_fields() {
+        let fields = parse_urlencoded("firstName=Ada+L.&tag=a&&tag=b%26c&flag&=empty").unwrap();
+        assert_eq!(
+            fields,
+            vec![
+                (String::from("firstName"), String::from("Ada L.")),
+                (String::from("tag"), String::from("a")),
+                (String::from("tag"), String::from("b&c")),
+                (String::from("flag"), String::new()),
+                (String::new(), String::from("empty")),
+            ]
+        );
+        assert_eq!(
+            parse_urlencoded("a=1=2"),
+            Some(vec![(String::from("a"), String::from("1=2"))])
+        );
+        assert_eq!(parse_urlencoded(""), Some(Vec::new()));
+        assert_eq!(parse_urlencoded("a=%"), None);
+        assert_eq!(parse_urlencoded("a%G1=1"), None);
+    }
+}
@@ -1,76 +1,165 @@
+#![allow(clippy::needless_return)]
 mod http_handler;
+use http_handler::config::config;
+use http_handler::progress::{write_events, MAX_EVENT_STREAM_DURATION, PROGRESS_EVENT_INTERVAL};
 use http_handler::request::*;
 use http_handler::response::*;
+use http_handler::state::{load_state, start_state_sync};
+use http_handler::sweeper::start_sweeper;
+use http_handler::uploads::UploadStore;
 use std::io::prelude::*;
 use std::net::TcpListener;
 use std::net::TcpStream;
 use std::os::fd::AsRawFd;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
 extern crate core;
 
-fn sendfile(payload: &ResponsePayload, stream: &mut TcpStream) -> i32 {
+// How many connections are served at once
+const WORKER_THREADS: usize = 32;
+// How many accepted connections may wait for a worker, before new ones wait in the listen backlog
+const PENDING_CONNECTIONS: usize = 64;
+// How long a client may take to send the next bytes, or to make room for those sent to it
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn sendfile(payload: &ResponsePayload, offset: u64, length: u64, stream: &mut TcpStream) -> i32 {
     extern "C" {
         fn tcp_utils_send_file(
             file_path: *const std::os::raw::c_char,
-            file_size: u64,
+            offset: u64,
+            length: u64,
             socket: i32,
         ) -> i32;
     }
-    let c_string = std::ffi::CString::new(payload.path).unwrap();
+    let c_string = std::ffi::CString::new(payload.path.as_str()).unwrap();
     unsafe {
-        return tcp_utils_send_file(
-            (&c_string).as_ptr(),
-            payload.content_length,
-            stream.as_raw_fd(),
-        );
+        return tcp_utils_send_file(c_string.as_ptr(), offset, length, stream.as_raw_fd());
     }
 }
 
-fn main() {
-    println!("Hello, TCP!");
-    let listener: TcpListener = TcpListener::bind("0.0.0.0:8081").unwrap();
-    for incoming in listener.incoming() {
-        let mut stream = incoming.unwrap();
-        let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
-        let mut buffer = String::new();
-        let mut count: usize;
-        loop {
-            count = reader.read_line(&mut buffer).unwrap();
-            // read_line() includes the EOL -> len() == 2 means "\r\n" only, i.e., empty line
-            if count <= 2 {
-                // End of header detected
-                break;
+fn send_payload(payload: &ResponsePayload, stream: &mut TcpStream) -> std::io::Result<()> {
+    if let Some(feed) = &payload.event_stream {
+        if let Err(e) = write_events(
+            feed,
+            stream,
+            PROGRESS_EVENT_INTERVAL,
+            MAX_EVENT_STREAM_DURATION,
+        ) {
+            println!("Event stream ended: {}", e);
+        }
+        return Ok(());
+    }
+    if let Some(data) = &payload.data {
+        return stream.write_all(data);
+    }
+    if payload.segments.is_empty() {
+        if sendfile(payload, 0, payload.content_length, stream) < 0 {
+            println!("Error while sending {:?}", payload);
+        }
+        return Ok(());
+    }
+    for segment in payload.segments.iter() {
+        match segment {
+            PayloadSegment::Data(data) => stream.write_all(data)?,
+            PayloadSegment::File { offset, length } => {
+                if sendfile(payload, *offset, *length, stream) < 0 {
+                    println!("Error while sending {:?}", payload);
+                    return Ok(());
+                }
             }
         }
-        println!(
-            "---- request header start ----\n{}---- request header end ----",
-            buffer
-        );
-        let request_header: RequestHeader = RequestHeader::new(&buffer);
-        RequestBody::process(&request_header, &mut reader);
-        let response_data: Response = Response::new(&request_header);
-        let mut response_header: String = format!(
-            "{} {}\r\nContent-Length: {}\r\n",
-            request_header.http_version.to_str(),
-            response_data.status.to_str(),
-            response_data.payload.content_length,
-        )
-        .to_string();
+    }
+    return Ok(());
+}
+
+// Read one request from the connection and answer it
+fn handle_connection(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(SOCKET_TIMEOUT))?;
+    stream.set_write_timeout(Some(SOCKET_TIMEOUT))?;
+    let mut reader = std::io::BufReader::new(stream.try_clone()?);
+    let mut buffer = String::new();
+    let mut count: usize;
+    loop {
+        count = reader.read_line(&mut buffer)?;
+        // read_line() includes the EOL -> len() == 2 means "\r\n" only, i.e., empty line
+        if count <= 2 {
+            // End of header detected
+            break;
+        }
+    }
+    // The client went away, or its connection timed out, before sending a request
+    if buffer.is_empty() {
+        return Ok(());
+    }
+    println!(
+        "---- request header start ----\n{}---- request header end ----",
+        buffer
+    );
+    let mut request_header: RequestHeader = RequestHeader::new(&buffer);
+    if let Ok(address) = stream.peer_addr() {
+        request_header.client_address = address.ip().to_string();
+    }
+    let request_body: RequestBody = RequestBody::process(&request_header, &mut reader);
+    let response_data: Response = Response::new(&request_header, &request_body);
+    let response_header: String = response_data.header_to_string(&request_header.http_version);
+    println!("{}", response_header);
+    stream.write_all(response_header.as_bytes())?;
+    if response_data.has_body(&request_header) {
+        send_payload(&response_data.payload, &mut stream)?;
+    }
+    return stream.flush();
+}
 
-        if response_data.payload.content_length > 0 {
-            response_header.push_str("Content-Type: ");
-            response_header.push_str(response_data.payload.content_type.to_str());
-            response_header.push_str("\r\n\r\n");
-            println!("{}", response_header);
-            stream.write(response_header.as_bytes()).unwrap();
-            if sendfile(&response_data.payload, &mut stream) < 0 {
-                println!("Error while sending {:?}", response_data.payload);
+// Start the threads serving connections. A long upload, or a client following its progress, only
+// holds up one of them. When they are all busy, accepted connections queue up to
+// `PENDING_CONNECTIONS`, then sending a connection blocks until a worker is free.
+fn start_workers() -> mpsc::SyncSender<TcpStream> {
+    let (sender, receiver) = mpsc::sync_channel::<TcpStream>(PENDING_CONNECTIONS);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..WORKER_THREADS {
+        let receiver = Arc::clone(&receiver);
+        std::thread::spawn(move || loop {
+            let stream = match receiver.lock().unwrap().recv() {
+                Ok(stream) => stream,
+                Err(_) => return,
+            };
+            // A request that brings the handler down must not take the worker with it
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                handle_connection(stream)
+            }));
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => println!("Connection failed: {}", e),
+                Err(_) => println!("Connection handler panicked"),
             }
-            stream.flush().unwrap();
-        } else {
-            response_header.push_str("\r\n");
-            println!("{}", response_header);
-            stream.write(response_header.as_bytes()).unwrap();
-            stream.flush().unwrap();
+        });
+    }
+    return sender;
+}
+
+fn main() {
+    println!("Hello, TCP!");
+    let listener: TcpListener = TcpListener::bind("0.0.0.0:8081").unwrap();
+    if let Err(e) = start_sweeper(UploadStore::configured(), config().sweep_interval) {
+        println!("Failed to start the upload sweeper: {}", e);
+        std::process::exit(1);
+    }
+    match load_state() {
+        Ok(records) => println!("Replayed {} logged state update(s)", records),
+        Err(e) => {
+            println!("Failed to load the state: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Err(e) = start_state_sync() {
+        println!("Failed to start syncing the state: {}", e);
+        std::process::exit(1);
+    }
+    let workers = start_workers();
+    for incoming in listener.incoming() {
+        match incoming {
+            Ok(stream) => workers.send(stream).unwrap(),
+            Err(e) => println!("Failed to accept a connection: {}", e),
         }
     }
 }